@@ -0,0 +1,419 @@
+// Arithmetic evaluation for integer variables and arithmetic expressions
+// Works like C on 64-bit signed integers, with overflow wrapping around
+use crate::shell::Shell;
+
+// Operators grouped by precedence, loosest first; all are left-associative
+const BINARY_OPERATORS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+// Every operator, longest first so that the tokenizer prefers `<<=` over `<<` over `<`
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "!", "~",
+    "?", ":", "=", "(", ")", ",",
+];
+
+const ASSIGNMENT_OPERATORS: &[&str] = &[
+    "=", "+=", "-=", "*=", "/=", "%=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+// How deeply variables may refer to other variables' expressions before giving up
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+}
+
+// A parsed expression, evaluated once parsing has succeeded so that
+// the untaken sides of &&, || and ?: never run their assignments
+#[derive(Debug)]
+enum Expr {
+    Number(i64),
+    Variable(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    // name, operator (= or a compound operator like +=), value
+    Assign(String, &'static str, Box<Expr>),
+    // name, amount added, whether the old value is the result (x++) or the new one (++x)
+    Increment(String, i64, bool),
+}
+
+// Evaluates an arithmetic expression such as `x * (y + 2)`
+// Variables are read from the shell and may be assigned to with =, +=, ++ and so on
+// An empty expression evaluates to 0
+pub fn evaluate(shell: &mut Shell, expression: &str) -> Result<i64, String> {
+    evaluate_at_depth(shell, expression, 0)
+}
+
+fn evaluate_at_depth(shell: &mut Shell, expression: &str, depth: usize) -> Result<i64, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!(
+            "{}: expression recursion level exceeded",
+            expression.trim()
+        ));
+    }
+    let tokens = tokenize(expression)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+    };
+    let expr = parser.parse_comma();
+    let error = |position: usize| {
+        let rest = tokens[position.min(tokens.len())..]
+            .iter()
+            .map(token_text)
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            "{}: syntax error in expression (error token is \"{}\")",
+            expression.trim(),
+            rest
+        )
+    };
+    match expr {
+        Ok(expr) if parser.position == tokens.len() => eval(shell, &expr, depth),
+        Ok(_) => Err(error(parser.position)),
+        Err(position) => Err(error(position)),
+    }
+}
+
+fn token_text(token: &Token) -> String {
+    match token {
+        Token::Number(n) => n.to_string(),
+        Token::Name(name) => name.clone(),
+        Token::Operator(op) => op.to_string(),
+    }
+}
+
+// Splits an expression into numbers, variable names and operators
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            // Numbers may contain letters and # too, as in 0xff or 2#1010
+            let len = chars[i..]
+                .iter()
+                .take_while(|&&c| c.is_ascii_alphanumeric() || c == '_' || c == '#')
+                .count();
+            let word: String = chars[i..i + len].iter().collect();
+            tokens.push(if c.is_ascii_digit() {
+                Token::Number(parse_number(&word)?)
+            } else {
+                Token::Name(word)
+            });
+            i += len;
+        } else {
+            let rest: String = chars[i..].iter().take(3).collect();
+            let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) else {
+                let rest: String = chars[i..].iter().collect();
+                return Err(format!(
+                    "{}: syntax error: invalid arithmetic operator (error token is \"{}\")",
+                    expression.trim(),
+                    rest
+                ));
+            };
+            tokens.push(Token::Operator(op));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+// Reads an integer constant: decimal, octal with a leading 0, hex with 0x,
+// or any base from 2 to 64 written as base#digits
+fn parse_number(text: &str) -> Result<i64, String> {
+    let (base, digits) = if let Some((base, digits)) = text.split_once('#') {
+        match base.parse::<u32>() {
+            Ok(base) if (2..=64).contains(&base) => (base, digits),
+            _ => return Err(format!("{}: invalid arithmetic base", text)),
+        }
+    } else if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (16, hex)
+    } else if text.len() > 1 && text.starts_with('0') {
+        (8, &text[1..])
+    } else {
+        (10, text)
+    };
+
+    if digits.is_empty() {
+        return Err(format!("{}: invalid number", text));
+    }
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        // Bases above 36 use lowercase, then uppercase, then @ and _ as digits
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(format!("{}: invalid number", text)),
+        };
+        if digit >= base {
+            return Err(format!(
+                "{}: value too great for base (error token is \"{}\")",
+                text, text
+            ));
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+// Recursive descent parser over the tokens of one expression
+// Errors carry the index of the token where parsing failed
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), usize> {
+        if self.peek_operator() == Some(op) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.position)
+        }
+    }
+
+    // expr , expr
+    fn parse_comma(&mut self) -> Result<Expr, usize> {
+        let mut left = self.parse_assignment()?;
+        while self.peek_operator() == Some(",") {
+            self.position += 1;
+            let right = self.parse_assignment()?;
+            left = Expr::Binary(",", Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    // name = expr, name += expr, ...
+    fn parse_assignment(&mut self) -> Result<Expr, usize> {
+        let start = self.position;
+        let target = self.parse_conditional()?;
+        match self.peek_operator() {
+            Some(op) if ASSIGNMENT_OPERATORS.contains(&op) => {
+                let Expr::Variable(name) = target else {
+                    return Err(start);
+                };
+                self.position += 1;
+                let value = self.parse_assignment()?;
+                Ok(Expr::Assign(name, op, Box::new(value)))
+            }
+            _ => Ok(target),
+        }
+    }
+
+    // condition ? expr : expr
+    fn parse_conditional(&mut self) -> Result<Expr, usize> {
+        let condition = self.parse_binary(0)?;
+        if self.peek_operator() != Some("?") {
+            return Ok(condition);
+        }
+        self.position += 1;
+        let if_true = self.parse_comma()?;
+        self.expect(":")?;
+        let if_false = self.parse_conditional()?;
+        Ok(Expr::Conditional(
+            Box::new(condition),
+            Box::new(if_true),
+            Box::new(if_false),
+        ))
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, usize> {
+        let Some(operators) = BINARY_OPERATORS.get(level) else {
+            return self.parse_power();
+        };
+        let mut left = self.parse_binary(level + 1)?;
+        while let Some(op) = self.peek_operator().filter(|op| operators.contains(op)) {
+            self.position += 1;
+            let right = self.parse_binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    // base ** exponent, which groups to the right
+    fn parse_power(&mut self) -> Result<Expr, usize> {
+        let base = self.parse_unary()?;
+        if self.peek_operator() != Some("**") {
+            return Ok(base);
+        }
+        self.position += 1;
+        let exponent = self.parse_power()?;
+        Ok(Expr::Binary("**", Box::new(base), Box::new(exponent)))
+    }
+
+    // -x, !x, ~x, ++x, --x
+    fn parse_unary(&mut self) -> Result<Expr, usize> {
+        match self.peek_operator() {
+            Some(op @ ("+" | "-" | "!" | "~")) => {
+                self.position += 1;
+                Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+            }
+            Some(op @ ("++" | "--")) => {
+                self.position += 1;
+                match self.tokens.get(self.position) {
+                    Some(Token::Name(name)) => {
+                        self.position += 1;
+                        let amount = if op == "++" { 1 } else { -1 };
+                        Ok(Expr::Increment(name.clone(), amount, false))
+                    }
+                    _ => Err(self.position),
+                }
+            }
+            _ => self.parse_postfix(),
+        }
+    }
+
+    // x++, x--, or a plain operand
+    fn parse_postfix(&mut self) -> Result<Expr, usize> {
+        let operand = self.parse_primary()?;
+        if let Expr::Variable(name) = &operand {
+            if let Some(op @ ("++" | "--")) = self.peek_operator() {
+                self.position += 1;
+                let amount = if op == "++" { 1 } else { -1 };
+                return Ok(Expr::Increment(name.clone(), amount, true));
+            }
+        }
+        Ok(operand)
+    }
+
+    // A number, a variable or a parenthesized expression
+    fn parse_primary(&mut self) -> Result<Expr, usize> {
+        let token = self.tokens.get(self.position).ok_or(self.position)?;
+        self.position += 1;
+        match token {
+            Token::Number(n) => Ok(Expr::Number(*n)),
+            Token::Name(name) => Ok(Expr::Variable(name.clone())),
+            Token::Operator("(") => {
+                let inner = self.parse_comma()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Operator(_) => Err(self.position - 1),
+        }
+    }
+}
+
+fn eval(shell: &mut Shell, expr: &Expr, depth: usize) -> Result<i64, String> {
+    Ok(match expr {
+        Expr::Number(n) => *n,
+        Expr::Variable(name) => variable_value(shell, name, depth)?,
+        Expr::Unary(op, operand) => {
+            let value = eval(shell, operand, depth)?;
+            match *op {
+                "-" => value.wrapping_neg(),
+                "!" => (value == 0) as i64,
+                "~" => !value,
+                _ => value,
+            }
+        }
+        Expr::Binary("&&", left, right) => {
+            (eval(shell, left, depth)? != 0 && eval(shell, right, depth)? != 0) as i64
+        }
+        Expr::Binary("||", left, right) => {
+            (eval(shell, left, depth)? != 0 || eval(shell, right, depth)? != 0) as i64
+        }
+        Expr::Binary(op, left, right) => {
+            let left = eval(shell, left, depth)?;
+            let right = eval(shell, right, depth)?;
+            apply_operator(op, left, right)?
+        }
+        Expr::Conditional(condition, if_true, if_false) => {
+            if eval(shell, condition, depth)? != 0 {
+                eval(shell, if_true, depth)?
+            } else {
+                eval(shell, if_false, depth)?
+            }
+        }
+        Expr::Assign(name, op, value) => {
+            let value = eval(shell, value, depth)?;
+            let value = match op.strip_suffix('=').filter(|op| !op.is_empty()) {
+                Some(op) => apply_operator(op, variable_value(shell, name, depth)?, value)?,
+                None => value,
+            };
+            shell.set_var(name, &value.to_string())?;
+            value
+        }
+        Expr::Increment(name, amount, postfix) => {
+            let old = variable_value(shell, name, depth)?;
+            let new = old.wrapping_add(*amount);
+            shell.set_var(name, &new.to_string())?;
+            if *postfix {
+                old
+            } else {
+                new
+            }
+        }
+    })
+}
+
+// Reads a variable as a number; unset and empty variables count as 0 and
+// any other value is itself evaluated as an expression
+fn variable_value(shell: &mut Shell, name: &str, depth: usize) -> Result<i64, String> {
+    match shell.get_var(name) {
+        Some(value) if !value.trim().is_empty() => evaluate_at_depth(shell, &value, depth + 1),
+        _ => Ok(0),
+    }
+}
+
+fn apply_operator(op: &str, left: i64, right: i64) -> Result<i64, String> {
+    Ok(match op {
+        "," => right,
+        "|" => left | right,
+        "^" => left ^ right,
+        "&" => left & right,
+        "==" => (left == right) as i64,
+        "!=" => (left != right) as i64,
+        "<" => (left < right) as i64,
+        "<=" => (left <= right) as i64,
+        ">" => (left > right) as i64,
+        ">=" => (left >= right) as i64,
+        "<<" => left.wrapping_shl(right as u32),
+        ">>" => left.wrapping_shr(right as u32),
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left.wrapping_mul(right),
+        "/" | "%" if right == 0 => return Err("division by 0".to_string()),
+        "/" => left.wrapping_div(right),
+        "%" => left.wrapping_rem(right),
+        "**" if right < 0 => return Err("exponent less than 0".to_string()),
+        "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+        _ => unreachable!("unknown arithmetic operator {}", op),
+    })
+}
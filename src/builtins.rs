@@ -0,0 +1,2561 @@
+// Builtin commands implemented directly by the shell
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+use crate::complete::{self, CompletionSpec};
+use crate::editor;
+use crate::expand;
+use crate::jobs;
+use crate::options::{SET_OPTIONS, SHOPT_OPTIONS};
+use crate::shell::{
+    error_message, executables_in_path, find_executable_in_path, find_file_in_path, CallFrame,
+    LoopControl, Shell,
+};
+use crate::sys;
+use crate::variables::{is_valid_name, Value, Variable};
+
+// Define a type alias for command handler functions
+// Each handler gets the shell state and a slice of command arguments,
+// and returns the command's exit status (0 for success)
+// args[0] is the name the command was run as, and any redirections are already in
+// place, so a handler just writes to stdout and stderr and reads stdin as usual
+pub type CommandHandler = fn(&mut Shell, &[&str]) -> i32;
+
+// The options given to a builtin: each option letter, with its value if it takes one
+type Options<'a> = Vec<(char, Option<&'a str>)>;
+
+// Splits a builtin's arguments (after the command name) into its options and operands
+// `spec` lists the valid option letters; as with getopts, a letter followed by : takes
+// a value, either the rest of the word (-pPROMPT) or the next word (-p PROMPT)
+// Options are words like -ab before the first operand, and a `--` word ends them so
+// that later words starting with - are taken as operands
+fn parse_options<'a, 'b>(
+    args: &'a [&'b str],
+    spec: &str,
+) -> Result<(Options<'b>, &'a [&'b str]), String> {
+    let mut options = Vec::new();
+    let mut i = 1;
+    while let Some(&arg) = args.get(i) {
+        if arg.len() < 2 || !arg.starts_with('-') {
+            break;
+        }
+        i += 1;
+        if arg == "--" {
+            break;
+        }
+        for (position, flag) in arg.char_indices().skip(1) {
+            let Some(found) = spec.find(flag).filter(|_| flag != ':') else {
+                return Err(format!("-{}: invalid option", flag));
+            };
+            if !spec[found + 1..].starts_with(':') {
+                options.push((flag, None));
+                continue;
+            }
+            let rest = &arg[position + flag.len_utf8()..];
+            let value = if rest.is_empty() {
+                i += 1;
+                *args
+                    .get(i - 1)
+                    .ok_or_else(|| format!("-{}: option requires an argument", flag))?
+            } else {
+                rest
+            };
+            options.push((flag, Some(value)));
+            break;
+        }
+    }
+    Ok((options, &args[i..]))
+}
+
+// Reports a builtin being called wrongly, like an invalid option from parse_options,
+// followed by a line showing how it should be called; returns the status 2 for that
+fn usage_error(shell: &mut Shell, name: &str, usage: &str, message: &str) -> i32 {
+    shell.print_error(name, message);
+    shell.print_error(name, &format!("usage: {}", usage));
+    2
+}
+
+// Create and return a registry of all available builtin commands
+// Maps command names (like "echo", "exit") to their handler functions
+pub fn register_builtins() -> HashMap<&'static str, CommandHandler> {
+    let mut builtins: HashMap<&'static str, CommandHandler> = HashMap::new();
+
+    // Add each builtin command and its handler function to the registry
+    builtins.insert("echo", echo_command);
+    builtins.insert("exit", exit_command);
+    builtins.insert("type", type_command);
+    builtins.insert("builtin", builtin_command);
+    builtins.insert("exec", exec_command);
+    builtins.insert("pwd", pwd_command);
+    builtins.insert("cd", cd_command);
+    builtins.insert("pushd", pushd_command);
+    builtins.insert("popd", popd_command);
+    builtins.insert("dirs", dirs_command);
+    builtins.insert("clear", clear_command);
+    builtins.insert(":", colon_command);
+    builtins.insert("sleep", sleep_command);
+    builtins.insert("set", set_command);
+    builtins.insert("shift", shift_command);
+    builtins.insert("shopt", shopt_command);
+    builtins.insert("settitle", settitle_command);
+    builtins.insert("trap", trap_command);
+    builtins.insert("hash", hash_command);
+    builtins.insert("compgen", compgen_command);
+    builtins.insert("complete", complete_command);
+    builtins.insert("source", source_command);
+    builtins.insert("caller", caller_command);
+    builtins.insert(".", source_command);
+    builtins.insert("declare", declare_command);
+    builtins.insert("printenv", printenv_command);
+    builtins.insert("typeset", declare_command);
+    builtins.insert("readonly", readonly_command);
+    builtins.insert("unset", unset_command);
+    builtins.insert("let", let_command);
+    builtins.insert("jobs", jobs_command);
+    builtins.insert("fg", fg_command);
+    builtins.insert("bg", bg_command);
+    builtins.insert("disown", disown_command);
+    builtins.insert("wait", wait_command);
+    builtins.insert("break", loop_control_command);
+    builtins.insert("continue", loop_control_command);
+    builtins.insert("return", return_command);
+    builtins.insert("fc", fc_command);
+    builtins.insert("history", history_command);
+    builtins.insert("read", read_command);
+    builtins.insert("mapfile", mapfile_command);
+    builtins.insert("readarray", mapfile_command);
+
+    builtins
+}
+
+// Handler for the 'echo' builtin command
+// Prints all arguments (after the command name) joined by spaces
+// Leading words made only of the flags n, e and E are options: -n leaves off the
+// newline, -e interprets backslash escapes and -E (the default) doesn't; the first
+// other word, or a word after --, starts the text, so `echo hi -n` prints `hi -n`
+// A failed write, as to a closed pipe, is reported rather than taking the shell down
+fn echo_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut i = 1;
+    while let Some(&arg) = args.get(i) {
+        if arg == "--" {
+            i += 1;
+            break;
+        }
+        let Some(flags) = arg.strip_prefix('-') else {
+            break;
+        };
+        if flags.is_empty() || !flags.chars().all(|flag| matches!(flag, 'n' | 'e' | 'E')) {
+            break;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        i += 1;
+    }
+
+    // Print the words separated by spaces; with no arguments that's a blank line
+    let mut line = args[i..].join(" ");
+    if escapes {
+        let (text, stop) = interpret_escapes(&line);
+        line = text;
+        // \c ends the output there, newline included
+        newline &= !stop;
+    }
+    if newline {
+        line.push('\n');
+    }
+    // The line goes out in one write, so none of it is left buffered if that fails
+    if let Err(e) = shell.out.write_all(line.as_bytes()) {
+        // Writing to a pipe that's been closed mustn't bring the shell down
+        shell.print_error("echo", &format!("write error: {}", error_message(&e)));
+        return 1;
+    }
+    0
+}
+
+// Interprets the backslash escapes of echo -e: \a \b \e \f \n \r \t \v and \\, \0NNN
+// for an octal byte and \xHH for a hex one, and \c, which drops the rest of the text
+// Returns the text and whether a \c cut it short
+fn interpret_escapes(text: &str) -> (String, bool) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '\\' || i + 1 == chars.len() {
+            result.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let escape = chars[i + 1];
+        i += 2;
+        match escape {
+            'a' => result.push('\x07'),
+            'b' => result.push('\x08'),
+            'c' => return (result, true),
+            'e' | 'E' => result.push('\x1b'),
+            'f' => result.push('\x0c'),
+            'n' => result.push('\n'),
+            'r' => result.push('\r'),
+            't' => result.push('\t'),
+            'v' => result.push('\x0b'),
+            '\\' => result.push('\\'),
+            '0' | 'x' => {
+                // Up to three octal digits after \0, or two hex digits after \x
+                let (radix, max_digits) = if escape == '0' { (8, 3) } else { (16, 2) };
+                let mut value = 0;
+                let mut digits = 0;
+                while digits < max_digits {
+                    let Some(digit) = chars.get(i).and_then(|c| c.to_digit(radix)) else {
+                        break;
+                    };
+                    value = value * radix + digit;
+                    digits += 1;
+                    i += 1;
+                }
+                if escape == 'x' && digits == 0 {
+                    result.push_str("\\x");
+                } else {
+                    result.extend(char::from_u32(value));
+                }
+            }
+            // Unknown escapes are printed as they were written
+            other => {
+                result.push('\\');
+                result.push(other);
+            }
+        }
+    }
+    (result, false)
+}
+
+// Handler for the 'exit' builtin command
+// Exits the shell with the specified exit code, by default the status of the last command
+// Like other exit statuses the code wraps round modulo 256, so `exit -1` exits with 255
+// More than one argument is an error that leaves the shell running, as a typo shouldn't
+// close it
+fn exit_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let exit_code = match args.get(1) {
+        Some(arg) => match arg.trim().parse::<i64>() {
+            Ok(_) if args.len() > 2 => {
+                shell.print_error("exit", "too many arguments");
+                return 1;
+            }
+            Ok(code) => code.rem_euclid(256) as i32,
+            Err(_) => {
+                shell.print_error("exit", &format!("{}: numeric argument required", arg));
+                2
+            }
+        },
+        None => shell.last_status,
+    };
+    shell.exit(exit_code);
+}
+
+// Handler for the 'settitle' builtin command
+// Sets the terminal's window or tab title to the arguments joined by spaces; with no
+// arguments, prints the title it last set
+// The title is only sent to the terminal in an interactive shell writing to a terminal,
+// so scripts and redirected output never see the escape sequence
+fn settitle_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    if args.len() < 2 {
+        if let Some(title) = &shell.title {
+            writeln!(shell.out, "{}", title).ok();
+        }
+        return 0;
+    }
+
+    let title = args[1..].join(" ");
+    if shell.interactive && sys::is_terminal(1) {
+        // OSC 0 sets both the window and the icon title
+        write!(shell.out, "\x1b]0;{}\x07", title).ok();
+        shell.out.flush().ok();
+    }
+    shell.title = Some(title);
+    0
+}
+
+// Handler for the 'trap' builtin command
+// `trap COMMAND CONDITION...` runs COMMAND when the shell exits (EXIT, or 0) or gets
+// one of the signals, given by name with or without SIG (INT, SIGINT) or by number (2)
+// An empty COMMAND ignores the signals, `trap - CONDITION` or a lone condition puts
+// back what was there before, and `trap` alone or `trap -p` lists the traps that are
+// set, or with -p just those for the conditions given
+// -l lists the signal names with their numbers
+fn trap_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, operands) = match parse_options(args, "lp") {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return usage_error(
+                shell,
+                "trap",
+                "trap [-lp] [[action] condition ...]",
+                &message,
+            )
+        }
+    };
+
+    if options.iter().any(|&(flag, _)| flag == 'l') {
+        let entries: Vec<String> = sys::SIGNAL_NAMES
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(number, name)| format!("{:2}) SIG{}", number, name))
+            .collect();
+        for row in entries.chunks(5) {
+            writeln!(shell.out, "{}", row.join("\t")).ok();
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    let mut conditions = Vec::new();
+    // With -p, or nothing at all, the operands are only conditions to list
+    let listing = operands.is_empty() || !options.is_empty();
+    let (action, specs) = match operands {
+        // A lone condition resets it, like `trap - EXIT`
+        [condition] if !listing => ("-", std::slice::from_ref(condition)),
+        [action, specs @ ..] if !listing => (*action, specs),
+        _ => ("", operands),
+    };
+    for &spec in specs {
+        match trap_condition(spec) {
+            Some(condition) => conditions.push(condition),
+            None => {
+                shell.print_error("trap", &format!("{}: invalid signal specification", spec));
+                status = 1;
+            }
+        }
+    }
+
+    if listing {
+        let mut traps: Vec<(&String, &String)> = shell
+            .traps
+            .iter()
+            .filter(|(condition, _)| specs.is_empty() || conditions.contains(condition))
+            .collect();
+        traps.sort_by_key(|(condition, _)| trap_number(condition));
+        let lines: Vec<String> = traps
+            .into_iter()
+            .map(|(condition, command)| {
+                let quoted = command.replace('\'', "'\\''");
+                match trap_number(condition) {
+                    0 => format!("trap -- '{}' {}", quoted, condition),
+                    _ => format!("trap -- '{}' SIG{}", quoted, condition),
+                }
+            })
+            .collect();
+        for line in lines {
+            writeln!(shell.out, "{}", line).ok();
+        }
+        return status;
+    }
+
+    for condition in conditions {
+        let number = trap_number(&condition) as i32;
+        if action == "-" {
+            shell.traps.remove(&condition);
+            if number != 0 {
+                sys::set_signal_action(number, sys::SignalAction::Restore);
+            }
+            continue;
+        }
+        if number != 0 {
+            let signal_action = if action.is_empty() {
+                sys::SignalAction::Ignore
+            } else {
+                sys::SignalAction::Catch
+            };
+            sys::set_signal_action(number, signal_action);
+        }
+        shell.traps.insert(condition, action.to_string());
+    }
+    status
+}
+
+// Turns a trap condition as given into the name its trap is kept under: EXIT, or a
+// signal name without SIG, so that SIGINT, INT, int and 2 all mean the same trap
+fn trap_condition(spec: &str) -> Option<String> {
+    if spec == "0" || spec.eq_ignore_ascii_case("EXIT") {
+        return Some("EXIT".to_string());
+    }
+    if let Ok(number) = spec.parse::<usize>() {
+        return sys::SIGNAL_NAMES
+            .get(number)
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_string());
+    }
+    let upper = spec.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    sys::SIGNAL_NAMES
+        .iter()
+        .skip(1)
+        .find(|&&known| known == name)
+        .map(|name| name.to_string())
+}
+
+// The signal number of a trap condition, with 0 for EXIT
+fn trap_number(condition: &str) -> usize {
+    sys::SIGNAL_NAMES
+        .iter()
+        .position(|&name| name == condition)
+        .unwrap_or(0)
+}
+
+// Handler for the 'hash' builtin command
+// Lists the commands the shell remembers finding in PATH, with how many times each
+// has been run since, or with names looks each one up in PATH and remembers it
+// -r forgets them all, so the next run of each searches PATH again, -d forgets just
+// the names given, and -t prints where each named command was found
+fn hash_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, names) = match parse_options(args, "drt") {
+        Ok(parsed) => parsed,
+        Err(message) => return usage_error(shell, "hash", "hash [-r] [-dt] [name ...]", &message),
+    };
+    let has = |letter| options.iter().any(|&(flag, _)| flag == letter);
+
+    if has('r') {
+        shell.command_hash.clear();
+    }
+    if names.is_empty() {
+        if has('r') {
+            return 0;
+        }
+        if shell.command_hash.is_empty() {
+            writeln!(shell.out, "hash: hash table empty").ok();
+            return 0;
+        }
+        let lines: Vec<String> = shell
+            .command_hash
+            .values()
+            .map(|(path, hits)| format!("{:4}\t{}", hits, path))
+            .collect();
+        writeln!(shell.out, "hits\tcommand").ok();
+        for line in lines {
+            writeln!(shell.out, "{}", line).ok();
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for &name in names {
+        if has('d') {
+            if shell.command_hash.remove(name).is_none() {
+                shell.print_error("hash", &format!("{}: not found", name));
+                status = 1;
+            }
+            continue;
+        }
+        if has('t') {
+            match shell.command_hash.get(name) {
+                Some((path, _)) => {
+                    writeln!(shell.out, "{}", path).ok();
+                }
+                None => {
+                    shell.print_error("hash", &format!("{}: not found", name));
+                    status = 1;
+                }
+            }
+            continue;
+        }
+        // Builtins are never looked for in PATH, so there's nothing to remember for them
+        if shell.is_builtin(name) {
+            continue;
+        }
+        match find_executable_in_path(name).filter(|_| !name.contains('/')) {
+            Some(path) => {
+                shell.command_hash.insert(name.to_string(), (path, 0));
+            }
+            None => {
+                shell.print_error("hash", &format!("{}: not found", name));
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+// Handler for the 'break' and 'continue' builtin commands
+// `break [n]` leaves the innermost loop, or the n innermost ones, and `continue [n]`
+// goes on to the next round of the innermost loop, or of the nth one out
+// A count beyond the loops there are means the outermost, and outside a loop both
+// do nothing
+fn loop_control_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let operands = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => return usage_error(shell, args[0], &format!("{} [n]", args[0]), &message),
+    };
+    let levels = match operands.first() {
+        None => 1,
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(levels) if levels > 0 => levels,
+            Ok(_) => {
+                shell.print_error(args[0], &format!("{}: loop count out of range", arg));
+                return 1;
+            }
+            Err(_) => {
+                shell.print_error(args[0], &format!("{}: numeric argument required", arg));
+                return 1;
+            }
+        },
+    };
+    if shell.loop_depth == 0 {
+        shell.print_error(
+            args[0],
+            "only meaningful in a `for', `while', `until', or `select' loop",
+        );
+        return 0;
+    }
+    let levels = levels.min(shell.loop_depth);
+    shell.loop_control = Some(if args[0] == "break" {
+        LoopControl::Break(levels)
+    } else {
+        LoopControl::Continue(levels)
+    });
+    0
+}
+
+// Handler for the 'return' builtin command
+// `return [n]` ends the function or sourced file being run, with status n, or the
+// status of the last command without one
+fn return_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let operands = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => return usage_error(shell, "return", "return [n]", &message),
+    };
+    if shell.call_stack.is_empty() {
+        shell.print_error(
+            "return",
+            "can only `return' from a function or sourced script",
+        );
+        return 1;
+    }
+    let status = match operands.first() {
+        None => shell.last_status,
+        Some(arg) => match arg.parse::<i64>() {
+            Ok(status) => status.rem_euclid(256) as i32,
+            Err(_) => {
+                shell.print_error("return", &format!("{}: numeric argument required", arg));
+                2
+            }
+        },
+    };
+    shell.loop_control = Some(LoopControl::Return);
+    status
+}
+
+// Handler for the 'compgen' builtin command
+// Prints the completions of a word, or of an empty one, one per line, for completion
+// scripts: -b builtins, -c commands, -d directories, -f files and directories,
+// -v variables and -W WORDLIST the words of the list
+// The candidates of each kind come in turn, in the order the options are given
+// Returns 1 if there are none
+fn compgen_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, operands) = match parse_options(args, "bcdfvW:") {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return usage_error(
+                shell,
+                "compgen",
+                "compgen [-bcdfv] [-W wordlist] [word]",
+                &message,
+            )
+        }
+    };
+    let spec = CompletionSpec::new(&options);
+    let prefix = operands.first().copied().unwrap_or_default();
+    let candidates = complete::spec_candidates(shell, &spec, &[prefix.to_string()]);
+    for candidate in &candidates {
+        writeln!(shell.out, "{}", candidate).ok();
+    }
+    if candidates.is_empty() {
+        1
+    } else {
+        0
+    }
+}
+
+// Handler for the 'complete' builtin command
+// `complete [-bcdfv] [-W wordlist] name...` has Tab complete the arguments of the
+// named commands with what compgen would give for the same options
+// -F FUNCTION completes with a shell function instead, which finds the words of the
+// command in COMP_WORDS and leaves its candidates in COMPREPLY
+// -p prints the settings for the names given, or for every command, in a form that
+// can be run again, and -r removes them, for every command if none are named
+fn complete_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, names) = match parse_options(args, "bcdfprvF:W:") {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return usage_error(
+                shell,
+                "complete",
+                "complete [-bcdfprv] [-F function] [-W wordlist] [name ...]",
+                &message,
+            )
+        }
+    };
+    let has = |letter| options.iter().any(|&(flag, _)| flag == letter);
+
+    if has('r') {
+        if names.is_empty() {
+            shell.completion_specs.clear();
+        }
+        for &name in names {
+            shell.completion_specs.remove(name);
+        }
+        return 0;
+    }
+    let spec = CompletionSpec::new(&options);
+    if has('p') || spec.actions.is_empty() {
+        let mut status = 0;
+        let mut lines = Vec::new();
+        for (name, spec) in &shell.completion_specs {
+            if names.is_empty() || names.contains(&name.as_str()) {
+                lines.push(format!("complete {} {}", spec.describe(), name));
+            }
+        }
+        for line in lines {
+            writeln!(shell.out, "{}", line).ok();
+        }
+        for &name in names {
+            if !shell.completion_specs.contains_key(name) {
+                shell.print_error(
+                    "complete",
+                    &format!("{}: no completion specification", name),
+                );
+                status = 1;
+            }
+        }
+        return status;
+    }
+
+    for &name in names {
+        shell
+            .completion_specs
+            .insert(name.to_string(), spec.clone());
+    }
+    0
+}
+
+// Handler for the 'pwd' builtin command
+// Prints the full absolute path of the current working directory
+// -L and -P are accepted but, as with cd, make no difference
+fn pwd_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    if let Err(message) = parse_options(args, "LP") {
+        return usage_error(shell, "pwd", "pwd [-LP]", &message);
+    }
+    match std::env::current_dir() {
+        Ok(path) => {
+            // Print the absolute path as a string
+            if let Some(path_str) = path.to_str() {
+                writeln!(shell.out, "{}", path_str).ok();
+            } else {
+                writeln!(
+                    shell.out,
+                    "Error: current directory path is not valid UTF-8"
+                )
+                .ok();
+                return 1;
+            }
+            0
+        }
+        Err(e) => {
+            shell.print_error("pwd", &format!("error retrieving current directory: {}", e));
+            1
+        }
+    }
+}
+
+// Handler for the 'cd' builtin command
+// Changes the current working directory to the specified path
+// A relative path not starting with . or .. is also looked for in each directory of CDPATH
+fn cd_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    // Step 1: Separate the options from the path; -L and -P are accepted but,
+    // as symlinks are never resolved, make no difference
+    let operands = match parse_options(args, "LP") {
+        Ok((_, operands)) => operands,
+        Err(message) => return usage_error(shell, "cd", "cd [-L|-P] [dir]", &message),
+    };
+
+    // Step 2: Check if a path argument was provided
+    let Some(&path) = operands.first() else {
+        shell.print_error("cd", "missing operand");
+        return 1;
+    };
+
+    // Step 3: Try the CDPATH directories, printing where we ended up as bash does
+    if let Some(dir) = find_in_cdpath(shell, path) {
+        if std::env::set_current_dir(&dir).is_ok() {
+            writeln!(shell.out, "{}", dir).ok();
+            return 0;
+        }
+    }
+
+    // Step 4: Try to change to that directory
+    match std::env::set_current_dir(path) {
+        Ok(_) => {
+            // Success! Directory was changed
+            0
+        }
+        Err(_) => {
+            // Failed to change directory - print error message
+            shell.print_error("cd", &format!("{}: No such file or directory", path));
+            1
+        }
+    }
+}
+
+// Looks for `path` under the directories listed in CDPATH
+// Paths that are absolute or start with . or .. never use CDPATH, and an empty CDPATH
+// entry (meaning the current directory) is left to the plain lookup
+fn find_in_cdpath(shell: &Shell, path: &str) -> Option<String> {
+    let explicit = path.starts_with('/')
+        || path == "."
+        || path == ".."
+        || path.starts_with("./")
+        || path.starts_with("../");
+    if explicit {
+        return None;
+    }
+    let cdpath = shell.get_var("CDPATH")?;
+    cdpath
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(path))
+        .find(|candidate| candidate.is_dir())
+        .map(|candidate| candidate.to_string_lossy().into_owned())
+}
+
+// Handler for the 'pushd' builtin command
+// Saves the current directory on the directory stack and changes to the given one
+// With no argument it swaps the top two directories, and with +N it rotates the stack
+// so that entry N of the dirs listing (counting from 0 on the left, or on the right
+// for -N) comes to the top
+fn pushd_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let Some(mut dirs) = directory_list(shell, args[0]) else {
+        return 1;
+    };
+    match args.get(1) {
+        None if dirs.len() < 2 => {
+            shell.print_error("pushd", "no other directory");
+            return 1;
+        }
+        None => dirs.swap(0, 1),
+        Some(arg) if is_stack_index(arg) => match stack_index(arg, dirs.len()) {
+            Some(index) => dirs.rotate_left(index),
+            None => {
+                shell.print_error(
+                    "pushd",
+                    &format!("{}: directory stack index out of range", arg),
+                );
+                return 1;
+            }
+        },
+        Some(dir) => {
+            if std::env::set_current_dir(dir).is_err() {
+                shell.print_error("pushd", &format!("{}: No such file or directory", dir));
+                return 1;
+            }
+            dirs.insert(
+                0,
+                std::env::current_dir().unwrap_or_else(|_| PathBuf::from(dir)),
+            );
+        }
+    }
+
+    if let Err(e) = std::env::set_current_dir(&dirs[0]) {
+        shell.print_error("pushd", &format!("{}: {}", dirs[0].display(), e));
+        return 1;
+    }
+    shell.dir_stack = dirs.split_off(1);
+    print_directories(shell, &dirs[0]);
+    0
+}
+
+// Handler for the 'popd' builtin command
+// Removes the top directory from the directory stack and changes to the next one
+// With +N (or -N, counting from the right) it removes entry N of the dirs listing
+// instead, staying in the current directory unless that's the entry removed
+fn popd_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let Some(mut dirs) = directory_list(shell, args[0]) else {
+        return 1;
+    };
+    if dirs.len() < 2 {
+        shell.print_error("popd", "directory stack empty");
+        return 1;
+    }
+    let index = match args.get(1) {
+        None => 0,
+        Some(arg) if is_stack_index(arg) => match stack_index(arg, dirs.len()) {
+            Some(index) => index,
+            None => {
+                shell.print_error(
+                    "popd",
+                    &format!("{}: directory stack index out of range", arg),
+                );
+                return 1;
+            }
+        },
+        Some(arg) => {
+            return usage_error(
+                shell,
+                "popd",
+                "popd [+N | -N]",
+                &format!("{}: invalid argument", arg),
+            )
+        }
+    };
+
+    dirs.remove(index);
+    if index == 0 {
+        if let Err(e) = std::env::set_current_dir(&dirs[0]) {
+            shell.print_error("popd", &format!("{}: {}", dirs[0].display(), e));
+            return 1;
+        }
+    }
+    shell.dir_stack = dirs.split_off(1);
+    print_directories(shell, &dirs[0]);
+    0
+}
+
+// Handler for the 'dirs' builtin command
+// Shows the directory stack, starting with the current directory
+// -v numbers the entries one per line, -p puts them one per line, -c clears the stack,
+// and +N or -N shows just that entry
+// Directories under $HOME are shown with ~ unless -l asks for them in full
+fn dirs_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let mut long = false;
+    let mut numbered = false;
+    let mut one_per_line = false;
+    let mut entry = None;
+    for arg in &args[1..] {
+        if is_stack_index(arg) {
+            entry = Some(*arg);
+            continue;
+        }
+        let Some(flags) = arg.strip_prefix('-') else {
+            shell.print_error("dirs", &format!("{}: invalid argument", arg));
+            return 2;
+        };
+        for flag in flags.chars() {
+            match flag {
+                'c' => shell.dir_stack.clear(),
+                'v' => numbered = true,
+                'p' => one_per_line = true,
+                'l' => long = true,
+                _ => {
+                    return usage_error(
+                        shell,
+                        "dirs",
+                        "dirs [-clpv] [+N] [-N]",
+                        &format!("-{}: invalid option", flag),
+                    )
+                }
+            }
+        }
+    }
+
+    let Some(dirs) = directory_list(shell, args[0]) else {
+        return 1;
+    };
+    let dirs: Vec<String> = dirs
+        .iter()
+        .map(|dir| {
+            if long {
+                dir.display().to_string()
+            } else {
+                shell.abbreviate_home(dir)
+            }
+        })
+        .collect();
+    if let Some(arg) = entry {
+        match stack_index(arg, dirs.len()) {
+            Some(index) => {
+                writeln!(shell.out, "{}", dirs[index]).ok();
+            }
+            None => {
+                shell.print_error(
+                    "dirs",
+                    &format!("{}: directory stack index out of range", arg),
+                );
+                return 1;
+            }
+        }
+    } else if numbered {
+        for (index, dir) in dirs.iter().enumerate() {
+            writeln!(shell.out, "{:2}  {}", index, dir).ok();
+        }
+    } else if one_per_line {
+        for dir in &dirs {
+            writeln!(shell.out, "{}", dir).ok();
+        }
+    } else {
+        writeln!(shell.out, "{}", dirs.join(" ")).ok();
+    }
+    0
+}
+
+// The current directory followed by the directory stack, as dirs lists them
+fn directory_list(shell: &mut Shell, command: &str) -> Option<Vec<PathBuf>> {
+    match std::env::current_dir() {
+        Ok(cwd) => Some(
+            std::iter::once(cwd)
+                .chain(shell.dir_stack.iter().cloned())
+                .collect(),
+        ),
+        Err(e) => {
+            shell.print_error(
+                command,
+                &format!("error retrieving current directory: {}", e),
+            );
+            None
+        }
+    }
+}
+
+// Returns true for a directory stack argument like +2 or -1
+fn is_stack_index(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with(['+', '-']) && arg[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+// Turns +N (counting from the left) or -N (from the right) into an index into a
+// directory list of `len` entries, or None if it's out of range
+fn stack_index(arg: &str, len: usize) -> Option<usize> {
+    let n: usize = arg[1..].parse().ok()?;
+    if n >= len {
+        return None;
+    }
+    Some(if arg.starts_with('+') { n } else { len - 1 - n })
+}
+
+// Prints the current directory and the directory stack on one line
+fn print_directories(shell: &mut Shell, cwd: &Path) {
+    let dirs: Vec<String> = std::iter::once(cwd)
+        .chain(shell.dir_stack.iter().map(PathBuf::as_path))
+        .map(|dir| shell.abbreviate_home(dir))
+        .collect();
+    writeln!(shell.out, "{}", dirs.join(" ")).ok();
+}
+
+// Handler for the ':' builtin command
+// Does nothing and succeeds; its arguments are still expanded before it runs, which
+// is what `: ${VAR:=default}` relies on to assign a default
+fn colon_command(_shell: &mut Shell, _args: &[&str]) -> i32 {
+    0
+}
+
+// Handler for the 'sleep' builtin command
+// Waits for the total of its arguments, each a number of seconds, possibly fractional,
+// or with a suffix of s, m, h or d for seconds, minutes, hours or days, as in
+// `sleep 0.5` or `sleep 1m 30s`
+// Ctrl-C cuts the wait short with status 130
+// With job control the wait happens in a forked job of its own, as the external sleep
+// would, so that Ctrl-Z stops it and it can be carried on with fg or bg
+fn sleep_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let operands = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => return usage_error(shell, "sleep", "sleep number[smhd] ...", &message),
+    };
+    if operands.is_empty() {
+        shell.print_error("sleep", "missing operand");
+        return 1;
+    }
+    let mut total = Duration::ZERO;
+    for operand in operands {
+        match sleep_interval(operand) {
+            Some(interval) => total = total.saturating_add(interval),
+            None => {
+                shell.print_error("sleep", &format!("invalid time interval `{}'", operand));
+                return 1;
+            }
+        }
+    }
+
+    if shell.job_control {
+        return shell
+            .run_as_job(|shell| sleep_command(shell, args))
+            .unwrap_or_else(|e| {
+                shell.print_error("sleep", &e);
+                1
+            });
+    }
+
+    // The wait goes in short steps, so that Ctrl-C is noticed straight away
+    let catcher = sys::catch_interrupts();
+    let deadline = Instant::now().checked_add(total);
+    loop {
+        if catcher.interrupted() {
+            return 130;
+        }
+        let remaining = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => Duration::MAX,
+        };
+        if remaining.is_zero() {
+            return 0;
+        }
+        std::thread::sleep(remaining.min(Duration::from_millis(50)));
+    }
+}
+
+// Turns an argument of sleep, like 2, 0.5 or 1m, into how long it asks for
+fn sleep_interval(arg: &str) -> Option<Duration> {
+    let (number, unit) = match arg.char_indices().last()? {
+        (index, 's') => (&arg[..index], 1.0),
+        (index, 'm') => (&arg[..index], 60.0),
+        (index, 'h') => (&arg[..index], 3600.0),
+        (index, 'd') => (&arg[..index], 86400.0),
+        _ => (arg, 1.0),
+    };
+    // Digits and a dot only, so that words like inf and nan aren't taken for numbers
+    if !number.contains(|c: char| c.is_ascii_digit())
+        || !number.chars().all(|c| c.is_ascii_digit() || c == '.')
+    {
+        return None;
+    }
+    Duration::try_from_secs_f64(number.parse::<f64>().ok()? * unit).ok()
+}
+
+// Handler for the 'clear' builtin command
+// Clears the terminal screen and moves the cursor to the top-left corner
+fn clear_command(shell: &mut Shell, _args: &[&str]) -> i32 {
+    write!(shell.out, "{}", editor::CLEAR_SCREEN).ok();
+    shell.out.flush().ok();
+    0
+}
+
+// Handler for the 'set' builtin command
+// Turns shell options on (-o name, -C) or off (+o name, +C)
+// With a bare -o or +o, lists the current option settings
+// Any words after the options, or after --, replace the positional parameters, and
+// a -- with nothing after it clears them
+fn set_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let mut i = 1;
+    let mut replace_params = false;
+    while i < args.len() {
+        let arg = args[i];
+        if arg == "--" {
+            replace_params = true;
+            i += 1;
+            break;
+        }
+        let enable = arg.starts_with('-');
+        if !enable && !arg.starts_with('+') {
+            break;
+        }
+
+        if &arg[1..] == "o" {
+            // `-o name` takes the option name from the next argument
+            match args.get(i + 1) {
+                Some(name) => {
+                    let known = SET_OPTIONS.iter().any(|&(known, _)| known == *name);
+                    if !known || !shell.options.set(name, enable) {
+                        shell.print_error("set", &format!("{}: invalid option name", name));
+                        return 1;
+                    }
+                    i += 1;
+                }
+                None => print_options(shell, enable),
+            }
+        } else {
+            // A run of single-letter flags like -C
+            for flag in arg[1..].chars() {
+                match SET_OPTIONS
+                    .iter()
+                    .find(|&&(_, letter)| letter == Some(flag))
+                {
+                    Some(&(name, _)) => {
+                        shell.options.set(name, enable);
+                    }
+                    None => {
+                        shell.print_error("set", &format!("{}{}: invalid option", &arg[..1], flag));
+                        return 2;
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+    if replace_params || i < args.len() {
+        shell.positional_params = args[i..].iter().map(|arg| arg.to_string()).collect();
+    }
+    0
+}
+
+// Handler for the 'shift' builtin command
+// Drops the first N positional parameters (by default one), so $N+1 becomes $1
+// Fails, leaving them alone, if there aren't N to drop
+fn shift_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let operands = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => return usage_error(shell, "shift", "shift [n]", &message),
+    };
+    let count = match operands.first() {
+        None => 1,
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) if arg.starts_with('-') && arg[1..].parse::<usize>().is_ok() => {
+                shell.print_error("shift", &format!("{}: shift count out of range", arg));
+                return 1;
+            }
+            Err(_) => {
+                shell.print_error("shift", &format!("{}: numeric argument required", arg));
+                return 1;
+            }
+        },
+    };
+    if count > shell.positional_params.len() {
+        shell.print_error("shift", &format!("{}: shift count out of range", count));
+        return 1;
+    }
+    shell.positional_params.drain(..count);
+    0
+}
+
+// Lists option states, either as a table (`set -o`) or as re-runnable commands (`set +o`)
+fn print_options(shell: &mut Shell, as_table: bool) {
+    for &(name, _) in SET_OPTIONS {
+        let enabled = shell.options.get(name).unwrap_or(false);
+        if as_table {
+            writeln!(
+                shell.out,
+                "{:<15}\t{}",
+                name,
+                if enabled { "on" } else { "off" }
+            )
+            .ok();
+        } else {
+            writeln!(
+                shell.out,
+                "set {}o {}",
+                if enabled { '-' } else { '+' },
+                name
+            )
+            .ok();
+        }
+    }
+}
+
+// Handler for the 'shopt' builtin command
+// `shopt -s name...` turns options on and `shopt -u name...` turns them off; otherwise
+// the named options, or all of them, are listed, as commands with -p, and -q only
+// sets the exit status: 0 if every named option is on
+// `shopt -s` and `shopt -u` alone list just the options that are on or off
+fn shopt_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, names) = match parse_options(args, "supq") {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return usage_error(shell, "shopt", "shopt [-pqsu] [optname ...]", &message)
+        }
+    };
+    let has = |flag: char| options.iter().any(|&(option, _)| option == flag);
+    let enable = match (has('s'), has('u')) {
+        (true, true) => {
+            shell.print_error("shopt", "cannot set and unset shell options simultaneously");
+            return 1;
+        }
+        (true, false) => Some(true),
+        (false, true) => Some(false),
+        (false, false) => None,
+    };
+
+    if let Some(name) = names.iter().find(|name| !SHOPT_OPTIONS.contains(name)) {
+        shell.print_error("shopt", &format!("{}: invalid shell option name", name));
+        return 1;
+    }
+
+    if let (Some(enable), false) = (enable, names.is_empty()) {
+        for name in names {
+            shell.options.set(name, enable);
+        }
+        return 0;
+    }
+
+    let listed: Vec<&str> = if names.is_empty() {
+        SHOPT_OPTIONS.to_vec()
+    } else {
+        names.to_vec()
+    };
+    let mut status = 0;
+    for name in listed {
+        let enabled = shell.options.get(name).unwrap_or(false);
+        if !enabled {
+            status = 1;
+        }
+        if has('q') || enable.is_some_and(|enable| enable != enabled) {
+            continue;
+        }
+        if has('p') {
+            writeln!(
+                shell.out,
+                "shopt {} {}",
+                if enabled { "-s" } else { "-u" },
+                name
+            )
+            .ok();
+        } else {
+            writeln!(
+                shell.out,
+                "{:<15}\t{}",
+                name,
+                if enabled { "on" } else { "off" }
+            )
+            .ok();
+        }
+    }
+    // Listing every option always succeeds
+    if names.is_empty() {
+        0
+    } else {
+        status
+    }
+}
+
+// Handler for the 'source' (and '.') builtin command
+// Reads a file and runs its commands in the current shell, until a return if there is one
+// A name without a slash is looked for in PATH first, then in the current directory
+fn source_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let operands = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => {
+            return usage_error(shell, args[0], &format!("{} filename", args[0]), &message)
+        }
+    };
+    match operands.first() {
+        Some(name) => {
+            let path = if name.contains('/') {
+                None
+            } else {
+                find_file_in_path(name)
+            };
+            shell.call_stack.push(CallFrame {
+                name: "source".to_string(),
+                file: shell.source_name.clone(),
+                line: shell.line_number,
+            });
+            let result = shell.source_file(path.as_deref().unwrap_or(name));
+            shell.call_stack.pop();
+            // A return in the file only ends the file
+            if shell.loop_control == Some(LoopControl::Return) {
+                shell.loop_control = None;
+            }
+            match result {
+                Ok(status) => status,
+                Err(e) => {
+                    shell.print_error(args[0], &e);
+                    1
+                }
+            }
+        }
+        None => {
+            shell.print_error(args[0], "filename argument required");
+            2
+        }
+    }
+}
+
+// Handler for the 'caller' builtin command
+// Prints where the running function or sourced file was called from, as `LINE FILE`
+// `caller N` goes N calls further out and adds the function the call was made from,
+// as `LINE NAME FILE`, where NAME is "main" outside any function, or "source" in a
+// sourced file
+// Fails when there's no such call, as at the top level
+fn caller_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let operands = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => return usage_error(shell, "caller", "caller [expr]", &message),
+    };
+    let depth = match operands.first() {
+        None => 0,
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(depth) => depth,
+            Err(_) => {
+                return usage_error(
+                    shell,
+                    "caller",
+                    "caller [expr]",
+                    &format!("{}: invalid number", arg),
+                )
+            }
+        },
+    };
+    let mut frames = shell.call_stack.iter().rev().skip(depth);
+    let Some(frame) = frames.next() else {
+        return 1;
+    };
+    if !operands.is_empty() {
+        let caller = frames.next().map_or("main", |outer| &outer.name);
+        writeln!(shell.out, "{} {} {}", frame.line, caller, frame.file).ok();
+    } else {
+        writeln!(shell.out, "{} {}", frame.line, frame.file).ok();
+    }
+    0
+}
+
+// Handler for the 'type' builtin command
+// Tells you what kind of command each name is (function, builtin, external program, or
+// not found)
+// With -a, every place a name is found is listed rather than just the one that runs
+// With -R, a program found through symlinks is also shown with the real file they lead to
+fn type_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, operands) = match parse_options(args, "aR") {
+        Ok(parsed) => parsed,
+        Err(message) => return usage_error(shell, "type", "type [-aR] name [name ...]", &message),
+    };
+    let all = options.iter().any(|&(flag, _)| flag == 'a');
+    let resolve = options.iter().any(|&(flag, _)| flag == 'R');
+
+    // Check if the user provided a command name to look up
+    if operands.is_empty() {
+        shell.print_error("type", "missing operand");
+        return 1;
+    }
+
+    let mut status = 0;
+    for &cmd in operands {
+        let mut found = false;
+        // A function is run before anything else of the same name
+        if let Some(function) = shell.functions.get(cmd).cloned() {
+            writeln!(shell.out, "{} is a function", cmd).ok();
+            writeln!(shell.out, "{}", function.definition.text).ok();
+            found = true;
+        }
+        // Check if the command exists in our builtin registry next
+        if shell.is_builtin(cmd) && (!found || all) {
+            writeln!(shell.out, "{} is a shell builtin", cmd).ok();
+            found = true;
+        }
+        // A program that's been run is where it was found last time, unless PATH has
+        // changed since
+        if let Some((path, _)) = shell.command_hash.get(cmd).filter(|_| !found && !all) {
+            writeln!(shell.out, "{} is hashed ({})", cmd, path).ok();
+            found = true;
+        }
+        if !found || all {
+            // Then for executables in PATH
+            for executable_path in executables_in_path(cmd) {
+                // Like `vi is /usr/bin/vi -> /usr/bin/vim` when resolving symlinks
+                let real_path = fs::canonicalize(&executable_path)
+                    .ok()
+                    .filter(|real_path| resolve && *real_path != Path::new(&executable_path));
+                match real_path {
+                    Some(real_path) => {
+                        writeln!(
+                            shell.out,
+                            "{} is {} -> {}",
+                            cmd,
+                            executable_path,
+                            real_path.display()
+                        )
+                        .ok();
+                    }
+                    None => {
+                        writeln!(shell.out, "{} is {}", cmd, executable_path).ok();
+                    }
+                }
+                found = true;
+                if !all {
+                    break;
+                }
+            }
+        }
+        if !found {
+            // Command not found as a builtin or in PATH
+            writeln!(shell.out, "{}: not found", cmd).ok();
+            status = 1;
+        }
+    }
+    status
+}
+
+// Handler for the 'builtin' builtin command
+// Runs the named builtin with the rest of the arguments, never a function or external
+// program of the same name
+// This is how a function named after a builtin reaches the real one, as a cd function
+// wrapping `builtin cd "$@"` does, without calling itself again
+fn builtin_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let operands = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => {
+            return usage_error(
+                shell,
+                "builtin",
+                "builtin [shell-builtin [arg ...]]",
+                &message,
+            )
+        }
+    };
+    let Some(&name) = operands.first() else {
+        return 0;
+    };
+    match shell.builtin_handler(name) {
+        Some(handler) => handler(shell, operands),
+        None => {
+            shell.print_error("builtin", &format!("{}: not a shell builtin", name));
+            1
+        }
+    }
+}
+
+// Handler for the 'exec' builtin command
+// Replaces the shell with the given program, which keeps the shell's process ID
+// Without a command the redirections given with exec stay in place for the rest of
+// the shell's life instead; that's handled where redirections are applied, as this
+// handler never sees them
+fn exec_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let operands = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => {
+            return usage_error(shell, "exec", "exec [command [argument ...]]", &message)
+        }
+    };
+    if operands.is_empty() {
+        return 0;
+    }
+    shell.exec_program(operands)
+}
+
+// Handler for the 'printenv' builtin command
+// With no names, prints the whole environment as NAME=value lines; otherwise prints the
+// value of each name given on a line of its own, failing if any of them isn't set
+fn printenv_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let names = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => return usage_error(shell, "printenv", "printenv [name ...]", &message),
+    };
+    if names.is_empty() {
+        for (name, value) in env::vars_os() {
+            writeln!(
+                shell.out,
+                "{}={}",
+                name.to_string_lossy(),
+                value.to_string_lossy()
+            )
+            .ok();
+        }
+        return 0;
+    }
+    let mut status = 0;
+    for name in names {
+        match env::var_os(name) {
+            Some(value) => {
+                writeln!(shell.out, "{}", value.to_string_lossy()).ok();
+            }
+            None => status = 1,
+        }
+    }
+    status
+}
+
+// Handler for the 'declare' (and 'typeset') builtin command
+// Sets variables and their attributes: -a array, -i integer, -r readonly, -x exported
+// A + instead of - removes an attribute; -p prints variables as declare commands
+// With no names, lists all variables (or those with the given attributes)
+fn declare_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let mut add = Vec::new();
+    let mut remove = Vec::new();
+    let mut print = false;
+    let mut i = 1;
+
+    while let Some(arg) = args.get(i) {
+        let enable = arg.starts_with('-');
+        if arg.len() < 2 || (!enable && !arg.starts_with('+')) {
+            break;
+        }
+        i += 1;
+        if *arg == "--" {
+            break;
+        }
+        for flag in arg[1..].chars() {
+            match flag {
+                'a' | 'i' | 'r' | 'x' if enable => add.push(flag),
+                'a' => {
+                    shell.print_error(args[0], "+a: cannot destroy array variables in this way");
+                    return 1;
+                }
+                'r' => {
+                    shell.print_error(args[0], "+r: cannot remove the readonly attribute");
+                    return 1;
+                }
+                'i' | 'x' => remove.push(flag),
+                'p' => print = true,
+                _ => {
+                    return usage_error(
+                        shell,
+                        args[0],
+                        &format!("{} [-aiprx] [name[=value] ...]", args[0]),
+                        &format!("{}{}: invalid option", &arg[..1], flag),
+                    )
+                }
+            }
+        }
+    }
+
+    let names = &args[i..];
+    if names.is_empty() {
+        for (name, variable) in shell.variables.sorted() {
+            let flags = attribute_flags(variable);
+            if !add.iter().all(|&flag| flags.contains(flag)) {
+                continue;
+            }
+            if print || !add.is_empty() {
+                writeln!(shell.out, "{}", declare_statement(name, variable)).ok();
+            } else {
+                writeln!(shell.out, "{}={}", name, quote_value(variable.scalar())).ok();
+            }
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in names {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (*arg, None),
+        };
+        if !is_valid_name(name) {
+            shell.print_error(args[0], &format!("`{}': not a valid identifier", arg));
+            status = 1;
+            continue;
+        }
+
+        if print && value.is_none() {
+            match shell.variables.get(name) {
+                Some(variable) => {
+                    writeln!(shell.out, "{}", declare_statement(name, variable)).ok();
+                }
+                None => {
+                    shell.print_error(args[0], &format!("{}: not found", name));
+                    status = 1;
+                }
+            }
+            continue;
+        }
+
+        if let Err(e) = declare_variable(shell, name, value, &add, &remove) {
+            shell.print_error(args[0], &e);
+            status = 1;
+        }
+    }
+    status
+}
+
+// Applies declare's attribute changes to one variable, then assigns its value if one was given
+fn declare_variable(
+    shell: &mut Shell,
+    name: &str,
+    value: Option<&str>,
+    add: &[char],
+    remove: &[char],
+) -> Result<(), String> {
+    let readonly = shell.variables.get(name).is_some_and(|var| var.readonly);
+    if readonly && (value.is_some() || !remove.is_empty()) {
+        return Err(format!("{}: readonly variable", name));
+    }
+
+    let variable = shell.variables.entry(name);
+    for &flag in add.iter().filter(|&&flag| flag != 'r') {
+        set_attribute(variable, flag, true);
+    }
+    for &flag in remove {
+        set_attribute(variable, flag, false);
+    }
+    if let Some(value) = value {
+        shell.set_var(name, value)?;
+    }
+    // Readonly goes on last, so that `declare -r NAME=value` can still set the value
+    if add.contains(&'r') {
+        shell.variables.entry(name).readonly = true;
+    }
+    shell.variables.sync_export(name);
+    Ok(())
+}
+
+fn set_attribute(variable: &mut Variable, flag: char, enabled: bool) {
+    match flag {
+        'a' => {
+            variable.value.make_array();
+        }
+        'i' => variable.integer = enabled,
+        'r' => variable.readonly = enabled,
+        'x' => variable.exported = enabled,
+        _ => {}
+    }
+}
+
+// The attribute letters of a variable, in the order declare prints them
+fn attribute_flags(variable: &Variable) -> String {
+    [
+        (matches!(variable.value, Value::Array(_)), 'a'),
+        (variable.integer, 'i'),
+        (variable.readonly, 'r'),
+        (variable.exported, 'x'),
+    ]
+    .iter()
+    .filter(|&&(set, _)| set)
+    .map(|&(_, flag)| flag)
+    .collect()
+}
+
+// Formats a variable as the declare command that would recreate it, like declare -x HOME="/root"
+// Arrays list their elements by index, like declare -a arr=([0]="a" [1]="b")
+fn declare_statement(name: &str, variable: &Variable) -> String {
+    let flags = attribute_flags(variable);
+    let value = match &variable.value {
+        Value::Scalar(value) => format!("\"{}\"", escape_double_quoted(value)),
+        Value::Array(elements) => {
+            let elements: Vec<String> = elements
+                .iter()
+                .map(|(index, value)| format!("[{}]=\"{}\"", index, escape_double_quoted(value)))
+                .collect();
+            format!("({})", elements.join(" "))
+        }
+    };
+    format!(
+        "declare -{} {}={}",
+        if flags.is_empty() { "-" } else { &flags },
+        name,
+        value
+    )
+}
+
+// Backslash-escapes the characters that are special inside double quotes
+fn escape_double_quoted(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| {
+            let escape = matches!(c, '"' | '\\' | '$' | '`').then_some('\\');
+            escape.into_iter().chain(Some(c))
+        })
+        .collect()
+}
+
+// Quotes a value so it can be read back by the shell, leaving simple values bare
+fn quote_value(value: &str) -> String {
+    let is_plain = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_./:,+@%=-".contains(c));
+    if is_plain && !value.is_empty() {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+// Handler for the 'readonly' builtin command
+// Marks variables readonly, assigning them first for NAME=value; -a makes them arrays
+// With no names, or with -p, lists the readonly variables as declare commands
+fn readonly_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, names) = match parse_options(args, "ap") {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return usage_error(
+                shell,
+                "readonly",
+                "readonly [-ap] [name[=value] ...]",
+                &message,
+            )
+        }
+    };
+
+    if names.is_empty() {
+        for (name, variable) in shell.variables.sorted() {
+            if variable.readonly {
+                writeln!(shell.out, "{}", declare_statement(name, variable)).ok();
+            }
+        }
+        return 0;
+    }
+
+    let mut add = vec!['r'];
+    if options.iter().any(|&(flag, _)| flag == 'a') {
+        add.push('a');
+    }
+    let mut status = 0;
+    for arg in names {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (*arg, None),
+        };
+        if !is_valid_name(name) {
+            shell.print_error("readonly", &format!("`{}': not a valid identifier", arg));
+            status = 1;
+            continue;
+        }
+        if let Err(e) = declare_variable(shell, name, value, &add, &[]) {
+            shell.print_error("readonly", &e);
+            status = 1;
+        }
+    }
+    status
+}
+
+// Handler for the 'unset' builtin command
+// Removes each named variable, or with -f each named function; readonly variables are
+// refused
+// Without either option a name that isn't a variable is taken as a function
+fn unset_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, names) = match parse_options(args, "fv") {
+        Ok(parsed) => parsed,
+        Err(message) => return usage_error(shell, "unset", "unset [-f] [-v] [name ...]", &message),
+    };
+    let functions = options.iter().any(|&(flag, _)| flag == 'f');
+    let variables = options.iter().any(|&(flag, _)| flag == 'v');
+
+    let mut status = 0;
+    for &name in names {
+        let as_function = functions || (!variables && shell.variables.get(name).is_none());
+        if as_function && (shell.functions.remove(name).is_some() || functions) {
+            continue;
+        }
+        if !is_valid_name(name) {
+            shell.print_error("unset", &format!("`{}': not a valid identifier", name));
+            status = 1;
+        } else if shell.variables.get(name).is_some_and(|var| var.readonly) {
+            shell.print_error(
+                "unset",
+                &format!("{}: cannot unset: readonly variable", name),
+            );
+            status = 1;
+        } else {
+            shell.variables.unset(name);
+            if name == "PATH" {
+                shell.command_hash.clear();
+            }
+        }
+    }
+    status
+}
+
+// Handler for the 'let' builtin command
+// Evaluates each argument as an arithmetic expression, as in `let "x = x + 1" y++`
+// References like $? are expanded first, so `let 'n = $? + 1'` works even when quoted
+// Returns 1 if the last expression evaluated to zero and 0 otherwise
+fn let_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    if args.len() < 2 {
+        shell.print_error("let", "expression expected");
+        return 1;
+    }
+    let mut last = 0;
+    for expression in &args[1..] {
+        let expression: Vec<char> = expression.chars().collect();
+        match expand::evaluate_expression(shell, &expression) {
+            Ok(value) => last = value,
+            Err(e) => {
+                shell.print_error("let", &e);
+                return 1;
+            }
+        }
+    }
+    if last == 0 {
+        1
+    } else {
+        0
+    }
+}
+
+// Handler for the 'jobs' builtin command
+// Lists background jobs with their status; finished jobs are forgotten once listed
+// -l adds each job's process ID and -p prints only the process IDs
+// Job specs like %1 limit the listing to those jobs
+fn jobs_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, specs) = match parse_options(args, "lp") {
+        Ok(parsed) => parsed,
+        Err(message) => return usage_error(shell, "jobs", "jobs [-lp] [jobspec ...]", &message),
+    };
+    let has = |flag: char| options.iter().any(|&(option, _)| option == flag);
+
+    shell.jobs.update();
+    let mut ids = Vec::new();
+    let mut status = 0;
+    for &spec in specs {
+        match shell.jobs.find(Some(spec)) {
+            Ok(id) => ids.push(id),
+            Err(e) => {
+                shell.print_error("jobs", &e);
+                status = 1;
+            }
+        }
+    }
+
+    for job in shell.jobs.iter() {
+        if !specs.is_empty() && !ids.contains(&job.id) {
+            continue;
+        }
+        let mark = shell.jobs.mark(job.id);
+        if has('p') {
+            writeln!(shell.out, "{}", job.pgid).ok();
+        } else if has('l') {
+            writeln!(shell.out, "{}", jobs::format_job_with_pid(job, mark)).ok();
+        } else {
+            writeln!(shell.out, "{}", jobs::format_job(job, mark)).ok();
+        }
+    }
+    shell.jobs.take_finished();
+    status
+}
+
+// Handler for the 'fg' builtin command
+// Brings a job (the current one, or one named like %2) into the foreground,
+// carrying it on if it was stopped, and waits for it
+fn fg_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let job = match take_job(shell, args) {
+        Ok(job) => job,
+        Err(status) => return status,
+    };
+    writeln!(shell.out, "{}", job.command).ok();
+    shell.continue_job(job, true).unwrap_or_else(|e| {
+        shell.print_error("fg", &e);
+        1
+    })
+}
+
+// Handler for the 'bg' builtin command
+// Carries on a stopped job (the current one, or one named like %2) in the background
+fn bg_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let job = match take_job(shell, args) {
+        Ok(job) => job,
+        Err(status) => return status,
+    };
+    if job.status() == jobs::JobStatus::Running {
+        shell.print_error("bg", &format!("job {} already in background", job.id));
+        shell.jobs.add(job);
+        return 0;
+    }
+    writeln!(shell.out, "[{}]+ {} &", job.id, job.command).ok();
+    match shell.continue_job(job, false) {
+        Ok(status) => status,
+        Err(e) => {
+            shell.print_error("bg", &e);
+            1
+        }
+    }
+}
+
+// Handler for the 'disown' builtin command
+// Removes jobs (the current one, or those named like %2) from the job table, so they
+// are no longer reported and aren't sent SIGHUP when the shell exits
+// -a disowns every job, and -h keeps the jobs in the table but still spares them SIGHUP
+fn disown_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, specs) = match parse_options(args, "ah") {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return usage_error(shell, "disown", "disown [-ah] [jobspec ...]", &message)
+        }
+    };
+    let has = |flag: char| options.iter().any(|&(option, _)| option == flag);
+
+    shell.jobs.update();
+    let mut ids = Vec::new();
+    let mut status = 0;
+    if has('a') {
+        ids.extend(shell.jobs.iter().map(|job| job.id));
+    } else if specs.is_empty() {
+        match shell.jobs.find(None) {
+            Ok(id) => ids.push(id),
+            Err(e) => {
+                shell.print_error("disown", &e);
+                return 1;
+            }
+        }
+    } else {
+        for &spec in specs {
+            match shell.jobs.find(Some(spec)) {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    shell.print_error("disown", &e);
+                    status = 1;
+                }
+            }
+        }
+    }
+
+    for id in ids {
+        if has('h') {
+            if let Some(job) = shell.jobs.get_mut(id) {
+                job.no_hang_up = true;
+            }
+        } else {
+            shell.jobs.remove(id);
+        }
+    }
+    status
+}
+
+// Handler for the 'wait' builtin command
+// Waits for background jobs, named like %1 or by the process ID of one of their
+// processes, or with none named for every job, and returns the status of the last
+// one named (0 when none are, whatever the jobs' own statuses)
+// With -n it waits only until the next of those jobs finishes, or one that already
+// has, and returns that job's status; 127 if there are none to wait for
+// Finished jobs are forgotten once they've been waited for, and Ctrl-C cuts the
+// wait short with status 130
+fn wait_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, operands) = match parse_options(args, "n") {
+        Ok(parsed) => parsed,
+        Err(message) => return usage_error(shell, "wait", "wait [-n] [id ...]", &message),
+    };
+    let next = !options.is_empty();
+
+    shell.jobs.update();
+    // The job each operand names, or None for one that isn't a job of this shell
+    let mut targets = Vec::new();
+    for &operand in operands {
+        let id = if operand.starts_with('%') {
+            shell.jobs.find(Some(operand))
+        } else {
+            match operand.parse::<i32>() {
+                Ok(pid) => shell
+                    .jobs
+                    .iter()
+                    .find(|job| job.processes.iter().any(|&(other, _)| other == pid))
+                    .map(|job| job.id)
+                    .ok_or_else(|| format!("pid {} is not a child of this shell", pid)),
+                Err(_) => Err(format!("`{}': not a pid or valid job spec", operand)),
+            }
+        };
+        match id {
+            Ok(id) => targets.push(Some(id)),
+            Err(e) => {
+                shell.print_error("wait", &e);
+                targets.push(None);
+            }
+        }
+    }
+    if operands.is_empty() {
+        targets.extend(shell.jobs.iter().map(|job| Some(job.id)));
+    }
+
+    if next {
+        let ids: Vec<usize> = targets.into_iter().flatten().collect();
+        if ids.is_empty() {
+            return 127;
+        }
+        let finished = wait_for_jobs(shell, |jobs| {
+            jobs.iter()
+                .find(|job| ids.contains(&job.id) && job.is_finished())
+                .map(|job| job.id)
+        });
+        return match finished.and_then(|id| shell.jobs.remove(id)) {
+            Some(job) => job.status().exit_code(),
+            None => 130,
+        };
+    }
+
+    let mut status = 0;
+    for target in targets {
+        let Some(id) = target else {
+            status = 127;
+            continue;
+        };
+        // A stopped job counts as waited for, but is kept so it can be carried on
+        let waited = wait_for_jobs(shell, |jobs| {
+            jobs.iter()
+                .find(|job| job.id == id && job.status() != jobs::JobStatus::Running)
+                .map(|job| job.id)
+        });
+        if waited.is_none() {
+            return 130;
+        }
+        let Some(job) = shell.jobs.remove(id) else {
+            continue;
+        };
+        if !operands.is_empty() {
+            status = job.status().exit_code();
+        }
+        if !job.is_finished() {
+            shell.jobs.add(job);
+        }
+    }
+    status
+}
+
+// Checks the job table every few milliseconds until `ready` picks out a job from it,
+// and returns that job's number, or None if Ctrl-C stopped the wait first
+fn wait_for_jobs(
+    shell: &mut Shell,
+    ready: impl Fn(&jobs::JobTable) -> Option<usize>,
+) -> Option<usize> {
+    let catcher = sys::catch_interrupts();
+    loop {
+        shell.jobs.update();
+        if let Some(id) = ready(&shell.jobs) {
+            return Some(id);
+        }
+        if catcher.interrupted() {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Takes the job named by fg's or bg's argument out of the job table, reporting
+// why not if it can't and returning the status to fail with
+fn take_job(shell: &mut Shell, args: &[&str]) -> Result<jobs::Job, i32> {
+    let operands = match parse_options(args, "") {
+        Ok((_, operands)) => operands,
+        Err(message) => {
+            return Err(usage_error(
+                shell,
+                args[0],
+                &format!("{} [job_spec]", args[0]),
+                &message,
+            ))
+        }
+    };
+    if !shell.job_control {
+        shell.print_error(args[0], "no job control");
+        return Err(1);
+    }
+    shell.jobs.update();
+    let id = match shell.jobs.find(operands.first().copied()) {
+        Ok(id) => id,
+        Err(e) => {
+            shell.print_error(args[0], &e);
+            return Err(1);
+        }
+    };
+    let job = shell.jobs.remove(id).ok_or(1)?;
+    if job.is_finished() {
+        shell.print_error(args[0], "job has terminated");
+        shell.jobs.add(job);
+        return Err(1);
+    }
+    Ok(job)
+}
+
+// Handler for the 'history' builtin command
+// Lists the history with each entry's number, or just the last N entries, or with -c
+// clears it
+// With HISTTIMEFORMAT set, each entry is shown after the time it was entered, in that
+// strftime format
+fn history_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, operands) = match parse_options(args, "c") {
+        Ok(parsed) => parsed,
+        Err(message) => return usage_error(shell, "history", "history [-c] [n]", &message),
+    };
+    if !options.is_empty() {
+        shell.history.clear();
+        return 0;
+    }
+    let entries = shell.history.entries();
+    let count = match operands {
+        [] => entries.len(),
+        [count] => match count.parse::<usize>() {
+            Ok(count) => count.min(entries.len()),
+            Err(_) => {
+                shell.print_error("history", &format!("{}: numeric argument required", count));
+                return 2;
+            }
+        },
+        _ => {
+            shell.print_error("history", "too many arguments");
+            return 2;
+        }
+    };
+
+    let time_format = shell.get_var("HISTTIMEFORMAT");
+    let mut lines = Vec::new();
+    for (index, entry) in entries.iter().enumerate().skip(entries.len() - count) {
+        let time = match (&time_format, shell.history.time(index)) {
+            (Some(format), Some(time)) => sys::format_local_time(time, format),
+            _ => String::new(),
+        };
+        lines.push(format!("{:>5}  {}{}", index + 1, time, entry));
+    }
+    for line in lines {
+        writeln!(shell.out, "{}", line).ok();
+    }
+    0
+}
+
+// Handler for the 'fc' builtin command
+// Lists (-l), edits and re-runs, or (-s) directly re-runs commands from the history
+// Commands are picked by number (negative numbers count back from the latest) or by
+// the start of their text; the default is the latest command, or the last 16 for -l
+//   fc -l [-nr] [first [last]]
+//   fc [-e editor] [first [last]]
+//   fc -s [old=new] [command]
+fn fc_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let mut list = false;
+    let mut numbers = true;
+    let mut reverse = false;
+    let mut substitute = false;
+    let mut editor = None;
+    let mut i = 1;
+
+    // Options end at the first argument that isn't one, like a history number such as -2
+    while let Some(arg) = args.get(i) {
+        let Some(flags) = arg.strip_prefix('-') else {
+            break;
+        };
+        if flags.is_empty() || flags.starts_with(|c: char| c.is_ascii_digit()) {
+            break;
+        }
+        i += 1;
+        if flags == "-" {
+            break;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'l' => list = true,
+                'n' => numbers = false,
+                'r' => reverse = true,
+                's' => substitute = true,
+                'e' => {
+                    let Some(name) = args.get(i) else {
+                        shell.print_error("fc", "-e: option requires an argument");
+                        return 2;
+                    };
+                    editor = Some(name.to_string());
+                    i += 1;
+                }
+                _ => {
+                    return usage_error(
+                        shell,
+                        "fc",
+                        "fc [-e ename] [-lnr] [first] [last] or fc -s [pat=rep] [command]",
+                        &format!("-{}: invalid option", flag),
+                    )
+                }
+            }
+        }
+    }
+    let operands = &args[i..];
+
+    // At the prompt, this fc command is already the latest entry, unless HISTCONTROL
+    // kept it out, and isn't one to pick
+    let entries = shell.history.entries();
+    let count = entries.len() - usize::from(shell.line_in_history && !entries.is_empty());
+    let entries = entries[..count].to_vec();
+    if entries.is_empty() {
+        shell.print_error("fc", "no command found");
+        return 1;
+    }
+    let find = |spec: &str| find_history_entry(&entries, spec);
+
+    if substitute || editor.as_deref() == Some("-") {
+        let (replacement, spec) = match operands.first() {
+            Some(operand) if operand.contains('=') => (operand.split_once('='), operands.get(1)),
+            spec => (None, spec),
+        };
+        let Some(index) = spec.map_or(Some(count - 1), |spec| find(spec)) else {
+            shell.print_error("fc", "no command found");
+            return 1;
+        };
+        let command = match replacement {
+            Some((old, new)) if !old.is_empty() => entries[index].replace(old, new),
+            _ => entries[index].clone(),
+        };
+        writeln!(shell.out, "{}", command).ok();
+        record_fc_command(shell, &command);
+        return shell.run_line(&command);
+    }
+
+    let default_first = if list {
+        count.saturating_sub(16)
+    } else {
+        count - 1
+    };
+    let first = match operands.first() {
+        Some(spec) => find(spec),
+        None => Some(default_first),
+    };
+    let last = match operands.get(1) {
+        Some(spec) => find(spec),
+        None if list => Some(count - 1),
+        None => first,
+    };
+    let (Some(first), Some(last)) = (first, last) else {
+        shell.print_error("fc", "history specification out of range");
+        return 1;
+    };
+    // A range given backwards lists the commands backwards
+    let (reverse, first, last) = if first > last {
+        (!reverse, last, first)
+    } else {
+        (reverse, first, last)
+    };
+    let mut selected: Vec<(usize, &String)> =
+        (first..=last).map(|i| (i + 1, &entries[i])).collect();
+    if reverse {
+        selected.reverse();
+    }
+
+    if list {
+        for (number, command) in selected {
+            if numbers {
+                writeln!(shell.out, "{}\t{}", number, command).ok();
+            } else {
+                writeln!(shell.out, "\t{}", command).ok();
+            }
+        }
+        return 0;
+    }
+
+    // Edit the commands in a temporary file, then run whatever was saved
+    let editor = editor
+        .or_else(|| shell.get_var("FCEDIT"))
+        .or_else(|| shell.get_var("EDITOR"))
+        .filter(|editor| !editor.is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+    let path = env::temp_dir().join(format!("fc-{}.sh", process::id()));
+    let text: String = selected
+        .iter()
+        .map(|(_, command)| format!("{}\n", command))
+        .collect();
+    if let Err(e) = fs::write(&path, text) {
+        shell.print_error("fc", &format!("{}: {}", path.display(), e));
+        return 1;
+    }
+    let path = path.to_string_lossy().into_owned();
+    let mut status = shell.run_line(&format!("{} {}", editor, path));
+    if status == 0 {
+        if let Ok(edited) = fs::read_to_string(&path) {
+            write!(shell.out, "{}", edited).ok();
+            record_fc_command(shell, &edited);
+            status = shell.source_file(&path).unwrap_or(1);
+        }
+    }
+    let _ = fs::remove_file(&path);
+    status
+}
+
+// Puts the command fc runs in the history in place of the fc command itself, or just
+// adds it if HISTCONTROL kept the fc command out
+fn record_fc_command(shell: &mut Shell, command: &str) {
+    if shell.line_in_history {
+        shell.history.replace_last(command);
+    } else {
+        shell.history.add(command);
+    }
+}
+
+// Finds the history entry a spec for fc refers to and returns its index
+// A positive number is a history number, a negative one counts back from the latest
+// entry, and anything else picks the latest entry starting with it
+fn find_history_entry(entries: &[String], spec: &str) -> Option<usize> {
+    match spec.parse::<i64>() {
+        Ok(number) if number > 0 => Some((number as usize).min(entries.len()) - 1),
+        Ok(number) => Some(
+            entries
+                .len()
+                .saturating_sub(number.unsigned_abs() as usize)
+                .min(entries.len() - 1),
+        ),
+        Err(_) => entries.iter().rposition(|entry| entry.starts_with(spec)),
+    }
+}
+
+// Handler for the 'read' builtin command
+// Reads a line from stdin and splits it into words on the characters in IFS
+// Each name gets one word and the last name gets the rest of the line; with no names
+// the whole line goes into REPLY, and with -a ARRAY the words fill an array
+// Backslashes escape the next character unless -r is given; -p PROMPT prints a prompt
+// Returns 1 if the end of input was reached before a newline
+fn read_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, names) = match parse_options(args, "ra:p:st:") {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return usage_error(
+                shell,
+                "read",
+                "read [-rs] [-a array] [-p prompt] [-t timeout] [name ...]",
+                &message,
+            )
+        }
+    };
+    let mut raw = false;
+    let mut array = None;
+    let mut prompt = None;
+    let mut silent = false;
+    let mut timeout = None;
+    for (flag, value) in options {
+        match flag {
+            'r' => raw = true,
+            's' => silent = true,
+            't' => {
+                let value = value.unwrap_or_default();
+                match value
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                {
+                    Some(duration) => timeout = Some(duration),
+                    None => {
+                        shell.print_error(
+                            "read",
+                            &format!("{}: invalid timeout specification", value),
+                        );
+                        return 1;
+                    }
+                }
+            }
+            'a' => array = value,
+            _ => prompt = value,
+        }
+    }
+    if let Some(name) = array.iter().chain(names).find(|name| !is_valid_name(name)) {
+        shell.print_error("read", &format!("`{}': not a valid identifier", name));
+        return 1;
+    }
+
+    // Like bash, -t 0 reads nothing and just reports whether there's input waiting
+    if timeout == Some(Duration::ZERO) {
+        return match sys::wait_readable(0, Duration::ZERO) {
+            Ok(true) => 0,
+            _ => 1,
+        };
+    }
+
+    // Like bash, the prompt only appears when reading from a terminal
+    if let Some(prompt) = prompt {
+        if sys::is_terminal(0) {
+            write!(shell.err, "{}", prompt).ok();
+        }
+    }
+
+    // With -s the typed characters aren't echoed, and the terminal is put back as it
+    // was when the guard is dropped, however reading ends
+    let silence = if silent && sys::is_terminal(0) {
+        SilentInput::enable().ok()
+    } else {
+        None
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut line = Vec::new();
+    let result = read_input_line(&mut line, raw, silence.is_some(), deadline);
+    drop(silence);
+    // Running out of time throws away whatever was typed so far, leaving the variables
+    // empty, and as in bash reports 128 + SIGALRM
+    let mut timed_out = false;
+    let complete = match result {
+        Ok(complete) => complete,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+            writeln!(shell.err).ok();
+            return 130;
+        }
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            line.clear();
+            timed_out = true;
+            false
+        }
+        Err(e) => {
+            shell.print_error("read", &format!("read error: {}", e));
+            return 1;
+        }
+    };
+
+    let ifs = shell.get_var("IFS").unwrap_or_else(|| " \t\n".to_string());
+    let result = if let Some(array) = array {
+        let words = split_fields(&line, ifs.as_bytes(), usize::MAX);
+        shell.set_array(array, words.into_iter().enumerate().collect())
+    } else if names.is_empty() {
+        let bytes: Vec<u8> = line.iter().map(|&(byte, _)| byte).collect();
+        shell.set_var("REPLY", &String::from_utf8_lossy(&bytes))
+    } else {
+        let mut words = split_fields(&line, ifs.as_bytes(), names.len()).into_iter();
+        names
+            .iter()
+            .try_for_each(|name| shell.set_var(name, &words.next().unwrap_or_default()))
+    };
+    if let Err(e) = result {
+        shell.print_error("read", &e);
+        return 1;
+    }
+
+    if timed_out {
+        142
+    } else if complete {
+        0
+    } else {
+        1
+    }
+}
+
+// Turns off echo on the terminal for read -s, until dropped
+struct SilentInput {
+    original: sys::Termios,
+}
+
+impl SilentInput {
+    fn enable() -> io::Result<SilentInput> {
+        let original = sys::get_termios(0)?;
+        let mut silent = original;
+        silent.make_silent();
+        sys::set_termios(0, &silent)?;
+        Ok(SilentInput { original })
+    }
+}
+
+impl Drop for SilentInput {
+    fn drop(&mut self) {
+        let _ = sys::set_termios(0, &self.original);
+    }
+}
+
+// Reads one line from stdin a byte at a time, so nothing after the newline is consumed
+// Adds each byte to `line` with whether it was escaped by a backslash, and returns
+// whether the line ended with a newline rather than the end of input
+// Unless `raw`, a backslash escapes the next byte, and backslash-newline continues the line
+// With `terminal_keys`, as when the terminal's own line editing is off for read -s,
+// Backspace, Ctrl-U, Ctrl-D and Ctrl-C are handled here, Ctrl-C as an Interrupted error
+// With a deadline, the whole line must arrive by then or reading fails with TimedOut,
+// leaving what did arrive in `line`
+pub fn read_input_line(
+    line: &mut Vec<(u8, bool)>,
+    raw: bool,
+    terminal_keys: bool,
+    deadline: Option<Instant>,
+) -> io::Result<bool> {
+    let mut escaped = false;
+    let mut byte = [0u8; 1];
+    loop {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !sys::wait_readable(0, remaining)? {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+        }
+        if sys::read_fd(0, &mut byte)? == 0 {
+            return Ok(false);
+        }
+        if terminal_keys {
+            match byte[0] {
+                0x03 => return Err(io::ErrorKind::Interrupted.into()),
+                0x04 if line.is_empty() && !escaped => return Ok(false),
+                0x7f | 0x08 => {
+                    if !std::mem::take(&mut escaped) {
+                        line.pop();
+                    }
+                    continue;
+                }
+                0x15 => {
+                    line.clear();
+                    escaped = false;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        match byte[0] {
+            b'\n' if escaped => escaped = false,
+            b'\n' => return Ok(true),
+            b'\\' if !raw && !escaped => escaped = true,
+            other => {
+                line.push((other, escaped));
+                escaped = false;
+            }
+        }
+    }
+}
+
+// Splits a line read by `read` into at most `count` words on the characters in `ifs`
+// Runs of IFS whitespace count as one separator and are trimmed from the ends, while
+// every other IFS character separates a word on its own; escaped bytes never separate
+// The last word gets the rest of the line, separators included
+fn split_fields(line: &[(u8, bool)], ifs: &[u8], count: usize) -> Vec<String> {
+    let is_separator = |&(byte, escaped): &(u8, bool)| !escaped && ifs.contains(&byte);
+    let is_space = |entry: &(u8, bool)| is_separator(entry) && b" \t\n".contains(&entry.0);
+    let text = |entries: &[(u8, bool)]| {
+        let bytes: Vec<u8> = entries.iter().map(|&(byte, _)| byte).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+    let skip_spaces = |mut position: usize| {
+        while line.get(position).is_some_and(is_space) {
+            position += 1;
+        }
+        position
+    };
+
+    let mut words = Vec::new();
+    let mut position = skip_spaces(0);
+    while position < line.len() {
+        if words.len() + 1 == count {
+            let mut end = line.len();
+            while end > position && is_space(&line[end - 1]) {
+                end -= 1;
+            }
+            words.push(text(&line[position..end]));
+            break;
+        }
+        let start = position;
+        while line.get(position).is_some_and(|entry| !is_separator(entry)) {
+            position += 1;
+        }
+        words.push(text(&line[start..position]));
+        // A separator is IFS whitespace around at most one other IFS character
+        position = skip_spaces(position);
+        if line
+            .get(position)
+            .is_some_and(|entry| is_separator(entry) && !is_space(entry))
+        {
+            position = skip_spaces(position + 1);
+        }
+    }
+    words
+}
+
+// Handler for the 'mapfile' (and 'readarray') builtin command
+// Reads all of stdin into an indexed array, one line per element (MAPFILE by default)
+// With -t the newline at the end of each line is removed
+fn mapfile_command(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (options, operands) = match parse_options(args, "t") {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return usage_error(
+                shell,
+                args[0],
+                &format!("{} [-t] [array]", args[0]),
+                &message,
+            )
+        }
+    };
+    let trim = !options.is_empty();
+
+    let name = operands.first().copied().unwrap_or("MAPFILE");
+    if !is_valid_name(name) {
+        shell.print_error(args[0], &format!("`{}': not a valid identifier", name));
+        return 1;
+    }
+
+    let mut input = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        match sys::read_fd(0, &mut buffer) {
+            Ok(0) => break,
+            Ok(count) => input.extend_from_slice(&buffer[..count]),
+            Err(e) => {
+                shell.print_error(args[0], &format!("read error: {}", e));
+                return 1;
+            }
+        }
+    }
+
+    let lines = input
+        .split_inclusive(|&byte| byte == b'\n')
+        .map(|line| {
+            let line = match line.strip_suffix(b"\n") {
+                Some(stripped) if trim => stripped,
+                _ => line,
+            };
+            String::from_utf8_lossy(line).into_owned()
+        })
+        .enumerate()
+        .collect();
+    if let Err(e) = shell.set_array(name, lines) {
+        shell.print_error(args[0], &e);
+        return 1;
+    }
+    0
+}
@@ -0,0 +1,364 @@
+// Tab completion of command names and file paths for the line editor
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::editor::Completer;
+use crate::options::ShellOptions;
+use crate::shell::{is_executable, Shell};
+
+// Characters that have to be escaped with a backslash when inserted into the command line
+const SPECIAL_CHARS: &str = " \t\\'\"$`&|;<>()*?[]{}!#";
+
+impl Completer for Shell {
+    fn complete(&mut self, line: &str) -> (usize, Vec<String>) {
+        let chars: Vec<char> = line.chars().collect();
+        let start = word_start(&chars);
+        let word = unescape(&chars[start..]);
+
+        // The first word of a command names a program unless it's written as a path
+        let in_command_position = chars[..start]
+            .iter()
+            .rev()
+            .find(|c| !c.is_whitespace())
+            .map_or(true, |&c| matches!(c, '|' | ';' | '&' | '('));
+
+        // Arguments of a command set up with the 'complete' builtin complete as it says
+        let mut words = command_words(&chars[..start]);
+        words.push(word.clone());
+        let spec = words
+            .first()
+            .filter(|_| !in_command_position)
+            .and_then(|name| self.completion_specs.get(name))
+            .cloned();
+
+        let candidates = if let Some(spec) = spec {
+            spec_candidates(self, &spec, &words)
+                .into_iter()
+                .map(|candidate| {
+                    if spec.completes_paths() && Path::new(&candidate).is_dir() {
+                        format!("{}/", escape(&candidate))
+                    } else {
+                        format!("{} ", escape(&candidate))
+                    }
+                })
+                .collect()
+        } else if in_command_position && !word.contains('/') {
+            command_candidates(self, &word)
+                .into_iter()
+                .map(|name| format!("{} ", escape(&name)))
+                .collect()
+        } else {
+            path_candidates(&self.options, &word)
+                .into_iter()
+                .map(|path| match path.strip_suffix('/') {
+                    Some(dir) => format!("{}/", escape(dir)),
+                    None => format!("{} ", escape(&path)),
+                })
+                .collect()
+        };
+        (start, candidates)
+    }
+
+    fn columns(&self) -> Option<usize> {
+        let columns = self.get_var("COLUMNS")?.trim().parse().ok()?;
+        (columns > 0).then_some(columns)
+    }
+}
+
+// How to complete the arguments of a command, as set with the 'complete' builtin: the
+// compgen options that make the candidates, each letter with its value if it takes one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompletionSpec {
+    pub actions: Vec<(char, Option<String>)>,
+}
+
+impl CompletionSpec {
+    // Picks the options that make candidates out of those given to compgen or complete
+    pub fn new(options: &[(char, Option<&str>)]) -> Self {
+        let actions = options
+            .iter()
+            .filter(|(flag, _)| "bcdfvFW".contains(*flag))
+            .map(|&(flag, value)| (flag, value.map(|value| value.to_string())))
+            .collect();
+        CompletionSpec { actions }
+    }
+
+    // The options as they'd be given to complete, like `-f -W 'start stop'`
+    pub fn describe(&self) -> String {
+        let options: Vec<String> = self
+            .actions
+            .iter()
+            .map(|(flag, value)| match value {
+                Some(value) if *flag == 'W' => {
+                    format!("-{} '{}'", flag, value.replace('\'', "'\\''"))
+                }
+                Some(value) => format!("-{} {}", flag, value),
+                None => format!("-{}", flag),
+            })
+            .collect();
+        options.join(" ")
+    }
+
+    // Returns true if the candidates can be files, which complete with a / after a
+    // directory rather than a space
+    fn completes_paths(&self) -> bool {
+        self.actions
+            .iter()
+            .any(|&(flag, _)| flag == 'f' || flag == 'd')
+    }
+}
+
+// Returns what a completion spec gives for the last of `words`, the words of the
+// command typed so far, with the candidates of each of its options in turn without
+// repeats, as compgen prints them
+// Directories come without the / that path completion adds to them
+pub fn spec_candidates(shell: &mut Shell, spec: &CompletionSpec, words: &[String]) -> Vec<String> {
+    let prefix = words.last().map_or("", |word| word.as_str());
+    let mut candidates: Vec<String> = Vec::new();
+    for (flag, value) in &spec.actions {
+        let found = match flag {
+            'F' => function_candidates(shell, value.as_deref().unwrap_or_default(), words),
+            'b' => builtin_candidates(shell, prefix),
+            'c' => command_candidates(shell, prefix),
+            'v' => variable_candidates(shell, prefix),
+            'W' => {
+                let words: Vec<&str> = value
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .collect();
+                word_candidates(&shell.options, &words, prefix)
+            }
+            _ => path_candidates(&shell.options, prefix)
+                .into_iter()
+                .filter_map(|path| match path.strip_suffix('/') {
+                    Some(dir) => Some(dir.to_string()),
+                    None => (*flag == 'f').then_some(path),
+                })
+                .collect(),
+        };
+        for candidate in found {
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+    }
+    candidates
+}
+
+// Runs the completion function `name` as bash does: with COMP_WORDS holding `words`,
+// COMP_CWORD the index of the last one, which is being completed, and as arguments
+// the command name, the word being completed and the word before it
+// The candidates are whatever it leaves in the COMPREPLY array, as they are
+fn function_candidates(shell: &mut Shell, name: &str, words: &[String]) -> Vec<String> {
+    let Some(function) = shell.functions.get(name).cloned() else {
+        return Vec::new();
+    };
+    let current = words.len().saturating_sub(1);
+    shell
+        .variables
+        .set_array("COMP_WORDS", words.iter().cloned().enumerate().collect());
+    shell.variables.set("COMP_CWORD", &current.to_string());
+    shell.variables.unset("COMPREPLY");
+
+    let word = |index: usize| words.get(index).map_or("", |word| word.as_str());
+    let args = [
+        name,
+        word(0),
+        word(current),
+        current.checked_sub(1).map_or("", word),
+    ];
+    let last_status = shell.last_status;
+    shell.call_function(&function, &args);
+    shell.last_status = last_status;
+
+    shell
+        .variables
+        .get("COMPREPLY")
+        .map(|reply| reply.elements().into_iter().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+// Returns the sorted names of builtins that match `prefix`
+pub fn builtin_candidates(shell: &Shell, prefix: &str) -> Vec<String> {
+    shell
+        .builtin_names()
+        .into_iter()
+        .filter(|name| name_matches(&shell.options, prefix, name))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+// Returns the sorted names of variables that match `prefix`
+pub fn variable_candidates(shell: &Shell, prefix: &str) -> Vec<String> {
+    shell
+        .variables
+        .sorted()
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| name_matches(&shell.options, prefix, name))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+// Returns the words of `words` that match `prefix`, in the order given
+pub fn word_candidates(options: &ShellOptions, words: &[&str], prefix: &str) -> Vec<String> {
+    words
+        .iter()
+        .filter(|word| name_matches(options, prefix, word))
+        .map(|word| word.to_string())
+        .collect()
+}
+
+// Returns the sorted names of builtins and programs in PATH that match `prefix`
+pub fn command_candidates(shell: &Shell, prefix: &str) -> Vec<String> {
+    let options = &shell.options;
+    let mut names: BTreeSet<String> = builtin_candidates(shell, prefix).into_iter().collect();
+
+    let path_var = env::var("PATH").unwrap_or_default();
+    for dir in path_var.split(':') {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name_matches(options, prefix, &name) && is_executable(&entry.path()) {
+                names.insert(name);
+            }
+        }
+    }
+    names.into_iter().collect()
+}
+
+// Returns the sorted paths matching `prefix`, with a trailing / on directories
+// Only the last component is matched loosely; the directory part must be written exactly
+// Hidden files are only offered when the name being completed starts with a dot,
+// or with dotglob on
+pub fn path_candidates(options: &ShellOptions, prefix: &str) -> Vec<String> {
+    let (dir, name_prefix) = match prefix.rfind('/') {
+        Some(index) => prefix.split_at(index + 1),
+        None => ("", prefix),
+    };
+
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name_matches(options, name_prefix, &name)
+                || (name.starts_with('.') && !name_prefix.starts_with('.') && !options.dotglob)
+            {
+                return None;
+            }
+            let path = format!("{}{}", dir, name);
+            if Path::new(&path).is_dir() {
+                Some(format!("{}/", path))
+            } else {
+                Some(path)
+            }
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+// Returns true if a completion candidate matches what has been typed so far
+// By default `typed` must be an exact prefix of `name`; the nocasecomplete option ignores case
+// and fuzzycomplete accepts any name containing the typed characters in order
+fn name_matches(options: &ShellOptions, typed: &str, name: &str) -> bool {
+    let fold = |c: char| {
+        if options.nocasecomplete {
+            c.to_lowercase().next().unwrap_or(c)
+        } else {
+            c
+        }
+    };
+    let mut name_chars = name.chars().map(fold);
+    if options.fuzzycomplete {
+        typed.chars().map(fold).all(|t| name_chars.any(|n| n == t))
+    } else {
+        typed
+            .chars()
+            .map(fold)
+            .all(|t| name_chars.next() == Some(t))
+    }
+}
+
+// Finds where the word ending at the end of `chars` begins
+// Words are separated by unescaped whitespace and command separators
+fn word_start(chars: &[char]) -> usize {
+    let mut start = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            c if c.is_whitespace() || matches!(c, '|' | ';' | '&' | '(' | '<' | '>') => {
+                start = i + 1
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    start.min(chars.len())
+}
+
+// Finds the words of the command whose arguments are being typed, given the line up
+// to the word being completed: those after the last command separator, starting with
+// the command's name
+fn command_words(chars: &[char]) -> Vec<String> {
+    let start = chars
+        .iter()
+        .rposition(|c| matches!(c, '|' | ';' | '&' | '('))
+        .map_or(0, |index| index + 1);
+    let mut words = Vec::new();
+    let mut word = Vec::new();
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                word.extend(chars.get(i..i + 2).unwrap_or(&chars[i..]));
+                i += 1;
+            }
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(unescape(&std::mem::take(&mut word)));
+                }
+            }
+            c => word.push(c),
+        }
+        i += 1;
+    }
+    if !word.is_empty() {
+        words.push(unescape(&word));
+    }
+    words
+}
+
+// Removes backslash escapes from a partially typed word
+fn unescape(chars: &[char]) -> String {
+    let mut result = String::new();
+    let mut chars = chars.iter();
+    while let Some(&c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+// Escapes characters the shell would otherwise treat specially
+fn escape(text: &str) -> String {
+    let mut result = String::new();
+    for c in text.chars() {
+        if SPECIAL_CHARS.contains(c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
@@ -0,0 +1,138 @@
+// Evaluation of the conditional expressions of [[ ]] commands
+// Words are expanded without splitting or filename matching, so [[ -f $file ]]
+// works whatever is in $file, and the right side of == and != is a wildcard pattern
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+use crate::expand;
+use crate::glob;
+use crate::parser::Condition;
+use crate::shell::Shell;
+use crate::sys;
+
+// The operators that compare numbers
+const NUMERIC_TESTS: &[&str] = &["-eq", "-ne", "-lt", "-le", "-gt", "-ge"];
+
+// Returns whether the condition holds, or an error for a bad number or regular expression
+// && and || only evaluate their right side when it can change the result
+pub fn evaluate(shell: &mut Shell, condition: &Condition) -> Result<bool, String> {
+    match condition {
+        Condition::Word(word) => Ok(!expand::expand_word(shell, word)?.is_empty()),
+        Condition::Not(condition) => Ok(!evaluate(shell, condition)?),
+        Condition::And(left, right) => Ok(evaluate(shell, left)? && evaluate(shell, right)?),
+        Condition::Or(left, right) => Ok(evaluate(shell, left)? || evaluate(shell, right)?),
+        Condition::Unary(operator, word) => {
+            let operand = expand::expand_word(shell, word)?;
+            Ok(unary_test(shell, operator, &operand))
+        }
+        Condition::Binary(left, operator, right) if NUMERIC_TESTS.contains(&operator.as_str()) => {
+            numeric_test(shell, left, operator, right)
+        }
+        Condition::Binary(left, operator, right) => {
+            let left = expand::expand_word(shell, left)?;
+            binary_test(shell, &left, operator, right)
+        }
+    }
+}
+
+// Tests a file, a string or a variable with one of the single-word operators
+fn unary_test(shell: &Shell, operator: &str, operand: &str) -> bool {
+    let metadata = || fs::metadata(operand).ok();
+    match operator {
+        "-n" => !operand.is_empty(),
+        "-z" => operand.is_empty(),
+        "-v" => shell.get_var(operand).is_some(),
+        "-t" => operand.parse().is_ok_and(sys::is_terminal),
+        "-r" => sys::can_access(operand, sys::R_OK),
+        "-w" => sys::can_access(operand, sys::W_OK),
+        "-x" => sys::can_access(operand, sys::X_OK),
+        "-h" | "-L" => fs::symlink_metadata(operand).is_ok_and(|m| m.file_type().is_symlink()),
+        "-a" | "-e" => metadata().is_some(),
+        "-f" => metadata().is_some_and(|m| m.is_file()),
+        "-d" => metadata().is_some_and(|m| m.is_dir()),
+        "-s" => metadata().is_some_and(|m| m.len() > 0),
+        "-b" => metadata().is_some_and(|m| m.file_type().is_block_device()),
+        "-c" => metadata().is_some_and(|m| m.file_type().is_char_device()),
+        "-p" => metadata().is_some_and(|m| m.file_type().is_fifo()),
+        "-S" => metadata().is_some_and(|m| m.file_type().is_socket()),
+        "-g" => metadata().is_some_and(|m| m.permissions().mode() & 0o2000 != 0),
+        "-u" => metadata().is_some_and(|m| m.permissions().mode() & 0o4000 != 0),
+        "-k" => metadata().is_some_and(|m| m.permissions().mode() & 0o1000 != 0),
+        "-O" => metadata().is_some_and(|m| m.uid() == sys::effective_user()),
+        "-G" => metadata().is_some_and(|m| m.gid() == sys::effective_group()),
+        // Modified since it was last read
+        _ => metadata().is_some_and(|m| m.mtime() > m.atime()),
+    }
+}
+
+// Compares two words with one of the two-word operators; `right` is still unexpanded,
+// as how it's expanded depends on the operator
+fn binary_test(shell: &mut Shell, left: &str, operator: &str, right: &str) -> Result<bool, String> {
+    match operator {
+        "==" | "=" | "!=" => {
+            let pattern = expand::expand_pattern(shell, right)?;
+            Ok(glob::matches(&pattern, left, shell.options.extglob) == (operator != "!="))
+        }
+        "=~" => {
+            let regex = expand::expand_regex(shell, right)?;
+            let groups = sys::match_regex(&regex, left)?;
+            // ${BASH_REMATCH[0]} is what the whole expression matched, and the elements
+            // after it what each group did
+            let elements: BTreeMap<usize, String> = groups
+                .iter()
+                .flatten()
+                .map(|group| {
+                    group
+                        .clone()
+                        .map_or(String::new(), |range| left[range].to_string())
+                })
+                .enumerate()
+                .collect();
+            shell.set_array("BASH_REMATCH", elements)?;
+            Ok(groups.is_some())
+        }
+        "-nt" | "-ot" | "-ef" => {
+            let right = expand::expand_word(shell, right)?;
+            let (Ok(left), Ok(right)) = (fs::metadata(left), fs::metadata(&right)) else {
+                // A file that exists is newer than one that doesn't
+                let exists = |path: &str| fs::metadata(path).is_ok();
+                return Ok(match operator {
+                    "-nt" => exists(left) && !exists(&right),
+                    "-ot" => !exists(left) && exists(&right),
+                    _ => false,
+                });
+            };
+            let modified = |m: &fs::Metadata| (m.mtime(), m.mtime_nsec());
+            Ok(match operator {
+                "-nt" => modified(&left) > modified(&right),
+                "-ot" => modified(&left) < modified(&right),
+                _ => (left.dev(), left.ino()) == (right.dev(), right.ino()),
+            })
+        }
+        // The string comparisons, sorting by byte
+        "<" => Ok(left < expand::expand_word(shell, right)?.as_str()),
+        _ => Ok(left > expand::expand_word(shell, right)?.as_str()),
+    }
+}
+
+// Compares two numbers, each given as an arithmetic expression, as in [[ $n+1 -gt 2 ]]
+fn numeric_test(
+    shell: &mut Shell,
+    left: &str,
+    operator: &str,
+    right: &str,
+) -> Result<bool, String> {
+    let left: Vec<char> = left.chars().collect();
+    let left = expand::evaluate_expression(shell, &left)?;
+    let right: Vec<char> = right.chars().collect();
+    let right = expand::evaluate_expression(shell, &right)?;
+    Ok(match operator {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    })
+}
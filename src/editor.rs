@@ -0,0 +1,633 @@
+// Interactive line editor used when the shell is attached to a terminal
+// Puts the terminal in raw mode while a line is being typed so we can handle keys ourselves
+use std::io::{self, Read, Write};
+
+use crate::sys::{self, Termios};
+
+// ANSI sequence that clears the whole screen and moves the cursor to the top-left corner
+pub const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+// Markers around parts of a prompt that take up no space on screen, like color sequences
+// They come from \[ and \] in PS1 and are never printed themselves
+pub const PROMPT_IGNORE_START: char = '\x01';
+pub const PROMPT_IGNORE_END: char = '\x02';
+
+// Removes the non-printing markers from a prompt, leaving the text to write to the terminal
+pub fn strip_prompt_markers(prompt: &str) -> String {
+    prompt
+        .chars()
+        .filter(|&c| c != PROMPT_IGNORE_START && c != PROMPT_IGNORE_END)
+        .collect()
+}
+
+// A prompt prepared for redrawing
+// Only the last line is redrawn while editing, so its on-screen width is measured separately
+#[derive(Default)]
+struct Prompt {
+    // Every line before the last, including the final newline
+    head: String,
+    last_line: String,
+    width: usize,
+}
+
+impl Prompt {
+    fn new(prompt: &str) -> Self {
+        let (head, last_line) = match prompt.rfind('\n') {
+            Some(index) => prompt.split_at(index + 1),
+            None => ("", prompt),
+        };
+
+        // Count only the characters outside the non-printing markers
+        let mut width = 0;
+        let mut printing = true;
+        for c in last_line.chars() {
+            match c {
+                PROMPT_IGNORE_START => printing = false,
+                PROMPT_IGNORE_END => printing = true,
+                _ if printing => width += 1,
+                _ => {}
+            }
+        }
+
+        Prompt {
+            head: strip_prompt_markers(head),
+            last_line: strip_prompt_markers(last_line),
+            width,
+        }
+    }
+}
+
+// Keeps the terminal in raw mode for as long as it is alive
+// The original settings are restored when it's dropped, even on early returns
+struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let original = sys::get_termios(0)?;
+        let mut raw = original;
+        raw.make_raw();
+        sys::set_termios(0, &raw)?;
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = sys::set_termios(0, &self.original);
+    }
+}
+
+// Supplies tab completion candidates to the line editor
+pub trait Completer {
+    // Given the line up to the cursor, returns the character index where the word being
+    // completed starts and the text that could replace it
+    // Candidates end with a space when complete, or a / for directories to descend into
+    fn complete(&mut self, line: &str) -> (usize, Vec<String>);
+
+    // The width to lay candidate lists out in, when it's known better than the terminal
+    // knows it, as the shell does when $COLUMNS is set
+    fn columns(&self) -> Option<usize> {
+        None
+    }
+}
+
+// Candidates being stepped through by repeated presses of Tab
+struct CompletionCycle {
+    // Where the word being completed starts in the buffer
+    start: usize,
+    candidates: Vec<String>,
+    // The candidate currently inserted, if cycling has begun
+    index: Option<usize>,
+}
+
+// A single decoded keypress
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    WordLeft,
+    WordRight,
+    KillToEnd,
+    KillToStart,
+    KillWord,
+    Yank,
+    CtrlC,
+    CtrlD,
+    CtrlL,
+    Tab,
+    ShiftTab,
+    Up,
+    Down,
+    // Ctrl-O: run the line, then bring back the history entry after it
+    OperateAndGetNext,
+    Unknown,
+}
+
+// Width assumed for a terminal that doesn't report its size
+const DEFAULT_COLUMNS: usize = 80;
+
+// State of the line currently being edited
+pub struct LineEditor {
+    prompt: Prompt,
+    buffer: Vec<char>,
+    cursor: usize,
+    // Completion candidates from the last Tab, dropped as soon as any other key is pressed
+    completion: Option<CompletionCycle>,
+    // Text removed by the last kill command, kept across lines for Ctrl-Y to paste back
+    kill_buffer: Vec<char>,
+    // The history entry shown in place of the line being typed, while browsing history
+    history_index: Option<usize>,
+    // The line being typed when history browsing began, put back by going past the newest entry
+    saved_line: Vec<char>,
+    // The history entry to start the next line with, after Ctrl-O
+    next_entry: Option<usize>,
+    // The width of the terminal, which long lines wrap at
+    columns: usize,
+    // The screen row the cursor was left on by the last redraw, counting from the row
+    // the last line of the prompt is on
+    cursor_row: usize,
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        LineEditor::new()
+    }
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor {
+            prompt: Prompt::default(),
+            buffer: Vec::new(),
+            cursor: 0,
+            completion: None,
+            kill_buffer: Vec::new(),
+            history_index: None,
+            saved_line: Vec::new(),
+            next_entry: None,
+            columns: DEFAULT_COLUMNS,
+            cursor_row: 0,
+        }
+    }
+
+    // Prints the prompt and reads one line of input with editing support
+    // The prompt may contain PROMPT_IGNORE_START/END markers around non-printing sequences
+    // Up and Down step through `history`, the lines entered so far, oldest first
+    // Returns Ok(None) when the user presses Ctrl-D on an empty line, and an Interrupted
+    // error when they abandon the line with Ctrl-C
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        history: &[String],
+        completer: &mut dyn Completer,
+    ) -> io::Result<Option<String>> {
+        let _raw = RawMode::enable()?;
+        self.prompt = Prompt::new(prompt);
+        self.buffer.clear();
+        self.saved_line.clear();
+        self.history_index = None;
+        self.completion = None;
+        // After Ctrl-O the line starts out as the entry following the one that was run
+        if let Some(index) = self
+            .next_entry
+            .take()
+            .filter(|&index| index < history.len())
+        {
+            self.buffer = history[index].chars().collect();
+            self.history_index = Some(index);
+        }
+        self.cursor = self.buffer.len();
+        self.columns = sys::terminal_columns(1).unwrap_or(DEFAULT_COLUMNS);
+        self.cursor_row = 0;
+        write_out(&self.prompt.head)?;
+        self.refresh()?;
+
+        loop {
+            let key = read_key()?;
+            // Editing the line in any other way ends a completion cycle
+            if !matches!(key, Key::Tab | Key::ShiftTab) {
+                self.completion = None;
+            }
+
+            match key {
+                Key::Char(c) => {
+                    self.buffer.insert(self.cursor, c);
+                    self.cursor += 1;
+                }
+                Key::Enter => {
+                    self.finish_line()?;
+                    return Ok(Some(self.buffer.iter().collect()));
+                }
+                Key::OperateAndGetNext => {
+                    // Only a line recalled from history has an entry after it to bring back
+                    self.next_entry = self.history_index.map(|index| index + 1);
+                    self.finish_line()?;
+                    return Ok(Some(self.buffer.iter().collect()));
+                }
+                Key::Up => {
+                    let index = self.history_index.unwrap_or(history.len());
+                    if index > 0 {
+                        if self.history_index.is_none() {
+                            self.saved_line = self.buffer.clone();
+                        }
+                        self.show_history_entry(Some(index - 1), history);
+                    }
+                }
+                Key::Down => {
+                    if let Some(index) = self.history_index {
+                        let next = Some(index + 1).filter(|&next| next < history.len());
+                        self.show_history_entry(next, history);
+                    }
+                }
+                Key::Backspace => {
+                    if self.cursor > 0 {
+                        self.cursor -= 1;
+                        self.buffer.remove(self.cursor);
+                    }
+                }
+                Key::Delete => {
+                    if self.cursor < self.buffer.len() {
+                        self.buffer.remove(self.cursor);
+                    }
+                }
+                Key::Left => self.cursor = self.cursor.saturating_sub(1),
+                Key::Right => self.cursor = (self.cursor + 1).min(self.buffer.len()),
+                Key::Home => self.cursor = 0,
+                Key::End => self.cursor = self.buffer.len(),
+                Key::WordLeft => self.cursor = self.previous_word_start(),
+                Key::WordRight => self.cursor = self.next_word_end(),
+                Key::KillToEnd => self.kill(self.cursor, self.buffer.len()),
+                Key::KillToStart => self.kill(0, self.cursor),
+                Key::KillWord => {
+                    // Like readline's unix-word-rubout, words here are separated by whitespace
+                    let mut start = self.cursor;
+                    while start > 0 && self.buffer[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    while start > 0 && !self.buffer[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    self.kill(start, self.cursor);
+                }
+                Key::Yank => {
+                    let text = self.kill_buffer.clone();
+                    self.buffer
+                        .splice(self.cursor..self.cursor, text.iter().copied());
+                    self.cursor += text.len();
+                }
+                Key::CtrlC => {
+                    // Abandon the current line and start over with a fresh prompt
+                    self.cursor = self.buffer.len();
+                    self.refresh()?;
+                    write_out("^C\r\n")?;
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                Key::CtrlD => {
+                    if self.buffer.is_empty() {
+                        write_out("\r\n")?;
+                        return Ok(None);
+                    }
+                    if self.cursor < self.buffer.len() {
+                        self.buffer.remove(self.cursor);
+                    }
+                }
+                Key::CtrlL => {
+                    // Wipe the screen, then the refresh below redraws the prompt and line at the top
+                    write_out(CLEAR_SCREEN)?;
+                    write_out(&self.prompt.head)?;
+                    self.cursor_row = 0;
+                }
+                Key::Tab => self.complete(completer, false)?,
+                Key::ShiftTab => self.complete(completer, true)?,
+                Key::Unknown => {}
+            }
+            self.refresh()?;
+        }
+    }
+
+    // Replaces the line with a history entry, or with the line that was being typed
+    // before browsing began if `index` is None
+    fn show_history_entry(&mut self, index: Option<usize>, history: &[String]) {
+        self.buffer = match index {
+            Some(index) => history[index].chars().collect(),
+            None => self.saved_line.clone(),
+        };
+        self.history_index = index;
+        self.cursor = self.buffer.len();
+    }
+
+    // Handles Tab (or Shift-Tab when `backward` is set)
+    // A single candidate is inserted straight away; with several, the first press extends
+    // the word to their common prefix or lists them, and further presses cycle through them
+    fn complete(&mut self, completer: &mut dyn Completer, backward: bool) -> io::Result<()> {
+        if let Some(cycle) = &mut self.completion {
+            let count = cycle.candidates.len();
+            let next = match (cycle.index, backward) {
+                (None, false) => 0,
+                (None, true) => count - 1,
+                (Some(index), false) => (index + 1) % count,
+                (Some(index), true) => (index + count - 1) % count,
+            };
+            cycle.index = Some(next);
+            let (start, candidate) = (cycle.start, cycle.candidates[next].clone());
+            self.replace_word(start, &candidate);
+            return Ok(());
+        }
+
+        let line: String = self.buffer[..self.cursor].iter().collect();
+        let (start, candidates) = completer.complete(&line);
+        match candidates.len() {
+            0 => write_out("\x07")?,
+            1 => self.replace_word(start, &candidates[0]),
+            _ => {
+                let prefix = common_prefix(&candidates);
+                if prefix.chars().count() > self.cursor - start {
+                    self.replace_word(start, &prefix);
+                } else {
+                    let columns = completer.columns().unwrap_or(self.columns);
+                    self.show_candidates(&candidates, columns)?;
+                }
+                self.completion = Some(CompletionCycle {
+                    start,
+                    candidates,
+                    index: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Cuts the text between two positions into the kill buffer, leaving the cursor at `start`
+    // Killing nothing keeps the previous contents so they can still be yanked
+    fn kill(&mut self, start: usize, end: usize) {
+        if start < end {
+            self.kill_buffer = self.buffer.drain(start..end).collect();
+            self.cursor = start;
+        }
+    }
+
+    // Position Alt-B moves to: the start of the word before the cursor
+    // Words are runs of letters and digits, as in readline
+    fn previous_word_start(&self) -> usize {
+        let mut position = self.cursor;
+        while position > 0 && !self.buffer[position - 1].is_alphanumeric() {
+            position -= 1;
+        }
+        while position > 0 && self.buffer[position - 1].is_alphanumeric() {
+            position -= 1;
+        }
+        position
+    }
+
+    // Position Alt-F moves to: the end of the word after the cursor
+    fn next_word_end(&self) -> usize {
+        let mut position = self.cursor;
+        while position < self.buffer.len() && !self.buffer[position].is_alphanumeric() {
+            position += 1;
+        }
+        while position < self.buffer.len() && self.buffer[position].is_alphanumeric() {
+            position += 1;
+        }
+        position
+    }
+
+    // Replaces the text between `start` and the cursor, leaving the cursor after it
+    fn replace_word(&mut self, start: usize, text: &str) {
+        self.buffer.splice(start..self.cursor, text.chars());
+        self.cursor = start + text.chars().count();
+    }
+
+    // Lists completion candidates below the current line, then redraws the prompt
+    // They go on one line if they fit in `columns`, and otherwise into columns
+    fn show_candidates(&mut self, candidates: &[String], columns: usize) -> io::Result<()> {
+        let names: Vec<&str> = candidates.iter().map(|c| c.trim_end_matches(' ')).collect();
+        let listing = names.join("  ");
+        let listing = if listing.chars().count() <= columns {
+            listing
+        } else {
+            format_columns(&names, columns)
+        };
+        self.move_to_end()?;
+        self.cursor_row = 0;
+        write_out(&format!("\r\n{}\r\n{}", listing, self.prompt.head))
+    }
+
+    // Moves the cursor below the line being edited, once it's been entered
+    fn finish_line(&mut self) -> io::Result<()> {
+        self.move_to_end()?;
+        write_out("\r\n")
+    }
+
+    // Moves the cursor down to the screen row the line ends on, for output that
+    // goes below the line
+    fn move_to_end(&mut self) -> io::Result<()> {
+        let end_row = (self.prompt.width + self.buffer.len()) / self.columns;
+        if end_row > self.cursor_row {
+            write_out(&format!("\x1b[{}B", end_row - self.cursor_row))?;
+        }
+        self.cursor_row = end_row;
+        Ok(())
+    }
+
+    // Redraws the last line of the prompt and the current line, then places the cursor
+    // where it belongs, counting only the visible width of the prompt
+    // A line longer than the terminal is wide wraps onto the rows below, so the redraw
+    // starts from the row the prompt is on and the cursor's row and column are worked
+    // out from the width; a resized terminal is measured again first
+    fn refresh(&mut self) -> io::Result<()> {
+        if sys::window_resized() {
+            self.columns = sys::terminal_columns(1).unwrap_or(DEFAULT_COLUMNS);
+        }
+        let line: String = self.buffer.iter().collect();
+        let mut output = String::new();
+        if self.cursor_row > 0 {
+            output.push_str(&format!("\x1b[{}A", self.cursor_row));
+        }
+        output.push_str(&format!("\r\x1b[J{}{}", self.prompt.last_line, line));
+
+        // A line that exactly fills its last row leaves the terminal waiting to wrap,
+        // so start the next row now to have somewhere to put the cursor
+        let end = self.prompt.width + self.buffer.len();
+        if end > 0 && end % self.columns == 0 {
+            output.push_str("\r\n");
+        }
+        let end_row = end / self.columns;
+        let position = self.prompt.width + self.cursor;
+        let (row, column) = (position / self.columns, position % self.columns);
+        if end_row > row {
+            output.push_str(&format!("\x1b[{}A", end_row - row));
+        }
+        output.push('\r');
+        if column > 0 {
+            output.push_str(&format!("\x1b[{}C", column));
+        }
+        self.cursor_row = row;
+        write_out(&output)
+    }
+}
+
+// Returns the longest prefix shared by all the candidates
+fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix: Vec<char> = candidates[0].chars().collect();
+    for candidate in &candidates[1..] {
+        let shared = prefix
+            .iter()
+            .zip(candidate.chars())
+            .take_while(|(a, b)| *a == b)
+            .count();
+        prefix.truncate(shared);
+    }
+    prefix.into_iter().collect()
+}
+
+// Lays names out in as many columns as fit in `width`, reading down each column first
+// like ls does; a name too long for the width gets a line to itself
+fn format_columns(names: &[&str], width: usize) -> String {
+    let column_width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0)
+        + 2;
+    let columns = (width / column_width).max(1);
+    let rows = names.len().div_ceil(columns);
+
+    let mut lines = Vec::new();
+    for row in 0..rows {
+        let mut line = String::new();
+        let mut index = row;
+        while index < names.len() {
+            let name = names[index];
+            line.push_str(name);
+            index += rows;
+            if index < names.len() {
+                let padding = column_width - name.chars().count();
+                line.extend(std::iter::repeat(' ').take(padding));
+            }
+        }
+        lines.push(line);
+    }
+    lines.join("\r\n")
+}
+
+// Writes a string to stdout and flushes it straight away
+fn write_out(text: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    stdout.write_all(text.as_bytes())?;
+    stdout.flush()
+}
+
+// Reads a single byte from stdin, returning None at end of input
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = [0u8; 1];
+    match io::stdin().read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Reads and decodes the next keypress, including escape sequences and UTF-8 characters
+fn read_key() -> io::Result<Key> {
+    let byte = match read_byte()? {
+        Some(byte) => byte,
+        None => return Ok(Key::CtrlD),
+    };
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x01 => Key::Home,
+        0x03 => Key::CtrlC,
+        0x04 => Key::CtrlD,
+        0x05 => Key::End,
+        0x09 => Key::Tab,
+        0x0b => Key::KillToEnd,
+        0x0c => Key::CtrlL,
+        0x0e => Key::Down,
+        0x0f => Key::OperateAndGetNext,
+        0x10 => Key::Up,
+        0x15 => Key::KillToStart,
+        0x17 => Key::KillWord,
+        0x19 => Key::Yank,
+        0x1b => read_escape_sequence()?,
+        byte if byte < 0x20 => Key::Unknown,
+        byte if byte < 0x80 => Key::Char(byte as char),
+        byte => read_utf8_char(byte)?,
+    };
+    Ok(key)
+}
+
+// Decodes the rest of an escape sequence after the initial ESC byte
+// Handles CSI sequences like "ESC [ D", SS3 sequences like "ESC O H" and Alt-key
+// combinations, which terminals send as ESC followed by the key
+fn read_escape_sequence() -> io::Result<Key> {
+    let key = match read_byte()? {
+        Some(b'[') => {
+            // Collect parameter bytes until the final byte of the sequence
+            let mut params = String::new();
+            loop {
+                match read_byte()? {
+                    Some(b) if (0x40..=0x7e).contains(&b) => {
+                        return Ok(match (params.as_str(), b) {
+                            ("", b'A') => Key::Up,
+                            ("", b'B') => Key::Down,
+                            ("", b'C') => Key::Right,
+                            ("", b'D') => Key::Left,
+                            ("", b'H') | ("1", b'~') | ("7", b'~') => Key::Home,
+                            ("", b'F') | ("4", b'~') | ("8", b'~') => Key::End,
+                            ("3", b'~') => Key::Delete,
+                            ("", b'Z') => Key::ShiftTab,
+                            _ => Key::Unknown,
+                        });
+                    }
+                    Some(b) => params.push(b as char),
+                    None => return Ok(Key::Unknown),
+                }
+            }
+        }
+        Some(b'O') => match read_byte()? {
+            Some(b'A') => Key::Up,
+            Some(b'B') => Key::Down,
+            Some(b'H') => Key::Home,
+            Some(b'F') => Key::End,
+            _ => Key::Unknown,
+        },
+        Some(b'b') => Key::WordLeft,
+        Some(b'f') => Key::WordRight,
+        _ => Key::Unknown,
+    };
+    Ok(key)
+}
+
+// Reads the continuation bytes of a multi-byte UTF-8 character
+fn read_utf8_char(first: u8) -> io::Result<Key> {
+    let len = match first {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Ok(Key::Unknown),
+    };
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        match read_byte()? {
+            Some(b) => bytes.push(b),
+            None => return Ok(Key::Unknown),
+        }
+    }
+    Ok(
+        match std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| s.chars().next())
+        {
+            Some(c) => Key::Char(c),
+            None => Key::Unknown,
+        },
+    )
+}
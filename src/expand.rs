@@ -0,0 +1,1050 @@
+// Word expansion: turns the raw words produced by the tokenizer into command arguments
+use std::io::{self, Write};
+use std::ops::Range;
+use std::process;
+
+use crate::arith;
+use crate::glob;
+use crate::jobs::JobStatus;
+use crate::parser;
+use crate::shell::Shell;
+use crate::sys::{self, Fork};
+use crate::variables::is_valid_name;
+
+// Which way data flows through a process substitution
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    // <(cmd): the command reads what the inner command writes
+    Read,
+    // >(cmd): the command writes what the inner command reads
+    Write,
+}
+
+// A running <(...) or >(...) inner command
+// The shell keeps its end of the pipe open until the outer command has finished with it
+pub struct ProcessSubstitution {
+    pid: i32,
+    fd: i32,
+}
+
+// A word partway through expansion
+// `pattern` mirrors `text` but with quoted wildcard characters escaped, so that
+// only unquoted *, ? and [ act as wildcards when matching filenames
+// `regex` is the same for the right side of =~ in [[ ]], where quoted characters that
+// are special in a regular expression are escaped instead, but substitutions aren't
+#[derive(Default)]
+struct ExpandedWord {
+    text: String,
+    pattern: String,
+    regex: String,
+    has_wildcards: bool,
+    // Set once the word has anything besides unquoted substitutions, which makes it an
+    // argument even if it's empty: `""` is one, but an empty unquoted $x is none at all
+    kept: bool,
+}
+
+impl ExpandedWord {
+    // Appends text that came from quotes or escapes and is never a wildcard
+    fn push_quoted(&mut self, text: &str) {
+        self.kept = true;
+        self.push_literal(text);
+        for c in text.chars() {
+            if ".[]()*+?{}|^$\\".contains(c) {
+                self.regex.push('\\');
+            }
+            self.regex.push(c);
+        }
+    }
+
+    // Appends the value of an unquoted substitution, which is never a wildcard either
+    fn push_substituted(&mut self, text: &str) {
+        self.push_literal(text);
+        self.regex.push_str(text);
+    }
+
+    // Appends text that only matches itself as a wildcard pattern
+    fn push_literal(&mut self, text: &str) {
+        for c in text.chars() {
+            if matches!(
+                c,
+                '*' | '?' | '[' | ']' | '\\' | '(' | ')' | '|' | '@' | '+' | '!'
+            ) {
+                self.pattern.push('\\');
+            }
+            self.pattern.push(c);
+        }
+        self.text.push_str(text);
+    }
+
+    // Appends a character typed without quotes, which may be a wildcard or, after
+    // @, + or !, the start of an extglob group
+    fn push_unquoted(&mut self, c: char) {
+        let group = c == '('
+            && self.pattern.ends_with(['@', '+', '!'])
+            && !self.pattern[..self.pattern.len() - 1].ends_with('\\');
+        if matches!(c, '*' | '?' | '[') || group {
+            self.has_wildcards = true;
+        }
+        self.kept = true;
+        self.text.push(c);
+        self.pattern.push(c);
+        self.regex.push(c);
+    }
+}
+
+// The words one word expands into
+// That's usually exactly one, but ${arr[@]} gives a word per element of the array,
+// and none at all for an empty array, and where words are split on IFS an unquoted
+// $x can give any number
+#[derive(Default)]
+struct ExpandedWords {
+    words: Vec<ExpandedWord>,
+    // The characters that split unquoted substitutions into words, or None where
+    // they aren't split, as in assignments
+    ifs: Option<String>,
+    // Set when an unquoted substitution ended in IFS whitespace, so that whatever
+    // comes next starts a new word
+    split: bool,
+}
+
+impl ExpandedWords {
+    fn new(ifs: Option<String>) -> Self {
+        ExpandedWords {
+            ifs,
+            ..ExpandedWords::default()
+        }
+    }
+
+    // The word being added to, started if there isn't one yet
+    fn current(&mut self) -> &mut ExpandedWord {
+        if self.words.is_empty() || std::mem::take(&mut self.split) {
+            self.words.push(ExpandedWord::default());
+        }
+        self.words.last_mut().unwrap()
+    }
+
+    fn push_quoted(&mut self, text: &str) {
+        self.current().push_quoted(text);
+    }
+
+    fn push_unquoted(&mut self, c: char) {
+        self.current().push_unquoted(c);
+    }
+
+    // Appends the fields of an expansion: the first joins the current word and each
+    // of the others starts a new one
+    // `quoted` is true inside double quotes, where even an empty field makes a word
+    fn push_fields(&mut self, fields: &[String], quoted: bool) {
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                self.words.push(ExpandedWord::default());
+            }
+            if quoted {
+                self.push_quoted(field);
+            } else {
+                self.push_split(field);
+            }
+        }
+    }
+
+    // Appends the value of an unquoted substitution, split into words on IFS
+    // Runs of IFS whitespace separate words and are dropped from the ends, while any
+    // other IFS character separates words on its own, so with IFS=: a::b is a, "", b
+    fn push_split(&mut self, text: &str) {
+        let Some(ifs) = self.ifs.take() else {
+            self.current().push_substituted(text);
+            return;
+        };
+        for c in text.chars() {
+            if !ifs.contains(c) {
+                self.current().push_substituted(&c.to_string());
+            } else if " \t\n".contains(c) {
+                let started = self
+                    .words
+                    .last()
+                    .is_some_and(|word| word.kept || !word.text.is_empty());
+                self.split |= started;
+            } else {
+                // Whitespace next to the separator is part of it
+                if !std::mem::take(&mut self.split) {
+                    self.current().kept = true;
+                }
+                self.words.push(ExpandedWord::default());
+            }
+        }
+        self.ifs = Some(ifs);
+    }
+
+    // The words that become arguments, leaving out those that came only from
+    // unquoted substitutions with empty values
+    fn into_fields(self) -> Vec<ExpandedWord> {
+        self.words
+            .into_iter()
+            .filter(|word| word.kept || !word.text.is_empty())
+            .collect()
+    }
+
+    // All the words joined with spaces, for places that take a single word
+    fn joined(self, part: fn(ExpandedWord) -> String) -> String {
+        self.words
+            .into_iter()
+            .map(part)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+// Expands every word of a command into the final argument list
+// Words containing unquoted wildcards are replaced by the filenames they match;
+// what happens when nothing matches depends on the nullglob and failglob options
+pub fn expand_words(shell: &mut Shell, words: &[String]) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    for word in words {
+        for expanded in expand_word_parts(shell, word, false, true)?.into_fields() {
+            if expanded.has_wildcards && !shell.options.noglob {
+                let matches = glob::expand(&expanded.pattern, &shell.options);
+                if !matches.is_empty() {
+                    args.extend(matches);
+                    continue;
+                }
+                if shell.options.failglob {
+                    return Err(format!("no match: {}", expanded.text));
+                }
+                if shell.options.nullglob {
+                    continue;
+                }
+            }
+            // Without wildcards, or when nothing matches, the word is used as written
+            args.push(expanded.text);
+        }
+    }
+    Ok(args)
+}
+
+// Expands a single word into exactly one string, without filename matching
+pub fn expand_word(shell: &mut Shell, word: &str) -> Result<String, String> {
+    Ok(expand_word_parts(shell, word, false, false)?.joined(|word| word.text))
+}
+
+// Expands the value of an assignment into one string
+// As well as at the start, a ~ right after an unquoted : is expanded, so that
+// PATH=~/bin:~/sbin gets both home directories
+pub fn expand_assignment(shell: &mut Shell, word: &str) -> Result<String, String> {
+    Ok(expand_word_parts(shell, word, true, false)?.joined(|word| word.text))
+}
+
+// Expands the target of a redirection, like the file in `> $out` or `> $(name)`, which
+// goes through tilde, variable and command expansion like any other word and has to
+// come out as exactly one word; a wildcard may be used as long as it matches just one
+// filename
+pub fn expand_redirect_target(shell: &mut Shell, word: &str) -> Result<String, String> {
+    let ambiguous = || format!("{}: ambiguous redirect", word);
+    let mut words = expand_word_parts(shell, word, false, true)?.into_fields();
+    if words.len() != 1 {
+        return Err(ambiguous());
+    }
+    let expanded = words.remove(0);
+    if expanded.has_wildcards && !shell.options.noglob {
+        let mut matches = glob::expand(&expanded.pattern, &shell.options);
+        match matches.len() {
+            0 => {}
+            1 => return Ok(matches.remove(0)),
+            _ => return Err(ambiguous()),
+        }
+    }
+    Ok(expanded.text)
+}
+
+// Expands a word for use as a pattern, as in case statements
+// Quoted wildcard characters come back escaped so they only match themselves
+pub fn expand_pattern(shell: &mut Shell, word: &str) -> Result<String, String> {
+    Ok(expand_word_parts(shell, word, false, false)?.joined(|word| word.pattern))
+}
+
+// Expands the right side of =~ in [[ ]] into an extended regular expression
+// Quoted characters come back escaped so they only match themselves
+pub fn expand_regex(shell: &mut Shell, word: &str) -> Result<String, String> {
+    Ok(expand_word_parts(shell, word, false, false)?.joined(|word| word.regex))
+}
+
+// Expands a single word: removes quotes and escapes, substitutes variables and the
+// output of commands, and runs process substitutions
+// In an assignment value, tildes after colons are expanded too, and with `split` the
+// results of unquoted substitutions are split into words on the characters in IFS
+fn expand_word_parts(
+    shell: &mut Shell,
+    word: &str,
+    assignment: bool,
+    split: bool,
+) -> Result<ExpandedWords, String> {
+    let chars: Vec<char> = word.chars().collect();
+    let ifs = split.then(|| shell.get_var("IFS").unwrap_or_else(|| " \t\n".to_string()));
+    let mut result = ExpandedWords::new(ifs.filter(|ifs| !ifs.is_empty()));
+    let mut i = 0;
+
+    if let Some((home, len)) = expand_tilde(shell, &chars, assignment) {
+        result.push_quoted(&home);
+        i = len;
+    }
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                // A backslash outside quotes keeps the next character literally
+                if let Some(&next) = chars.get(i + 1) {
+                    result.push_quoted(&next.to_string());
+                }
+                i += 2;
+            }
+            '\'' => {
+                // Single quotes preserve everything up to the closing quote
+                let end = parser::find_closing_quote(&chars, i)?;
+                let quoted: String = chars[i + 1..end].iter().collect();
+                result.push_quoted(&quoted);
+                i = end + 1;
+            }
+            '"' => {
+                let end = parser::find_closing_quote(&chars, i)?;
+                expand_double_quoted_into(shell, &chars[i + 1..end], &mut result)?;
+                i = end + 1;
+            }
+            '$' => match expand_dollar(shell, &chars, i, false)? {
+                Some((fields, next)) => {
+                    result.push_fields(&fields, false);
+                    i = next;
+                }
+                None => {
+                    result.push_unquoted('$');
+                    i += 1;
+                }
+            },
+            '`' => {
+                let end = parser::find_closing_quote(&chars, i)?;
+                let output = substitute_backquoted(shell, &chars[i + 1..end])?;
+                result.push_fields(&[output], false);
+                i = end + 1;
+            }
+            c @ ('<' | '>') if chars.get(i + 1) == Some(&'(') => {
+                let end = parser::find_closing_paren(&chars, i + 1)?;
+                let command: String = chars[i + 2..end].iter().collect();
+                let direction = if c == '<' {
+                    Direction::Read
+                } else {
+                    Direction::Write
+                };
+                result.push_quoted(&substitute_process(shell, &command, direction)?);
+                i = end + 1;
+            }
+            ':' if assignment => {
+                result.push_unquoted(':');
+                i += 1;
+                if let Some((home, len)) = expand_tilde(shell, &chars[i..], true) {
+                    result.push_quoted(&home);
+                    i += len;
+                }
+            }
+            c => {
+                result.push_unquoted(c);
+                i += 1;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+// Expands a ~ at the start of a word: ~ alone is $HOME and ~name is that user's home
+// The prefix runs up to the first /, or in an assignment value the first : too, and
+// must not be quoted
+// Returns the directory and the length of the prefix it replaces, or None to leave
+// the word as it is, as for unknown users
+fn expand_tilde(shell: &Shell, chars: &[char], assignment: bool) -> Option<(String, usize)> {
+    if chars.first() != Some(&'~') {
+        return None;
+    }
+    let len = chars
+        .iter()
+        .position(|&c| c == '/' || (assignment && c == ':'))
+        .unwrap_or(chars.len());
+    let user: String = chars[1..len].iter().collect();
+    if user.contains(['\\', '\'', '"', '$']) {
+        return None;
+    }
+    let home = if user.is_empty() {
+        shell.get_var("HOME")?
+    } else {
+        sys::home_dir_of(&user)?
+    };
+    Some((home, len))
+}
+
+// Expands the inside of a double-quoted string into a single string
+fn expand_double_quoted(shell: &mut Shell, chars: &[char]) -> Result<String, String> {
+    let mut result = ExpandedWords::default();
+    expand_double_quoted_into(shell, chars, &mut result)?;
+    Ok(result.joined(|word| word.text))
+}
+
+// Expands the inside of a double-quoted string, adding it to the words in `result`
+// Variables and commands' output are substituted, and only $, `, ", \ and newline can be escaped;
+// other backslashes stay as they are
+// The quotes make a word even when they're empty, unless all they held was
+// an empty "${arr[@]}"
+fn expand_double_quoted_into(
+    shell: &mut Shell,
+    chars: &[char],
+    result: &mut ExpandedWords,
+) -> Result<(), String> {
+    let mut had_empty_fields = false;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => match chars.get(i + 1) {
+                Some('\n') => {
+                    i += 2;
+                    continue;
+                }
+                Some(&next @ ('$' | '`' | '"' | '\\')) => {
+                    result.push_quoted(&next.to_string());
+                    i += 2;
+                    continue;
+                }
+                _ => {}
+            },
+            '$' => {
+                if let Some((fields, next)) = expand_dollar(shell, chars, i, true)? {
+                    had_empty_fields |= fields.is_empty();
+                    result.push_fields(&fields, true);
+                    i = next;
+                    continue;
+                }
+            }
+            '`' => {
+                let end = parser::find_closing_quote(chars, i)?;
+                result.push_quoted(&substitute_backquoted(shell, &chars[i + 1..end])?);
+                i = end + 1;
+                continue;
+            }
+            _ => {}
+        }
+        result.push_quoted(&chars[i].to_string());
+        i += 1;
+    }
+    if !had_empty_fields {
+        result.push_quoted("");
+    }
+    Ok(())
+}
+
+// Expands the body of a here-document whose delimiter wasn't quoted
+// As inside double quotes variables and commands' output are substituted, but a " is just a character,
+// so only $, `, \ and newline can be escaped
+pub fn expand_here_doc(shell: &mut Shell, body: &str) -> Result<String, String> {
+    let chars: Vec<char> = body.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => match chars.get(i + 1) {
+                Some('\n') => {
+                    i += 2;
+                    continue;
+                }
+                Some(&next @ ('$' | '`' | '\\')) => {
+                    result.push(next);
+                    i += 2;
+                    continue;
+                }
+                _ => {}
+            },
+            '$' => {
+                if let Some((fields, next)) = expand_dollar(shell, &chars, i, true)? {
+                    result.push_str(&fields.join(" "));
+                    i = next;
+                    continue;
+                }
+            }
+            '`' => {
+                let end = parser::find_closing_quote(&chars, i)?;
+                result.push_str(&substitute_backquoted(shell, &chars[i + 1..end])?);
+                i = end + 1;
+                continue;
+            }
+            _ => {}
+        }
+        result.push(chars[i]);
+        i += 1;
+    }
+    Ok(result)
+}
+
+// Expands the $ expression starting at `start`: a variable reference ($NAME, ${NAME}
+// or a special parameter such as $?, $$, $! or $0), an arithmetic expansion $((...))
+// or a command substitution $(...)
+// Returns the fields it expands to (one, except for arrays expanded with [@] or [*])
+// and the index just past the expression, or None if the $ isn't followed by
+// anything that can be expanded
+// `quoted` is true inside double quotes, where ${arr[*]} joins the elements into one
+fn expand_dollar(
+    shell: &mut Shell,
+    chars: &[char],
+    start: usize,
+    quoted: bool,
+) -> Result<Option<(Vec<String>, usize)>, String> {
+    if chars.get(start + 1) == Some(&'(') && chars.get(start + 2) == Some(&'(') {
+        let end = parser::find_closing_paren(chars, start + 1)?;
+        if chars[end - 1] == ')' && end - 1 > start + 2 {
+            return expand_arithmetic(shell, &chars[start + 3..end - 1])
+                .map(|value| Some((vec![value], end + 1)));
+        }
+    }
+    if chars.get(start + 1) == Some(&'(') {
+        let end = parser::find_closing_paren(chars, start + 1)?;
+        let command: String = chars[start + 2..end].iter().collect();
+        return Ok(Some((vec![substitute_command(shell, &command)?], end + 1)));
+    }
+
+    let (name, next) = match chars.get(start + 1) {
+        Some('{') => {
+            let close = parser::find_closing_brace(chars, start + 1)?;
+            let fields = expand_braced(shell, &chars[start + 2..close], quoted).map_err(|e| {
+                // Syntax problems are reported with the whole expression
+                if e.is_empty() {
+                    let text: String = chars[start..=close].iter().collect();
+                    format!("{}: bad substitution", text)
+                } else {
+                    e
+                }
+            })?;
+            return Ok(Some((fields, close + 1)));
+        }
+        Some(&c) if c == '_' || c.is_ascii_alphabetic() => {
+            let len = chars[start + 1..]
+                .iter()
+                .take_while(|&&c| c == '_' || c.is_ascii_alphanumeric())
+                .count();
+            (
+                chars[start + 1..start + 1 + len].iter().collect(),
+                start + 1 + len,
+            )
+        }
+        // $@ and $* give a field per positional parameter, as ${@} and ${*} do
+        Some(&c @ ('@' | '*')) => {
+            return Ok(Some((expand_braced(shell, &[c], quoted)?, start + 2)));
+        }
+        Some(&c) if is_special_parameter(&c.to_string()) => (c.to_string(), start + 2),
+        _ => return Ok(None),
+    };
+    Ok(Some((vec![shell.get_var(&name).unwrap_or_default()], next)))
+}
+
+// Expands the inside of ${...}: a name, optionally with a subscript as in ${arr[i]},
+// ${arr[@]} or ${arr[*]}, or the number of elements of an array as in ${#arr[@]}
+// ${#NAME} is the length of the value, and ${NAME:offset:length} a part of it
+// ${NAME#pattern} and ${NAME%pattern} remove the shortest prefix or suffix matching
+// the pattern, and ${NAME##pattern} and ${NAME%%pattern} the longest
+// ${NAME/pattern/string} replaces the first longest match of the pattern with the
+// string, ${NAME//pattern/string} every match, and ${NAME/#pattern/string} and
+// ${NAME/%pattern/string} a match at the start or end
+// The name can be followed by an operator that substitutes a word for unset
+// (or, with a colon, empty) values: ${NAME:-word} uses the word instead,
+// ${NAME:=word} also assigns it, ${NAME:?word} fails with it as the message,
+// and ${NAME:+word} uses the word only when the value is set
+// Fails with an empty message for a bad substitution, which the caller fills in
+fn expand_braced(shell: &mut Shell, inner: &[char], quoted: bool) -> Result<Vec<String>, String> {
+    let (length, start) = match inner {
+        ['#', _, ..] => (true, 1),
+        _ => (false, 0),
+    };
+    let name_len = match inner.get(start) {
+        Some(&c) if c == '_' || c.is_ascii_alphabetic() => inner[start..]
+            .iter()
+            .take_while(|&&c| c == '_' || c.is_ascii_alphanumeric())
+            .count(),
+        // Positional parameters past $9 need braces, as in ${10}
+        Some(c) if c.is_ascii_digit() => inner[start..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count(),
+        Some(&c) if is_special_parameter(&c.to_string()) => 1,
+        _ => return Err(String::new()),
+    };
+    let name: String = inner[start..start + name_len].iter().collect();
+    let mut position = start + name_len;
+
+    // The positional parameters as a whole, $@ and $*, work like the elements of an
+    // array with ${arr[@]} and ${arr[*]}
+    let subscript = if matches!(name.as_str(), "@" | "*") {
+        Some(name.clone())
+    } else if inner.get(position) == Some(&'[') {
+        let close = find_closing_bracket(inner, position).ok_or_else(String::new)?;
+        if !is_valid_name(&name) {
+            return Err(String::new());
+        }
+        let subscript: String = inner[position + 1..close].iter().collect();
+        position = close + 1;
+        Some(subscript)
+    } else {
+        None
+    };
+
+    // The value, or None if the variable (or array element) isn't set
+    let fields = match subscript.as_deref() {
+        Some("@" | "*") if !is_valid_name(&name) => Some(shell.positional_params.clone()),
+        Some("@" | "*") => shell
+            .variables
+            .get(&name)
+            .map(|var| var.elements().into_iter().map(String::from).collect()),
+        Some(subscript) => {
+            let index = evaluate_subscript(shell, &name, subscript)?;
+            let variable = shell.variables.get(&name);
+            let value = variable.and_then(|var| var.element(index));
+            value.map(|value| vec![value.to_string()])
+        }
+        None => shell.get_var(&name).map(|value| vec![value]),
+    };
+    // ${arr[*]} in double quotes is one word, joined with the first character of IFS
+    let join = |shell: &Shell, fields: Vec<String>| {
+        if subscript.as_deref() == Some("*") && quoted {
+            let separator = match shell.get_var("IFS") {
+                Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+                None => " ".to_string(),
+            };
+            vec![fields.join(&separator)]
+        } else {
+            fields
+        }
+    };
+
+    let operation = &inner[position..];
+    if length {
+        if !operation.is_empty() {
+            return Err(String::new());
+        }
+        let fields = fields.unwrap_or_default();
+        let length = match subscript.as_deref() {
+            Some("@" | "*") => fields.len(),
+            _ => fields.first().map_or(0, |value| value.chars().count()),
+        };
+        return Ok(vec![length.to_string()]);
+    }
+    if operation.is_empty() {
+        return Ok(join(shell, fields.unwrap_or_else(|| vec![String::new()])));
+    }
+
+    let (colon, operation) = match operation.split_first() {
+        Some((':', rest)) => (true, rest),
+        _ => (false, operation),
+    };
+    if colon && !matches!(operation.first(), Some('-' | '=' | '?' | '+')) {
+        // A substring, or for ${arr[@]} a range of elements
+        let (offset, length) = match operation.iter().position(|&c| c == ':') {
+            Some(split) => (&operation[..split], Some(&operation[split + 1..])),
+            None => (operation, None),
+        };
+        let offset = evaluate_expression(shell, offset)?;
+        let length = length
+            .map(|length| evaluate_expression(shell, length))
+            .transpose()?;
+        let mut fields = fields.unwrap_or_default();
+        // The positional parameters are counted from $0, so ${@:1} is all of them
+        if matches!(name.as_str(), "@" | "*") {
+            fields.insert(0, shell.arg0.clone());
+        }
+        return if matches!(subscript.as_deref(), Some("@" | "*")) {
+            let range = slice_range(&name, fields.len(), offset, length)?;
+            Ok(join(shell, fields[range].to_vec()))
+        } else {
+            let value: Vec<char> = fields
+                .first()
+                .map_or(vec![], |value| value.chars().collect());
+            let range = slice_range(&name, value.len(), offset, length)?;
+            Ok(vec![value[range].iter().collect()])
+        };
+    }
+    if let (false, Some(('/', rest))) = (colon, operation.split_first()) {
+        // A second / replaces every match, and # or % anchors the match at the start or end
+        let (all, anchor, rest) = match rest.split_first() {
+            Some(('/', rest)) => (true, None, rest),
+            Some((&anchor @ ('#' | '%'), rest)) => (false, Some(anchor), rest),
+            _ => (false, None, rest),
+        };
+        // The pattern ends at the first / that isn't quoted or escaped
+        let mut split = 0;
+        while split < rest.len() && rest[split] != '/' {
+            split = match rest[split] {
+                '\\' => split + 2,
+                '\'' | '"' => parser::find_closing_quote(rest, split)? + 1,
+                _ => split + 1,
+            };
+        }
+        let pattern = rest[..split.min(rest.len())].iter().collect::<String>();
+        let pattern = expand_pattern(shell, &pattern)?;
+        let extglob = shell.options.extglob;
+        let replacement = expand_operand(shell, rest.get(split + 1..).unwrap_or(&[]), quoted)?;
+        let fields: Vec<String> = fields
+            .unwrap_or_else(|| vec![String::new()])
+            .iter()
+            .map(|value| replace_matches(value, &pattern, &replacement, all, anchor, extglob))
+            .collect();
+        return Ok(join(shell, fields));
+    }
+    if let (false, Some((&operator @ ('#' | '%'), pattern))) = (colon, operation.split_first()) {
+        // Doubling the operator, as in ## and %%, removes the longest match
+        let (longest, pattern) = match pattern.split_first() {
+            Some((&c, rest)) if c == operator => (true, rest),
+            _ => (false, pattern),
+        };
+        let pattern = expand_pattern(shell, &pattern.iter().collect::<String>())?;
+        let extglob = shell.options.extglob;
+        let fields: Vec<String> = fields
+            .unwrap_or_else(|| vec![String::new()])
+            .iter()
+            .map(|value| remove_match(value, &pattern, operator == '#', longest, extglob))
+            .collect();
+        return Ok(join(shell, fields));
+    }
+    let Some((&operator @ ('-' | '=' | '?' | '+'), word)) = operation.split_first() else {
+        return Err(String::new());
+    };
+    let is_set = match &fields {
+        Some(fields) if colon => fields.iter().any(|field| !field.is_empty()),
+        Some(_) => true,
+        None => false,
+    };
+    match (operator, fields) {
+        ('+', Some(_)) if is_set => Ok(vec![expand_operand(shell, word, quoted)?]),
+        ('+', _) => Ok(vec![String::new()]),
+        (_, Some(fields)) if is_set => Ok(join(shell, fields)),
+        ('-', _) => Ok(vec![expand_operand(shell, word, quoted)?]),
+        ('=', _) => {
+            let value = expand_operand(shell, word, quoted)?;
+            match subscript.as_deref() {
+                _ if !is_valid_name(&name) => {
+                    return Err(format!("${}: cannot assign in this way", name))
+                }
+                Some("@" | "*") => return Err(format!("{}: bad array subscript", name)),
+                Some(subscript) => {
+                    let index = evaluate_subscript(shell, &name, subscript)?;
+                    shell.set_element(&name, index, &value)?;
+                }
+                None => shell.set_var(&name, &value)?,
+            }
+            Ok(vec![value])
+        }
+        _ => {
+            let message = expand_operand(shell, word, quoted)?;
+            if message.is_empty() {
+                Err(format!("{}: parameter null or not set", name))
+            } else {
+                Err(format!("{}: {}", name, message))
+            }
+        }
+    }
+}
+
+// Expands and evaluates an arithmetic expression, like the offset in ${NAME:offset} or
+// an argument of let; references like $? and ${x} are expanded before evaluating
+pub fn evaluate_expression(shell: &mut Shell, expression: &[char]) -> Result<i64, String> {
+    let expression = expand_double_quoted(shell, expression)?;
+    arith::evaluate(shell, &expression)
+}
+
+// Works out which part of a value of `len` characters (or elements) ${NAME:offset:length}
+// takes, clamped to the value
+// A negative offset counts back from the end, and a negative length gives the end
+// as a count back from the end
+fn slice_range(
+    name: &str,
+    len: usize,
+    offset: i64,
+    length: Option<i64>,
+) -> Result<Range<usize>, String> {
+    let len = len as i64;
+    let start = if offset < 0 { len + offset } else { offset };
+    if start < 0 || start > len {
+        return Ok(0..0);
+    }
+    let end = match length {
+        None => len,
+        Some(length) if length >= 0 => (start + length).min(len),
+        Some(length) => len + length,
+    };
+    if end < start {
+        return Err(format!("{}: substring expression < 0", name));
+    }
+    Ok(start as usize..end as usize)
+}
+
+// Removes the shortest (or `longest`) prefix or suffix of `value` that matches `pattern`
+fn remove_match(value: &str, pattern: &str, prefix: bool, longest: bool, extglob: bool) -> String {
+    let mut cuts: Vec<usize> = value
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(Some(value.len()))
+        .collect();
+    // Try the places to cut in order from the shortest match to the longest
+    if prefix == longest {
+        cuts.reverse();
+    }
+    let cut = if prefix {
+        cuts.into_iter()
+            .find(|&i| glob::matches(pattern, &value[..i], extglob))
+    } else {
+        cuts.into_iter()
+            .find(|&i| glob::matches(pattern, &value[i..], extglob))
+    };
+    match cut {
+        Some(i) if prefix => value[i..].to_string(),
+        Some(i) => value[..i].to_string(),
+        None => value.to_string(),
+    }
+}
+
+// Replaces the first match of `pattern` in `value` with `replacement`, or every match
+// if `all`, trying the longest match at each position first
+// With an `anchor` of # or % the match has to be at the start or the end of the value
+fn replace_matches(
+    value: &str,
+    pattern: &str,
+    replacement: &str,
+    all: bool,
+    anchor: Option<char>,
+    extglob: bool,
+) -> String {
+    let cuts: Vec<usize> = value
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(Some(value.len()))
+        .collect();
+    match anchor {
+        Some('#') => match cuts
+            .iter()
+            .rev()
+            .find(|&&j| glob::matches(pattern, &value[..j], extglob))
+        {
+            Some(&j) => format!("{}{}", replacement, &value[j..]),
+            None => value.to_string(),
+        },
+        Some(_) => match cuts
+            .iter()
+            .find(|&&i| glob::matches(pattern, &value[i..], extglob))
+        {
+            Some(&i) => format!("{}{}", &value[..i], replacement),
+            None => value.to_string(),
+        },
+        None if pattern.is_empty() => value.to_string(),
+        None => {
+            let mut result = String::new();
+            let mut k = 0;
+            while k + 1 < cuts.len() {
+                let start = cuts[k];
+                let end = (k + 1..cuts.len())
+                    .rev()
+                    .find(|&m| glob::matches(pattern, &value[start..cuts[m]], extglob));
+                match end {
+                    Some(m) => {
+                        result.push_str(replacement);
+                        if !all {
+                            result.push_str(&value[cuts[m]..]);
+                            return result;
+                        }
+                        k = m;
+                    }
+                    None => {
+                        result.push_str(&value[start..cuts[k + 1]]);
+                        k += 1;
+                    }
+                }
+            }
+            result
+        }
+    }
+}
+
+// Expands the word after an operator in ${...}, like the default in ${NAME:-word}
+// Inside double quotes it's expanded as if it were double-quoted itself
+fn expand_operand(shell: &mut Shell, word: &[char], quoted: bool) -> Result<String, String> {
+    if quoted {
+        expand_double_quoted(shell, word)
+    } else {
+        expand_word(shell, &word.iter().collect::<String>())
+    }
+}
+
+// Returns the position of the ] closing the [ at `open`, allowing nested brackets
+fn find_closing_bracket(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, &c) in chars.iter().enumerate().skip(open) {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Evaluates an array subscript as arithmetic
+// Negative subscripts count back from the end of the array, so -1 is the last element
+pub fn evaluate_subscript(shell: &mut Shell, name: &str, subscript: &str) -> Result<usize, String> {
+    let chars: Vec<char> = subscript.chars().collect();
+    let index = evaluate_expression(shell, &chars)?;
+    if index >= 0 {
+        return Ok(index as usize);
+    }
+    let end = shell
+        .variables
+        .get(name)
+        .and_then(|var| var.last_index())
+        .map_or(0, |last| last as i64 + 1);
+    usize::try_from(end + index).map_err(|_| format!("{}: bad array subscript", name))
+}
+
+// Evaluates the expression inside $((...)) after expanding any variables in it
+fn expand_arithmetic(shell: &mut Shell, expression: &[char]) -> Result<String, String> {
+    evaluate_expression(shell, expression).map(|value| value.to_string())
+}
+
+// Returns true for the one-character parameters the shell sets itself: $?, $$, $!
+// and the positional parameters $0 to $9, their count $# and all of them, $@ and $*
+fn is_special_parameter(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(
+        (chars.next(), chars.next()),
+        (Some('?' | '$' | '!' | '#' | '@' | '*' | '0'..='9'), None)
+    )
+}
+
+// Runs the command inside `...`, where a backslash only escapes $, ` and \, and
+// returns its output
+fn substitute_backquoted(shell: &mut Shell, inner: &[char]) -> Result<String, String> {
+    let mut command = String::new();
+    let mut i = 0;
+    while i < inner.len() {
+        if inner[i] == '\\' && matches!(inner.get(i + 1), Some('$' | '`' | '\\')) {
+            i += 1;
+        }
+        command.push(inner[i]);
+        i += 1;
+    }
+    substitute_command(shell, &command)
+}
+
+// Runs `command` in a forked child with its stdout connected to a pipe, and returns
+// everything it writes there, less any newlines at the end
+// Its status is kept for a command made up only of assignments, as in x=$(false)
+fn substitute_command(shell: &mut Shell, command: &str) -> Result<String, String> {
+    let error = |e: io::Error| format!("command substitution: {}", e);
+    let (read_fd, write_fd) = sys::create_pipe().map_err(error)?;
+
+    // Nothing buffered may be written twice once there are two processes
+    io::stdout().flush().ok();
+    shell.out.flush().ok();
+
+    match sys::fork_process().map_err(error)? {
+        Fork::Child => {
+            if sys::duplicate_fd(write_fd, 1).is_err() {
+                process::exit(1);
+            }
+            sys::close_fd(write_fd);
+            sys::close_fd(read_fd);
+            shell.exec_in_place = false;
+            shell.leave_job_control();
+            shell.reset_traps();
+            shell.history_file = None;
+            for substitution in shell.process_substitutions.drain(..) {
+                sys::close_fd(substitution.fd);
+            }
+            // The output has to reach the pipe even if the shell's own writers have
+            // been swapped for something else
+            shell.out.release();
+            shell.err.release();
+            let status = shell.run_line(command);
+            shell.out.flush().ok();
+            process::exit(status);
+        }
+        Fork::Parent(pid) => {
+            sys::close_fd(write_fd);
+            let mut output = Vec::new();
+            let mut buf = [0u8; 4096];
+            let result = loop {
+                match sys::read_fd(read_fd, &mut buf) {
+                    Ok(0) => break Ok(()),
+                    Ok(len) => output.extend_from_slice(&buf[..len]),
+                    Err(e) => break Err(e),
+                }
+            };
+            sys::close_fd(read_fd);
+            let status = sys::wait_for(pid).map_err(error)?;
+            result.map_err(error)?;
+            shell.substitution_status = Some(JobStatus::from_wait_status(status).exit_code());
+            let output = String::from_utf8_lossy(&output);
+            Ok(output.trim_end_matches('\n').to_string())
+        }
+    }
+}
+
+// Runs `command` in a forked child connected to a pipe and returns a /dev/fd path
+// for the shell's end of that pipe, which the outer command receives as an argument
+fn substitute_process(
+    shell: &mut Shell,
+    command: &str,
+    direction: Direction,
+) -> Result<String, String> {
+    let error = |e: io::Error| format!("process substitution: {}", e);
+    let (read_fd, write_fd) = sys::create_pipe().map_err(error)?;
+    let (child_fd, shell_fd) = match direction {
+        Direction::Read => (write_fd, read_fd),
+        Direction::Write => (read_fd, write_fd),
+    };
+
+    // Nothing buffered may be written twice once there are two processes
+    io::stdout().flush().ok();
+
+    match sys::fork_process().map_err(error)? {
+        Fork::Child => {
+            // Connect the inner command's stdout (or stdin) to the pipe and run it
+            let target = if direction == Direction::Read { 1 } else { 0 };
+            if sys::duplicate_fd(child_fd, target).is_err() {
+                process::exit(1);
+            }
+            sys::close_fd(child_fd);
+            sys::close_fd(shell_fd);
+            // The inner command line may run several commands, so none can replace this child
+            shell.exec_in_place = false;
+            shell.leave_job_control();
+            shell.reset_traps();
+            shell.history_file = None;
+            // Pipes belonging to earlier substitutions must not be held open by this child
+            for substitution in shell.process_substitutions.drain(..) {
+                sys::close_fd(substitution.fd);
+            }
+            shell.out.release();
+            shell.err.release();
+            let status = shell.run_line(command);
+            io::stdout().flush().ok();
+            process::exit(status);
+        }
+        Fork::Parent(pid) => {
+            sys::close_fd(child_fd);
+            shell
+                .process_substitutions
+                .push(ProcessSubstitution { pid, fd: shell_fd });
+            Ok(format!("/dev/fd/{}", shell_fd))
+        }
+    }
+}
+
+// Closes the pipes handed to the last command and reaps their inner commands
+pub fn finish_process_substitutions(shell: &mut Shell) {
+    for substitution in shell.process_substitutions.drain(..) {
+        sys::close_fd(substitution.fd);
+        let _ = sys::wait_for(substitution.pid);
+    }
+}
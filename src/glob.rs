@@ -0,0 +1,401 @@
+// Filename wildcard matching for patterns like *.rs, file?.txt and [abc]*
+use std::fs;
+use std::path::Path;
+
+use crate::options::ShellOptions;
+
+// Returns true if `text` matches the whole of `pattern`
+// Supports *, ?, [...] bracket expressions and backslash escapes, and with `extglob`
+// the groups @(a|b), ?(a|b), *(a|b), +(a|b) and !(a|b)
+pub fn matches(pattern: &str, text: &str, extglob: bool) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_from(&pattern, &text, false, extglob)
+}
+
+// Like `matches`, but ignoring upper and lower case if `nocase` is set
+fn matches_from(pattern: &[char], text: &[char], nocase: bool, extglob: bool) -> bool {
+    if extglob {
+        if let Some((start, end)) = find_group(pattern) {
+            return matches_group(pattern, start, end, text, nocase);
+        }
+    }
+
+    let (mut p, mut t) = (0, 0);
+    // Where to resume if the most recent * needs to swallow one more character
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match match_bracket(&pattern[p..], text[t], nocase) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                // An unclosed [ is just a literal character
+                None => (text[t] == '[').then_some(1),
+            },
+            Some('\\') if p + 1 < pattern.len() => {
+                same_char(pattern[p + 1], text[t], nocase).then_some(2)
+            }
+            Some(&c) => same_char(c, text[t], nocase).then_some(1),
+            None => None,
+        };
+
+        match step {
+            Some(len) => {
+                p += len;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    // Any pattern left over must be stars that can match nothing
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Returns where the first extglob group in `pattern` starts and where its ) is
+fn find_group(pattern: &[char]) -> Option<(usize, usize)> {
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            '\\' => i += 1,
+            '[' => {
+                if let Some((_, len)) = match_bracket(&pattern[i..], '\0', false) {
+                    i += len - 1;
+                }
+            }
+            '@' | '?' | '*' | '+' | '!' if pattern.get(i + 1) == Some(&'(') => {
+                let mut depth = 0;
+                let mut j = i + 1;
+                while j < pattern.len() {
+                    match pattern[j] {
+                        '\\' => j += 1,
+                        '(' => depth += 1,
+                        ')' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                return Some((i, j));
+                            }
+                        }
+                        _ => {}
+                    }
+                    j += 1;
+                }
+                // An unclosed group is just literal characters
+                return None;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Matches `text` against a pattern whose first extglob group runs from `start` to `end`,
+// trying every way of splitting the text between the part before the group, the
+// group itself and the rest of the pattern
+fn matches_group(pattern: &[char], start: usize, end: usize, text: &[char], nocase: bool) -> bool {
+    let (prefix, rest) = (&pattern[..start], &pattern[end + 1..]);
+    let alternatives = split_alternatives(&pattern[start + 2..end]);
+    let any = |text: &[char]| {
+        alternatives
+            .iter()
+            .any(|alternative| matches_from(alternative, text, nocase, true))
+    };
+
+    (0..=text.len()).any(|a| {
+        matches_from(prefix, &text[..a], nocase, false)
+            && (a..=text.len()).any(|b| {
+                let group = &text[a..b];
+                let matched = match pattern[start] {
+                    '@' => any(group),
+                    '?' => group.is_empty() || any(group),
+                    '*' => group.is_empty() || repeats(&any, group),
+                    '+' => repeats(&any, group),
+                    _ => !any(group),
+                };
+                matched && matches_from(rest, &text[b..], nocase, true)
+            })
+    })
+}
+
+// Returns true if `text` is one or more pieces that each match `any`
+fn repeats(any: &dyn Fn(&[char]) -> bool, text: &[char]) -> bool {
+    any(text) || (1..text.len()).any(|k| any(&text[..k]) && repeats(any, &text[k..]))
+}
+
+// Splits the inside of a group on the | characters not escaped or nested in another group
+fn split_alternatives(pattern: &[char]) -> Vec<&[char]> {
+    let mut alternatives = Vec::new();
+    let (mut depth, mut start, mut i) = (0, 0, 0);
+    while i < pattern.len() {
+        match pattern[i] {
+            '\\' => i += 1,
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '|' if depth == 0 => {
+                alternatives.push(&pattern[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    alternatives.push(&pattern[start.min(pattern.len())..]);
+    alternatives
+}
+
+fn same_char(a: char, b: char, nocase: bool) -> bool {
+    a == b || (nocase && a.to_lowercase().eq(b.to_lowercase()))
+}
+
+// Matches one character against the bracket expression at the start of `pattern`
+// Returns (matched, length of the expression), or None if the bracket is never closed
+fn match_bracket(pattern: &[char], c: char, nocase: bool) -> Option<(bool, usize)> {
+    // Ignoring case, the character matches if any of its upper or lower case forms do
+    let forms: Vec<char> = if nocase {
+        c.to_lowercase().chain(c.to_uppercase()).collect()
+    } else {
+        vec![c]
+    };
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        let mut start = pattern[i];
+        // A ] right after the opening bracket is a literal, not the end
+        if start == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        if start == '\\' && i + 1 < pattern.len() {
+            i += 1;
+            start = pattern[i];
+        }
+        first = false;
+
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|&end| end != ']') {
+            let end = pattern[i + 2];
+            if forms.iter().any(|&c| start <= c && c <= end) {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if forms.contains(&start) {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    None
+}
+
+// Returns true if the pattern contains an unescaped wildcard character, or with
+// `extglob` the start of a group like @(a|b)
+fn has_wildcards(pattern: &str, extglob: bool) -> bool {
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            '@' | '+' | '!' if extglob && chars.peek() == Some(&'(') => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+// Removes backslash escapes from a pattern segment that has no wildcards
+fn unescape(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+// Expands a pattern into the sorted list of existing paths it matches
+// Each /-separated segment is matched against the entries of the directories found so far
+// The dotglob, nocaseglob and extglob options change which names a wildcard matches, and with
+// globstar a segment that is just ** matches any number of directories
+pub fn expand(pattern: &str, options: &ShellOptions) -> Vec<String> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+
+    let segments: Vec<&str> = rest.split('/').collect();
+    for (index, segment) in segments.iter().enumerate() {
+        let is_last = index + 1 == segments.len();
+        let mut next = Vec::new();
+
+        for path in &paths {
+            if segment.is_empty() {
+                // An empty segment comes from a doubled or trailing slash: only directories qualify
+                if is_last && Path::new(path).is_dir() {
+                    next.push(format!("{}/", path.trim_end_matches('/')));
+                }
+                continue;
+            }
+
+            if *segment == "**" && options.globstar {
+                // As the last segment ** matches every file and directory below the path,
+                // otherwise just the directories, including the path itself
+                if !is_last {
+                    next.push(path.clone());
+                }
+                walk(path, is_last, options, &mut next);
+                continue;
+            }
+
+            if !has_wildcards(segment, options.extglob) {
+                next.push(join(path, &unescape(segment)));
+                continue;
+            }
+
+            let dir = if path.is_empty() { "." } else { path.as_str() };
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            // Hidden files only match when the segment starts with a literal dot, quoted
+            // or not, or with dotglob on; read_dir never lists . and .., so neither .*
+            // nor dotglob ever matches them
+            let matches_hidden =
+                options.dotglob || segment.starts_with('.') || segment.starts_with("\\.");
+            let segment: Vec<char> = segment.chars().collect();
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') && !matches_hidden {
+                    continue;
+                }
+                let name_chars: Vec<char> = name.chars().collect();
+                if matches_from(&segment, &name_chars, options.nocaseglob, options.extglob) {
+                    next.push(join(path, &name));
+                }
+            }
+        }
+        paths = next;
+    }
+
+    // Literal segments were taken on trust, so drop paths that don't exist
+    paths.retain(|path| fs::symlink_metadata(path).is_ok());
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+// Adds every directory below `path`, and every file too if `include_files` is set
+// Symlinks to directories are listed but not descended into, so a link back up the
+// tree can't send the walk round in circles
+fn walk(path: &str, include_files: bool, options: &ShellOptions, found: &mut Vec<String>) {
+    let dir = if path.is_empty() { "." } else { path };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !options.dotglob {
+            continue;
+        }
+        let entry_path = join(path, &name);
+        let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+        if is_dir {
+            found.push(entry_path.clone());
+            walk(&entry_path, include_files, options, found);
+        } else if include_files {
+            found.push(entry_path);
+        }
+    }
+}
+
+// Appends a name to a path prefix built up during expansion
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else if path.ends_with('/') {
+        format!("{}{}", path, name)
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A fresh directory holding a hidden file, a plain file and a subdirectory with a
+    // hidden file of its own
+    fn sample_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glob-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("d")).unwrap();
+        fs::write(dir.join(".h"), "").unwrap();
+        fs::write(dir.join("a.rs"), "").unwrap();
+        fs::write(dir.join("d/.x"), "").unwrap();
+        dir
+    }
+
+    // Expands `pattern` inside `dir`, giving back the matches relative to it
+    fn expand_in(dir: &Path, pattern: &str, options: &ShellOptions) -> Vec<String> {
+        let prefix = format!("{}/", dir.display());
+        expand(&format!("{}{}", prefix, pattern), options)
+            .into_iter()
+            .map(|path| path.strip_prefix(&prefix).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn star_skips_hidden_files() {
+        let dir = sample_dir("star");
+        let options = ShellOptions::default();
+        assert_eq!(expand_in(&dir, "*", &options), ["a.rs", "d"]);
+        assert_eq!(expand_in(&dir, "*/*", &options), Vec::<String>::new());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn leading_dot_matches_hidden_files_but_not_dot_and_dot_dot() {
+        let dir = sample_dir("dot");
+        let options = ShellOptions::default();
+        assert_eq!(expand_in(&dir, ".*", &options), [".h"]);
+        assert_eq!(expand_in(&dir, "*/.*", &options), ["d/.x"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dotglob_lets_star_match_hidden_files() {
+        let dir = sample_dir("dotglob");
+        let options = ShellOptions {
+            dotglob: true,
+            ..ShellOptions::default()
+        };
+        assert_eq!(expand_in(&dir, "*", &options), [".h", "a.rs", "d"]);
+        assert_eq!(expand_in(&dir, ".*", &options), [".h"]);
+        assert_eq!(expand_in(&dir, "*/*", &options), ["d/.x"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
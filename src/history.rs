@@ -0,0 +1,103 @@
+// The list of command lines entered at the prompt, for fc, history and friends
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Command lines in the order they were entered, numbered from 1
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    // When each entry was added, in seconds since 1970, if that's known
+    times: Vec<Option<i64>>,
+}
+
+impl History {
+    // Records a command line, without its trailing newline; blank lines are skipped
+    pub fn add(&mut self, line: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        self.add_at(line, Some(now));
+    }
+
+    fn add_at(&mut self, line: &str, time: Option<i64>) {
+        let line = line.trim_end_matches(['\n', '\r']);
+        if !line.trim().is_empty() {
+            self.entries.push(line.to_string());
+            self.times.push(time);
+        }
+    }
+
+    // Swaps the latest entry for `line`, as when fc re-runs a command in place of the
+    // fc command itself
+    pub fn replace_last(&mut self, line: &str) {
+        self.entries.pop();
+        self.times.pop();
+        self.add(line);
+    }
+
+    // Every entry, oldest first; entry i has the history number i + 1
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    // When entry i was added, in seconds since 1970; None for an entry read from a
+    // history file that didn't record it
+    pub fn time(&self, index: usize) -> Option<i64> {
+        self.times.get(index).copied().flatten()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.times.clear();
+    }
+
+    // Adds the entries of a history file to the end of the list
+    // As in bash's files, a line like #1714572239 gives the time of the entry after it;
+    // in a file with those lines, the lines between two of them are a single entry,
+    // so a command typed over several lines comes back as one
+    pub fn load(&mut self, path: &str) -> std::io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        let timestamp = |line: &str| {
+            line.strip_prefix('#')
+                .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|digits| digits.parse::<i64>().ok())
+        };
+        let has_timestamps = contents.lines().any(|line| timestamp(line).is_some());
+        let mut entry: Option<(String, Option<i64>)> = None;
+        for line in contents.lines() {
+            if let Some(time) = timestamp(line) {
+                if let Some((text, time)) = entry.take() {
+                    self.add_at(&text, time);
+                }
+                entry = Some((String::new(), Some(time)));
+                continue;
+            }
+            match &mut entry {
+                Some((text, _)) if has_timestamps && !text.is_empty() => {
+                    text.push('\n');
+                    text.push_str(line);
+                }
+                Some((text, _)) if has_timestamps => text.push_str(line),
+                _ => self.add_at(line, None),
+            }
+        }
+        if let Some((text, time)) = entry {
+            self.add_at(&text, time);
+        }
+        Ok(())
+    }
+
+    // Writes every entry to a history file, each after a #<seconds> line with the time
+    // it was added when that's known
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let mut contents = String::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            if let Some(time) = self.time(index) {
+                contents.push_str(&format!("#{}\n", time));
+            }
+            contents.push_str(entry);
+            contents.push('\n');
+        }
+        fs::write(path, contents)
+    }
+}
@@ -0,0 +1,298 @@
+// Tracking of jobs: commands started in the background with &, and foreground
+// commands stopped with Ctrl-Z
+use crate::sys;
+
+// What a process of a job is doing, or how it finished
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobStatus {
+    Running,
+    // Stopped by this signal, and can be carried on with fg or bg
+    Stopped(i32),
+    // Finished normally with this exit status
+    Exited(i32),
+    // Killed by this signal
+    Signaled(i32),
+}
+
+impl JobStatus {
+    // Decodes a raw wait status from waitpid
+    pub fn from_wait_status(wait_status: i32) -> Self {
+        if let Some(signal) = sys::stop_signal(wait_status) {
+            return JobStatus::Stopped(signal);
+        }
+        match sys::termination_signal(wait_status) {
+            Some(signal) => JobStatus::Signaled(signal),
+            None => JobStatus::Exited(sys::exit_code(wait_status)),
+        }
+    }
+
+    // Describes the status the way job listings show it, like "Running", "Exit 1" or,
+    // for a job killed by SIGTERM, "Terminated"
+    pub fn describe(&self) -> String {
+        match self {
+            JobStatus::Running => "Running".to_string(),
+            JobStatus::Stopped(_) => "Stopped".to_string(),
+            JobStatus::Exited(0) => "Done".to_string(),
+            JobStatus::Exited(code) => format!("Exit {}", code),
+            JobStatus::Signaled(signal) => signal_description(*signal),
+        }
+    }
+
+    // The shell exit status for this status: a stopped or killed process
+    // reports 128 + the signal number
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            JobStatus::Running => 0,
+            JobStatus::Exited(code) => code,
+            JobStatus::Stopped(signal) | JobStatus::Signaled(signal) => 128 + signal,
+        }
+    }
+}
+
+// What a job killed by `signal` is reported as, in the words bash uses, like
+// "Terminated" for SIGTERM; signals without a description of their own are shown
+// by number, as "Signal 29"
+pub fn signal_description(signal: i32) -> String {
+    let name = usize::try_from(signal)
+        .ok()
+        .and_then(|number| sys::SIGNAL_NAMES.get(number))
+        .copied()
+        .unwrap_or_default();
+    let description = match name {
+        "HUP" => "Hangup",
+        "INT" => "Interrupt",
+        "QUIT" => "Quit",
+        "ILL" => "Illegal instruction",
+        "TRAP" => "Trace/breakpoint trap",
+        "ABRT" => "Aborted",
+        "BUS" => "Bus error",
+        "FPE" => "Floating point exception",
+        "KILL" => "Killed",
+        "USR1" => "User defined signal 1",
+        "SEGV" => "Segmentation fault",
+        "USR2" => "User defined signal 2",
+        "PIPE" => "Broken pipe",
+        "ALRM" => "Alarm clock",
+        "TERM" => "Terminated",
+        _ => return format!("Signal {}", signal),
+    };
+    description.to_string()
+}
+
+// A command the shell is keeping track of: a pipeline of one or more processes
+// sharing a process group
+#[derive(Debug, Clone)]
+pub struct Job {
+    // The number used to refer to the job, as in %1; 0 until it's added to the table
+    pub id: usize,
+    // The process group, which is also the pid of the job's first process
+    pub pgid: i32,
+    // Every process of the job with what it's doing, in pipeline order
+    pub processes: Vec<(i32, JobStatus)>,
+    // The command line as it was typed
+    pub command: String,
+    // Set by disown -h, so the job isn't sent SIGHUP when the shell exits
+    pub no_hang_up: bool,
+    // The terminal modes the job had set when it was stopped, which it gets back when
+    // it's carried on in the foreground
+    pub terminal_modes: Option<sys::Termios>,
+}
+
+impl Job {
+    pub fn new(pids: &[i32], command: &str) -> Self {
+        Job {
+            id: 0,
+            pgid: pids.first().copied().unwrap_or(0),
+            processes: pids.iter().map(|&pid| (pid, JobStatus::Running)).collect(),
+            command: command.to_string(),
+            no_hang_up: false,
+            terminal_modes: None,
+        }
+    }
+
+    // The status of the job as a whole: stopped if any process is stopped, running
+    // while any process is, and otherwise how its last process finished
+    pub fn status(&self) -> JobStatus {
+        let mut statuses = self.processes.iter().map(|&(_, status)| status);
+        if let Some(stopped) = statuses
+            .clone()
+            .find(|status| matches!(status, JobStatus::Stopped(_)))
+        {
+            return stopped;
+        }
+        if statuses.clone().any(|status| status == JobStatus::Running) {
+            return JobStatus::Running;
+        }
+        statuses.next_back().unwrap_or(JobStatus::Exited(0))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.status(), JobStatus::Running | JobStatus::Stopped(_))
+    }
+
+    // Marks the stopped processes as running again, once they've been sent SIGCONT
+    pub fn set_running(&mut self) {
+        for (_, status) in &mut self.processes {
+            if matches!(status, JobStatus::Stopped(_)) {
+                *status = JobStatus::Running;
+            }
+        }
+    }
+
+    // Waits for the job's processes to finish, or until it's stopped
+    // Once one process has stopped the rest are only checked, in case one of them
+    // doesn't stop with the others
+    pub fn wait(&mut self) {
+        let mut stopped = false;
+        for (pid, status) in &mut self.processes {
+            if *status != JobStatus::Running {
+                continue;
+            }
+            let wait_status = if stopped {
+                sys::try_wait(*pid)
+            } else {
+                sys::wait_for_change(*pid).map(Some)
+            };
+            match wait_status {
+                Ok(Some(wait_status)) => *status = JobStatus::from_wait_status(wait_status),
+                Ok(None) => {}
+                // The process is gone without us hearing how it finished
+                Err(_) => *status = JobStatus::Exited(1),
+            }
+            stopped |= matches!(status, JobStatus::Stopped(_));
+        }
+    }
+}
+
+// The shell's jobs, in order of job number
+#[derive(Debug, Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+    // Job numbers from the least to the most recently started or stopped, which
+    // decides the current (+) and previous (-) jobs
+    recent: Vec<usize>,
+}
+
+impl JobTable {
+    // Adds a job and returns its job number
+    // A job that already has a number, like one stopped again after fg, keeps it
+    pub fn add(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        }
+        let id = job.id;
+        let position = self.jobs.partition_point(|other| other.id < id);
+        self.jobs.insert(position, job);
+        self.recent.retain(|&other| other != id);
+        self.recent.push(id);
+        id
+    }
+
+    // Takes a job out of the table, as fg does while the job runs in the foreground
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        let position = self.jobs.iter().position(|job| job.id == id)?;
+        self.recent.retain(|&other| other != id);
+        Some(self.jobs.remove(position))
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+
+    // The job number of the current job, the one most recently started or stopped
+    pub fn current_id(&self) -> Option<usize> {
+        self.recent.last().copied()
+    }
+
+    // The job number of the previous job, the one before the current one
+    pub fn previous_id(&self) -> Option<usize> {
+        self.recent.iter().rev().nth(1).copied()
+    }
+
+    // Finds the job a job spec like %2, %+ or %- refers to; with no spec, the current job
+    pub fn find(&self, spec: Option<&str>) -> Result<usize, String> {
+        let id = match spec.map(|spec| spec.strip_prefix('%').unwrap_or(spec)) {
+            None | Some("" | "+" | "%") => self.current_id(),
+            Some("-") => self.previous_id(),
+            Some(number) => number
+                .parse()
+                .ok()
+                .filter(|&id| self.jobs.iter().any(|job| job.id == id)),
+        };
+        id.ok_or_else(|| format!("{}: no such job", spec.unwrap_or("current")))
+    }
+
+    // Checks, without blocking, which processes of unfinished jobs have finished
+    // or been stopped since the last check
+    pub fn update(&mut self) {
+        for job in self.jobs.iter_mut().filter(|job| !job.is_finished()) {
+            for (pid, status) in &mut job.processes {
+                if *status != JobStatus::Running {
+                    continue;
+                }
+                if let Ok(Some(wait_status)) = sys::try_wait(*pid) {
+                    *status = JobStatus::from_wait_status(wait_status);
+                }
+            }
+        }
+    }
+
+    // Removes the jobs that have finished and returns them, with the + or - mark each
+    // one had in the table, so they can be reported
+    pub fn take_finished(&mut self) -> Vec<(Job, char)> {
+        let marks: Vec<char> = self.jobs.iter().map(|job| self.mark(job.id)).collect();
+        let mut finished = Vec::new();
+        let mut kept = Vec::new();
+        for (job, mark) in self.jobs.drain(..).zip(marks) {
+            if job.is_finished() {
+                finished.push((job, mark));
+            } else {
+                kept.push(job);
+            }
+        }
+        self.jobs = kept;
+        let jobs = &self.jobs;
+        self.recent
+            .retain(|&id| jobs.iter().any(|job| job.id == id));
+        finished
+    }
+
+    // The mark shown after a job's number: + for the current job, - for the previous one
+    pub fn mark(&self, id: usize) -> char {
+        if Some(id) == self.current_id() {
+            '+'
+        } else if Some(id) == self.previous_id() {
+            '-'
+        } else {
+            ' '
+        }
+    }
+}
+
+// Formats a line of a job listing, like `[1]+  Done                    sleep 5`
+pub fn format_job(job: &Job, mark: char) -> String {
+    format!(
+        "[{}]{}  {:<24}{}",
+        job.id,
+        mark,
+        job.status().describe(),
+        job.command
+    )
+}
+
+// Formats a line of a long job listing, which adds the process ID of the job's first
+// process, like `[1]+ 12345 Running                 sleep 5 &`
+pub fn format_job_with_pid(job: &Job, mark: char) -> String {
+    format!(
+        "[{}]{} {:>5} {:<24}{}",
+        job.id,
+        mark,
+        job.pgid,
+        job.status().describe(),
+        job.command
+    )
+}
@@ -0,0 +1,19 @@
+// The shell as a library, so other programs can embed it, run commands through a
+// Shell and add builtins of their own with Shell::register_builtin
+// The shell binary in main.rs is a thin front end on top of it
+pub mod arith;
+pub mod builtins;
+pub mod complete;
+pub mod conditional;
+pub mod editor;
+pub mod expand;
+pub mod glob;
+pub mod history;
+pub mod jobs;
+pub mod options;
+pub mod parser;
+pub mod prompt;
+pub mod redirect;
+pub mod shell;
+pub mod sys;
+pub mod variables;
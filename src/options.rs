@@ -0,0 +1,89 @@
+// Shell options toggled with the 'set' and 'shopt' builtins
+
+// Option names understood by `set -o`, with the single-letter flag that also toggles each one
+pub const SET_OPTIONS: &[(&str, Option<char>)] = &[
+    ("failglob", None),
+    ("fuzzycomplete", None),
+    ("nocasecomplete", None),
+    ("noclobber", Some('C')),
+    ("noglob", Some('f')),
+    ("nullglob", None),
+    ("verbose", Some('v')),
+];
+
+// Option names understood by `shopt`; nullglob and failglob can be changed with either builtin
+pub const SHOPT_OPTIONS: &[&str] = &[
+    "dotglob",
+    "extglob",
+    "failglob",
+    "globstar",
+    "nocaseglob",
+    "nullglob",
+];
+
+// Current settings of the shell options
+#[derive(Debug, Clone, Default)]
+pub struct ShellOptions {
+    // Refuse to overwrite existing files with `>` (`>|` still can)
+    pub noclobber: bool,
+    // Leave wildcards like *.rs as they are instead of matching filenames
+    pub noglob: bool,
+    // Remove words whose wildcards match no filenames instead of keeping them as written
+    pub nullglob: bool,
+    // Treat wildcards that match no filenames as an error that stops the command
+    pub failglob: bool,
+    // Let tab completion match names regardless of upper and lower case
+    pub nocasecomplete: bool,
+    // Let tab completion match names containing the typed characters in order, not just a prefix
+    pub fuzzycomplete: bool,
+    // Let wildcards match names starting with a dot, other than . and ..
+    pub dotglob: bool,
+    // Let ** match any number of directories
+    pub globstar: bool,
+    // Match filenames regardless of upper and lower case
+    pub nocaseglob: bool,
+    // Let patterns use groups like @(a|b), ?(a|b), *(a|b), +(a|b) and !(a|b)
+    pub extglob: bool,
+    // Echo input lines to stderr as they're read, before any expansion
+    pub verbose: bool,
+}
+
+impl ShellOptions {
+    // Returns the setting of an option by name, or None if there's no such option
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "noclobber" => Some(self.noclobber),
+            "noglob" => Some(self.noglob),
+            "nullglob" => Some(self.nullglob),
+            "failglob" => Some(self.failglob),
+            "nocasecomplete" => Some(self.nocasecomplete),
+            "fuzzycomplete" => Some(self.fuzzycomplete),
+            "dotglob" => Some(self.dotglob),
+            "globstar" => Some(self.globstar),
+            "nocaseglob" => Some(self.nocaseglob),
+            "extglob" => Some(self.extglob),
+            "verbose" => Some(self.verbose),
+            _ => None,
+        }
+    }
+
+    // Changes an option by name, returning false if there's no such option
+    pub fn set(&mut self, name: &str, enabled: bool) -> bool {
+        let flag = match name {
+            "noclobber" => &mut self.noclobber,
+            "noglob" => &mut self.noglob,
+            "nullglob" => &mut self.nullglob,
+            "failglob" => &mut self.failglob,
+            "nocasecomplete" => &mut self.nocasecomplete,
+            "fuzzycomplete" => &mut self.fuzzycomplete,
+            "dotglob" => &mut self.dotglob,
+            "globstar" => &mut self.globstar,
+            "nocaseglob" => &mut self.nocaseglob,
+            "extglob" => &mut self.extglob,
+            "verbose" => &mut self.verbose,
+            _ => return false,
+        };
+        *flag = enabled;
+        true
+    }
+}
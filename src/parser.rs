@@ -0,0 +1,1406 @@
+// Splits command lines into tokens and parses them into commands
+// Words keep their quotes and escapes; the expansion stage interprets them later
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::variables::is_valid_name;
+
+// What's wrong with a command line that can't be parsed
+// Each error carries the byte offset in the text it was found in, of the token or
+// unclosed bracket or quote to blame, or of the end for input that stops too soon
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    // A quote with nothing closing it, as in echo "hi
+    UnterminatedQuote { quote: char, offset: usize },
+    // A ( with no ), as in $(cmd or <(cmd
+    UnbalancedParen { offset: usize },
+    // A ${ with no }
+    UnbalancedBrace { offset: usize },
+    // A here-document whose delimiter line never comes
+    UnterminatedHereDoc { delimiter: String, offset: usize },
+    // A token that can't appear where it was found, like the ) in `echo )`
+    UnexpectedToken { token: String, offset: usize },
+    // The input ended partway through a command, like an unfinished case statement
+    UnexpectedEnd { offset: usize },
+}
+
+impl ParseError {
+    pub fn offset(&self) -> usize {
+        match *self {
+            ParseError::UnterminatedQuote { offset, .. }
+            | ParseError::UnbalancedParen { offset }
+            | ParseError::UnbalancedBrace { offset }
+            | ParseError::UnterminatedHereDoc { offset, .. }
+            | ParseError::UnexpectedToken { offset, .. }
+            | ParseError::UnexpectedEnd { offset } => offset,
+        }
+    }
+
+    // Returns true if more input could fix the error, as it's only that the input
+    // stopped partway through something
+    pub fn is_incomplete(&self) -> bool {
+        !matches!(self, ParseError::UnexpectedToken { .. })
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnterminatedQuote { quote, .. } => {
+                write!(f, "unexpected EOF while looking for matching `{}'", quote)
+            }
+            ParseError::UnbalancedParen { .. } => {
+                write!(f, "unexpected EOF while looking for matching `)'")
+            }
+            ParseError::UnbalancedBrace { .. } => {
+                write!(f, "unexpected EOF while looking for matching `}}'")
+            }
+            ParseError::UnterminatedHereDoc { delimiter, .. } => write!(
+                f,
+                "unexpected EOF while looking for here-document delimiter `{}'",
+                delimiter
+            ),
+            ParseError::UnexpectedToken { token, .. } => {
+                write!(f, "syntax error near unexpected token `{}'", token)
+            }
+            ParseError::UnexpectedEnd { .. } => write!(f, "syntax error: unexpected end of file"),
+        }
+    }
+}
+
+// Expansion reports its errors as strings, and parse errors found there join them
+impl From<ParseError> for String {
+    fn from(error: ParseError) -> String {
+        error.to_string()
+    }
+}
+
+// The byte offset of the character at `position`, for errors
+fn byte_offset(chars: &[char], position: usize) -> usize {
+    chars[..position.min(chars.len())]
+        .iter()
+        .map(|c| c.len_utf8())
+        .sum()
+}
+
+// A single token of a command line
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Word(String),
+    // A redirection operator, with the file descriptor number written before it (if any)
+    Redirect(Option<i32>, RedirectKind),
+    // |
+    Pipe,
+    // ;
+    Semicolon,
+    // & on its own, which runs the pipeline before it in the background
+    Background,
+    // ;; ending a branch of a case statement
+    DoubleSemicolon,
+    // && and ||, which run the next pipeline depending on the status of the previous one
+    And,
+    Or,
+    // ( and ) around subshells and after case patterns
+    LeftParen,
+    RightParen,
+    // ((EXPRESSION)), an arithmetic command, with the expression between the parentheses
+    Arithmetic(String),
+    // A line break, which separates commands like ;
+    Newline,
+    // The body of a here-document, read from the lines after the one with its << operator
+    // It comes straight after the delimiter word
+    HereDocBody(String),
+}
+
+// The different redirection operators
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectKind {
+    // < file
+    Input,
+    // > file
+    Output,
+    // >| file (overwrites even when noclobber is set)
+    Clobber,
+    // >> file
+    Append,
+    // >&N
+    DuplicateOutput,
+    // <&N
+    DuplicateInput,
+    // &> file (stdout and stderr)
+    OutputAll,
+    // &>> file (stdout and stderr)
+    AppendAll,
+    // << DELIMITER, with the input given by the lines that follow, up to DELIMITER
+    HereDoc,
+    // <<- DELIMITER, like << but leading tabs are removed from the lines
+    HereDocStripTabs,
+}
+
+// A redirection attached to a command, with its target word still unexpanded
+// For a here-document the target is the delimiter word and the body comes with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub fd: i32,
+    pub kind: RedirectKind,
+    pub target: String,
+    pub here_doc: Option<String>,
+}
+
+// A command name with its arguments and redirections
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SimpleCommand {
+    pub words: Vec<String>,
+    pub redirects: Vec<Redirect>,
+}
+
+impl SimpleCommand {
+    fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.redirects.is_empty()
+    }
+}
+
+// A command in a pipeline: a simple command, a control structure or the definition
+// of a function
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    // A compound command with the redirections written after it, which apply to all of it
+    Compound(CompoundCommand, Vec<Redirect>),
+    Function(Rc<FunctionDefinition>),
+}
+
+// NAME() COMMAND or function NAME [()] COMMAND, which defines NAME to run the
+// compound command COMMAND, usually a { ...; } group, with the arguments it's called
+// with as the positional parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub body: Command,
+    // The definition as it was written, which `type` shows
+    pub text: String,
+}
+
+// Control structures containing other commands
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompoundCommand {
+    // case WORD in PATTERN) LIST ;; ... esac
+    Case {
+        word: String,
+        items: Vec<CaseItem>,
+    },
+    // if LIST; then LIST; [elif LIST; then LIST;]... [else LIST;] fi
+    // Each condition with the commands it runs, then what runs if none succeeds
+    If {
+        branches: Vec<(CommandList, CommandList)>,
+        otherwise: Option<CommandList>,
+    },
+    // while LIST; do LIST; done, or with `until` set, until LIST; do LIST; done
+    While {
+        condition: CommandList,
+        body: CommandList,
+        until: bool,
+    },
+    // for NAME [in WORD...]; do LIST; done, where no `in` means the positional parameters
+    For {
+        name: String,
+        words: Option<Vec<String>>,
+        body: CommandList,
+    },
+    // ((EXPRESSION)), which succeeds if the expression comes to anything but 0
+    Arithmetic(String),
+    // [[ EXPRESSION ]], which succeeds if the condition holds
+    Conditional(Condition),
+    // for ((INIT; CONDITION; STEP)); do LIST; done, with the three expressions as given
+    ArithmeticFor {
+        init: String,
+        condition: String,
+        step: String,
+        body: CommandList,
+    },
+    // select NAME [in WORD...]; do LIST; done, which offers the words as a numbered menu
+    // and runs the body with NAME set to the one picked, until the body breaks out
+    Select {
+        name: String,
+        words: Option<Vec<String>>,
+        body: CommandList,
+    },
+    // ( LIST ), run in a copy of the shell so it can't change the shell's own state
+    Subshell(CommandList),
+    // { LIST; }, run in the shell itself, mainly so redirections can apply to a whole group
+    Group(CommandList),
+}
+
+// The expression of a [[ ]] command, with its words left unexpanded
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    // A word on its own, which holds if it isn't empty
+    Word(String),
+    // A test of one word, like -f FILE or -z STRING
+    Unary(String, String),
+    // A comparison of two words, like A == PATTERN, A =~ REGEX or A -lt B
+    Binary(String, String, String),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+// The operators of [[ ]] that test a single word
+const UNARY_TESTS: &[&str] = &[
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-n", "-p", "-r", "-s", "-t", "-u", "-v",
+    "-w", "-x", "-z", "-G", "-L", "-N", "-O", "-S",
+];
+
+// The operators of [[ ]] that compare two words
+const BINARY_TESTS: &[&str] = &[
+    "==", "=", "!=", "<", ">", "=~", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+// One branch of a case statement: the patterns before ) and the commands after it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseItem {
+    pub patterns: Vec<String>,
+    pub body: CommandList,
+}
+
+// Commands connected by pipes, each one's stdout feeding the next one's stdin
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pipeline {
+    // Set when the pipeline is prefixed by the `time` reserved word
+    pub timed: bool,
+    // Set when the pipeline is prefixed by `!`, which inverts its exit status
+    pub negated: bool,
+    pub commands: Vec<Command>,
+}
+
+// How a pipeline is joined to the one before it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    // && runs the pipeline only if the previous one succeeded
+    And,
+    // || runs the pipeline only if the previous one failed
+    Or,
+}
+
+// Pipelines joined by && and ||, run from left to right
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AndOrList {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+}
+
+// An && / || chain in a command list, together with how it is run
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ListItem {
+    pub and_or: AndOrList,
+    // Set when the chain is followed by &, so the shell doesn't wait for it
+    pub background: bool,
+    // The chain as it was written, for job listings
+    pub text: String,
+    // How many lines into the parsed text the chain starts, for $LINENO
+    pub line: usize,
+}
+
+// && / || chains separated by ;, & or newlines, run one after another
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CommandList {
+    pub items: Vec<ListItem>,
+}
+
+// Parses a command line into a list of pipelines ready for expansion and execution
+pub fn parse(input: &str) -> Result<CommandList, ParseError> {
+    let chars: Vec<char> = input.chars().collect();
+    let (tokens, spans) = tokenize(&chars)?.into_iter().unzip();
+    let mut parser = Parser {
+        chars,
+        tokens,
+        spans,
+        position: 0,
+    };
+    let list = parser.parse_list(&[])?;
+    // The list only stops early at a token that doesn't belong there, like a stray )
+    match parser.peek() {
+        Some(_) => Err(parser.unexpected()),
+        None => Ok(list),
+    }
+}
+
+// Returns true if the input ends partway through a command, like an unfinished case
+// statement or an unclosed quote, so more lines are needed before it can be run
+pub fn is_incomplete(input: &str) -> bool {
+    parse(input).is_err_and(|e| e.is_incomplete())
+}
+
+// Splits a script into its complete commands, each with the line it starts on, so they
+// can be run one at a time; blank and comment lines are left out, and anything still
+// unfinished at the end comes last, for parsing to report
+pub fn split_commands(contents: &str) -> Vec<(usize, String)> {
+    let mut commands = Vec::new();
+    let mut pending = String::new();
+    let mut start_line = 1;
+    for (index, line) in contents.lines().enumerate() {
+        if pending.is_empty() {
+            start_line = index + 1;
+        }
+        // Commands like case can go on over several lines, so wait until one is complete
+        pending.push_str(line);
+        pending.push('\n');
+        let Some(command) = preprocess_line(&pending) else {
+            pending.clear();
+            continue;
+        };
+        if is_incomplete(&command) {
+            continue;
+        }
+        commands.push((start_line, command));
+        pending.clear();
+    }
+    if let Some(command) = preprocess_line(&pending) {
+        commands.push((start_line, command));
+    }
+    commands
+}
+
+// Checks a whole script for syntax errors without running any of it, as sh -n does
+// Returns the first error with the line it was found on
+pub fn check_script(contents: &str) -> Result<(), (usize, ParseError)> {
+    for (line, command) in split_commands(contents) {
+        parse(&command).map_err(|e| {
+            let before = &command[..e.offset().min(command.len())];
+            // Input that ends too soon is blamed on its last line, not the one after
+            let lines = before.trim_end_matches('\n').matches('\n').count();
+            (line + lines, e)
+        })?;
+    }
+    Ok(())
+}
+
+// Prepares input for parsing the same way wherever it comes from: the interactive
+// prompt, a script, source or -c
+// Returns None for input with nothing to run, just blank lines and comments, which
+// then leaves the last status alone
+pub fn preprocess_line(line: &str) -> Option<String> {
+    let chars: Vec<char> = line.chars().collect();
+    match tokenize(&chars) {
+        Ok(tokens) if tokens.iter().all(|(token, _)| *token == Token::Newline) => None,
+        // Anything else, even input that doesn't tokenize, is left for the parser
+        _ => Some(line.to_string()),
+    }
+}
+
+// The text of an operator token, for error messages
+fn token_text(token: &Token) -> &str {
+    match token {
+        Token::Pipe => "|",
+        Token::Semicolon => ";",
+        Token::Background => "&",
+        Token::DoubleSemicolon => ";;",
+        Token::And => "&&",
+        Token::Or => "||",
+        Token::LeftParen => "(",
+        Token::RightParen => ")",
+        Token::Arithmetic(_) => "((",
+        Token::Newline | Token::HereDocBody(_) => "newline",
+        Token::Word(word) => word,
+        Token::Redirect(_, kind) => match kind {
+            RedirectKind::Input => "<",
+            RedirectKind::Output => ">",
+            RedirectKind::Clobber => ">|",
+            RedirectKind::Append => ">>",
+            RedirectKind::DuplicateOutput => ">&",
+            RedirectKind::DuplicateInput => "<&",
+            RedirectKind::OutputAll => "&>",
+            RedirectKind::AppendAll => "&>>",
+            RedirectKind::HereDoc => "<<",
+            RedirectKind::HereDocStripTabs => "<<-",
+        },
+    }
+}
+
+// Recursive descent parser over the tokens of a command line
+struct Parser {
+    chars: Vec<char>,
+    tokens: Vec<Token>,
+    // Where each token was found in `chars`
+    spans: Vec<Range<usize>>,
+    position: usize,
+}
+
+impl Parser {
+    // Returns the source text from the token at `start` up to the last token consumed
+    fn text_since(&self, start: usize) -> String {
+        match (
+            self.spans.get(start),
+            self.spans.get(self.position.wrapping_sub(1)),
+        ) {
+            (Some(first), Some(last)) if start < self.position => {
+                self.chars[first.start..last.end].iter().collect()
+            }
+            _ => String::new(),
+        }
+    }
+
+    // Returns how many lines of the text come before the token at `position`
+    fn line_of(&self, position: usize) -> usize {
+        let start = self.spans.get(position).map_or(0, |span| span.start);
+        self.chars[..start].iter().filter(|&&c| c == '\n').count()
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // Returns true if the next token is the word `word`, as reserved words like esac are
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w == word)
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.position += 1;
+        }
+    }
+
+    // Consumes the reserved word `word`, failing if something else comes next
+    fn expect_word(&mut self, word: &str) -> Result<(), ParseError> {
+        if self.peek_word(word) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    // The error for whatever token comes next, or for the input ending too soon
+    fn unexpected(&self) -> ParseError {
+        match (self.peek(), self.spans.get(self.position)) {
+            (Some(token), Some(span)) => ParseError::UnexpectedToken {
+                token: token_text(token).to_string(),
+                offset: byte_offset(&self.chars, span.start),
+            },
+            _ => ParseError::UnexpectedEnd {
+                offset: byte_offset(&self.chars, self.chars.len()),
+            },
+        }
+    }
+
+    // Parses && / || chains separated by ;, & and newlines
+    // Stops at the end of input, at a closing token like ) or ;;, or at one of `end_words`
+    // (reserved words such as esac that close the construct being parsed)
+    fn parse_list(&mut self, end_words: &[&str]) -> Result<CommandList, ParseError> {
+        let mut list = CommandList::default();
+        loop {
+            self.skip_newlines();
+            match self.peek() {
+                None | Some(Token::RightParen | Token::DoubleSemicolon) => break,
+                Some(Token::Word(word)) if end_words.contains(&word.as_str()) => break,
+                _ => {}
+            }
+
+            let start = self.position;
+            let line = self.line_of(start);
+            let and_or = self.parse_and_or()?;
+            let text = self.text_since(start);
+            let background = match self.peek() {
+                Some(Token::Background) => true,
+                Some(Token::Semicolon | Token::Newline) => false,
+                _ => {
+                    list.items.push(ListItem {
+                        and_or,
+                        background: false,
+                        text,
+                        line,
+                    });
+                    break;
+                }
+            };
+            self.position += 1;
+            list.items.push(ListItem {
+                and_or,
+                background,
+                text,
+                line,
+            });
+        }
+        Ok(list)
+    }
+
+    // Parses pipelines joined by && and ||
+    fn parse_and_or(&mut self) -> Result<AndOrList, ParseError> {
+        let mut and_or = AndOrList {
+            first: self.parse_pipeline()?,
+            rest: Vec::new(),
+        };
+        loop {
+            let connector = match self.peek() {
+                Some(Token::And) => Connector::And,
+                Some(Token::Or) => Connector::Or,
+                _ => return Ok(and_or),
+            };
+            self.position += 1;
+            // The next pipeline may start on the following line
+            self.skip_newlines();
+            and_or.rest.push((connector, self.parse_pipeline()?));
+        }
+    }
+
+    // Parses commands joined by |, optionally prefixed by `time` and by `!`
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let mut pipeline = Pipeline::default();
+        // `time` is only special as the very first word, where it applies to the whole pipeline
+        if self.peek_word("time") {
+            self.position += 1;
+            pipeline.timed = true;
+            if matches!(
+                self.peek(),
+                None | Some(
+                    Token::Semicolon | Token::Background | Token::Newline | Token::And | Token::Or
+                )
+            ) {
+                return Ok(pipeline);
+            }
+        }
+
+        // Each ! inverts the status again, so `! !` cancels out
+        if self.peek_word("!") {
+            while self.peek_word("!") {
+                self.position += 1;
+                pipeline.negated = !pipeline.negated;
+            }
+            if matches!(
+                self.peek(),
+                None | Some(
+                    Token::Semicolon | Token::Background | Token::Newline | Token::And | Token::Or
+                )
+            ) {
+                return Err(self.unexpected());
+            }
+        }
+
+        loop {
+            pipeline.commands.push(self.parse_command()?);
+            if self.peek() != Some(&Token::Pipe) {
+                return Ok(pipeline);
+            }
+            self.position += 1;
+            self.skip_newlines();
+        }
+    }
+
+    // Parses a single command, which may be a compound command followed by redirections
+    fn parse_command(&mut self) -> Result<Command, ParseError> {
+        let compound = if let Some(Token::Arithmetic(expression)) = self.peek() {
+            let expression = expression.clone();
+            self.position += 1;
+            CompoundCommand::Arithmetic(expression)
+        } else if self.peek_word("[[") {
+            self.parse_conditional()?
+        } else if self.peek_word("case") {
+            self.parse_case()?
+        } else if self.peek_word("if") {
+            self.parse_if()?
+        } else if self.peek_word("while") || self.peek_word("until") {
+            self.parse_while()?
+        } else if self.peek_word("for") || self.peek_word("select") {
+            self.parse_for()?
+        } else if self.peek() == Some(&Token::LeftParen) {
+            self.position += 1;
+            let list = self.parse_list(&[])?;
+            if list.items.is_empty() || self.peek() != Some(&Token::RightParen) {
+                return Err(self.unexpected());
+            }
+            self.position += 1;
+            CompoundCommand::Subshell(list)
+        } else if self.peek_word("function") || self.at_function_definition() {
+            return self.parse_function().map(Command::Function);
+        } else if self.peek_word("{") {
+            // The closing } is only recognised where a command could start, so it
+            // needs a ; or newline before it, as in `{ echo a; echo b; }`
+            self.position += 1;
+            let list = self.parse_list(&["}"])?;
+            if list.items.is_empty() {
+                return Err(self.unexpected());
+            }
+            self.expect_word("}")?;
+            CompoundCommand::Group(list)
+        } else {
+            return self.parse_simple_command().map(Command::Simple);
+        };
+
+        let mut redirects = Vec::new();
+        while let Some(Token::Redirect(fd, kind)) = self.peek().cloned() {
+            self.position += 1;
+            redirects.push(self.parse_redirect(fd, kind)?);
+        }
+        Ok(Command::Compound(compound, redirects))
+    }
+
+    // Returns true at the start of a NAME() function definition
+    fn at_function_definition(&self) -> bool {
+        matches!(
+            self.tokens.get(self.position..self.position + 3),
+            Some([Token::Word(name), Token::LeftParen, Token::RightParen])
+                if is_function_name(name)
+        )
+    }
+
+    // NAME() COMMAND or function NAME [()] COMMAND, where COMMAND may start on a later
+    // line but has to be a compound command
+    fn parse_function(&mut self) -> Result<Rc<FunctionDefinition>, ParseError> {
+        let start = self.position;
+        if self.peek_word("function") {
+            self.position += 1;
+        }
+        let name = match self.peek() {
+            Some(Token::Word(name)) if is_function_name(name) => name.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.position += 1;
+        if self.peek() == Some(&Token::LeftParen) {
+            self.position += 1;
+            if self.peek() != Some(&Token::RightParen) {
+                return Err(self.unexpected());
+            }
+            self.position += 1;
+        }
+        self.skip_newlines();
+        let body_start = self.position;
+        let body = self.parse_command()?;
+        if !matches!(body, Command::Compound(..)) {
+            self.position = body_start;
+            return Err(self.unexpected());
+        }
+        Ok(Rc::new(FunctionDefinition {
+            name,
+            body,
+            text: self.text_since(start),
+        }))
+    }
+
+    // Collects words and redirections up to the next operator
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut command = SimpleCommand::default();
+        loop {
+            match self.peek().cloned() {
+                Some(Token::Word(word)) => {
+                    self.position += 1;
+                    command.words.push(word);
+                }
+                Some(Token::Redirect(fd, kind)) => {
+                    self.position += 1;
+                    let redirect = self.parse_redirect(fd, kind)?;
+                    command.redirects.push(redirect);
+                }
+                _ => break,
+            }
+        }
+        if command.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(command)
+    }
+
+    // Reads the target word of a redirection whose operator has just been consumed
+    fn parse_redirect(
+        &mut self,
+        fd: Option<i32>,
+        kind: RedirectKind,
+    ) -> Result<Redirect, ParseError> {
+        // Every redirection operator must be followed by its target word
+        let target = match self.peek() {
+            Some(Token::Word(word)) => word.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.position += 1;
+        let fd = fd.unwrap_or(match kind {
+            RedirectKind::Input
+            | RedirectKind::DuplicateInput
+            | RedirectKind::HereDoc
+            | RedirectKind::HereDocStripTabs => 0,
+            _ => 1,
+        });
+        let here_doc = match (kind, self.peek()) {
+            (
+                RedirectKind::HereDoc | RedirectKind::HereDocStripTabs,
+                Some(Token::HereDocBody(body)),
+            ) => {
+                let body = body.clone();
+                self.position += 1;
+                Some(body)
+            }
+            _ => None,
+        };
+        Ok(Redirect {
+            fd,
+            kind,
+            target,
+            here_doc,
+        })
+    }
+
+    // case WORD in [(]PATTERN[|PATTERN]...) LIST ;; ... esac
+    fn parse_case(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_word("case")?;
+        let word = match self.next() {
+            Some(Token::Word(word)) => word,
+            _ => {
+                self.position -= 1;
+                return Err(self.unexpected());
+            }
+        };
+        self.skip_newlines();
+        self.expect_word("in")?;
+
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek_word("esac") {
+                self.position += 1;
+                break;
+            }
+
+            if self.peek() == Some(&Token::LeftParen) {
+                self.position += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                match self.next() {
+                    Some(Token::Word(pattern)) => patterns.push(pattern),
+                    _ => {
+                        self.position -= 1;
+                        return Err(self.unexpected());
+                    }
+                }
+                match self.next() {
+                    Some(Token::Pipe) => continue,
+                    Some(Token::RightParen) => break,
+                    _ => {
+                        self.position -= 1;
+                        return Err(self.unexpected());
+                    }
+                }
+            }
+
+            let body = self.parse_list(&["esac"])?;
+            items.push(CaseItem { patterns, body });
+            // The last branch doesn't need its ;; before esac
+            match self.peek() {
+                Some(Token::DoubleSemicolon) => self.position += 1,
+                _ if self.peek_word("esac") => {}
+                _ => return Err(self.unexpected()),
+            }
+        }
+        Ok(CompoundCommand::Case { word, items })
+    }
+
+    // [[ EXPRESSION ]]
+    // Inside, && and || join tests, ! negates one and parentheses group them, and
+    // < and > compare strings rather than redirecting
+    fn parse_conditional(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_word("[[")?;
+        let condition = self.parse_condition_or()?;
+        self.skip_newlines();
+        self.expect_word("]]")?;
+        Ok(CompoundCommand::Conditional(condition))
+    }
+
+    fn parse_condition_or(&mut self) -> Result<Condition, ParseError> {
+        let mut condition = self.parse_condition_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            let right = self.parse_condition_and()?;
+            condition = Condition::Or(Box::new(condition), Box::new(right));
+        }
+        Ok(condition)
+    }
+
+    fn parse_condition_and(&mut self) -> Result<Condition, ParseError> {
+        let mut condition = self.parse_condition_not()?;
+        while self.peek() == Some(&Token::And) {
+            self.position += 1;
+            let right = self.parse_condition_not()?;
+            condition = Condition::And(Box::new(condition), Box::new(right));
+        }
+        Ok(condition)
+    }
+
+    fn parse_condition_not(&mut self) -> Result<Condition, ParseError> {
+        self.skip_newlines();
+        if self.peek_word("!") {
+            self.position += 1;
+            let condition = self.parse_condition_not()?;
+            return Ok(Condition::Not(Box::new(condition)));
+        }
+        if self.peek() == Some(&Token::LeftParen) {
+            self.position += 1;
+            let condition = self.parse_condition_or()?;
+            self.skip_newlines();
+            if self.next() != Some(Token::RightParen) {
+                self.position -= 1;
+                return Err(self.unexpected());
+            }
+            return Ok(condition);
+        }
+
+        let Some(first) = self.condition_word(0) else {
+            return Err(self.unexpected());
+        };
+        let second = self.condition_word(1);
+        let operator = second.filter(|operator| BINARY_TESTS.contains(&operator.as_str()));
+        if let Some(operator) = operator {
+            self.position += 2;
+            // A regular expression is taken as written up to the end of the test, as
+            // its ( | ) would otherwise be read as operators
+            let right = if operator == "=~" {
+                self.regex_text()
+            } else {
+                self.condition_word(0)
+            };
+            let Some(right) = right else {
+                return Err(self.unexpected());
+            };
+            if operator != "=~" {
+                self.position += 1;
+            }
+            return Ok(Condition::Binary(first, operator, right));
+        }
+        if UNARY_TESTS.contains(&first.as_str()) {
+            if let Some(operand) = self.condition_word(1) {
+                self.position += 2;
+                return Ok(Condition::Unary(first, operand));
+            }
+        }
+        self.position += 1;
+        Ok(Condition::Word(first))
+    }
+
+    // The word `offset` tokens ahead inside [[ ]], where < and > are words too; None
+    // for an operator or the closing ]]
+    fn condition_word(&self, offset: usize) -> Option<String> {
+        match self.tokens.get(self.position + offset)? {
+            Token::Word(word) if word != "]]" => Some(word.clone()),
+            Token::Redirect(None, RedirectKind::Input) => Some("<".to_string()),
+            Token::Redirect(None, RedirectKind::Output) => Some(">".to_string()),
+            _ => None,
+        }
+    }
+
+    // Consumes the regular expression after =~, up to the && or || that ends the
+    // test or the closing ]], and returns it as it was written
+    fn regex_text(&mut self) -> Option<String> {
+        let start = self.position;
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                None | Some(Token::And | Token::Or | Token::Newline) => break,
+                Some(Token::Word(word)) if word == "]]" => break,
+                Some(Token::RightParen) if depth == 0 => break,
+                Some(Token::LeftParen) => depth += 1,
+                Some(Token::RightParen) => depth -= 1,
+                _ => {}
+            }
+            self.position += 1;
+        }
+        let text = self.text_since(start);
+        (!text.is_empty()).then_some(text)
+    }
+
+    // Parses the commands of a part of an if or a loop, which can't be empty, up to
+    // one of `end_words`, the reserved words that can follow them
+    fn parse_clause(&mut self, end_words: &[&str]) -> Result<CommandList, ParseError> {
+        let list = self.parse_list(end_words)?;
+        if list.items.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(list)
+    }
+
+    // if LIST; then LIST; [elif LIST; then LIST;]... [else LIST;] fi
+    fn parse_if(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_word("if")?;
+        let mut branches = Vec::new();
+        loop {
+            let condition = self.parse_clause(&["then"])?;
+            self.expect_word("then")?;
+            let body = self.parse_clause(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+            if self.peek_word("elif") {
+                self.position += 1;
+                continue;
+            }
+            break;
+        }
+        let otherwise = if self.peek_word("else") {
+            self.position += 1;
+            Some(self.parse_clause(&["fi"])?)
+        } else {
+            None
+        };
+        self.expect_word("fi")?;
+        Ok(CompoundCommand::If {
+            branches,
+            otherwise,
+        })
+    }
+
+    // while LIST; do LIST; done, and the same with until
+    fn parse_while(&mut self) -> Result<CompoundCommand, ParseError> {
+        let until = self.peek_word("until");
+        self.position += 1;
+        let condition = self.parse_clause(&["do"])?;
+        let body = self.parse_do_group()?;
+        Ok(CompoundCommand::While {
+            condition,
+            body,
+            until,
+        })
+    }
+
+    // for NAME [in WORD...]; do LIST; done, and select, which is written the same way,
+    // or the arithmetic for ((INIT; CONDITION; STEP)); do LIST; done
+    // The ; can be a newline, and can be left out when there's no `in`, as in
+    // `for arg do ...`
+    fn parse_for(&mut self) -> Result<CompoundCommand, ParseError> {
+        let select = self.peek_word("select");
+        self.position += 1;
+        if let (false, Some(Token::Arithmetic(expressions))) = (select, self.peek()) {
+            return self.parse_arithmetic_for(expressions.clone());
+        }
+        let name = match self.next() {
+            Some(Token::Word(name)) if is_valid_name(&name) => name,
+            _ => {
+                self.position -= 1;
+                return Err(self.unexpected());
+            }
+        };
+        self.skip_newlines();
+        let words = if self.peek_word("in") {
+            self.position += 1;
+            let mut words = Vec::new();
+            while let Some(Token::Word(word)) = self.peek() {
+                words.push(word.clone());
+                self.position += 1;
+            }
+            match self.next() {
+                Some(Token::Semicolon | Token::Newline) => {}
+                _ => {
+                    self.position -= 1;
+                    return Err(self.unexpected());
+                }
+            }
+            Some(words)
+        } else {
+            if self.peek() == Some(&Token::Semicolon) {
+                self.position += 1;
+            }
+            None
+        };
+        self.skip_newlines();
+        let body = self.parse_do_group()?;
+        if select {
+            return Ok(CompoundCommand::Select { name, words, body });
+        }
+        Ok(CompoundCommand::For { name, words, body })
+    }
+
+    // The rest of for ((INIT; CONDITION; STEP)); do LIST; done, from the (( on
+    fn parse_arithmetic_for(&mut self, expressions: String) -> Result<CompoundCommand, ParseError> {
+        let parts: Vec<&str> = expressions.split(';').collect();
+        let [init, condition, step] = parts[..] else {
+            return Err(self.unexpected());
+        };
+        self.position += 1;
+        if self.peek() == Some(&Token::Semicolon) {
+            self.position += 1;
+        }
+        self.skip_newlines();
+        let body = self.parse_do_group()?;
+        Ok(CompoundCommand::ArithmeticFor {
+            init: init.to_string(),
+            condition: condition.to_string(),
+            step: step.to_string(),
+            body,
+        })
+    }
+
+    // do LIST; done, the body of a loop
+    fn parse_do_group(&mut self) -> Result<CommandList, ParseError> {
+        self.expect_word("do")?;
+        let body = self.parse_clause(&["done"])?;
+        self.expect_word("done")?;
+        Ok(body)
+    }
+}
+
+// Returns true if `word` can name a function: anything that needs no expanding and
+// isn't a reserved word
+fn is_function_name(word: &str) -> bool {
+    const RESERVED_WORDS: &[&str] = &[
+        "!", "{", "}", "[[", "]]", "case", "do", "done", "elif", "else", "esac", "fi", "for",
+        "function", "if", "in", "select", "then", "time", "until", "while",
+    ];
+    !word.is_empty()
+        && !word.contains(['\\', '\'', '"', '`', '$', '=', '/'])
+        && !RESERVED_WORDS.contains(&word)
+}
+
+// Splits a word like NAME=value into the variable name and the (unexpanded) value
+// The name can have a subscript, as in arr[1]=value
+// Returns None if the word isn't an assignment
+pub fn assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    (is_valid_name(name) || subscript(name).is_some()).then_some((name, value))
+}
+
+// Splits an array reference like arr[i + 1] into the name and the (unexpanded) subscript
+pub fn subscript(name: &str) -> Option<(&str, &str)> {
+    let (array, index) = name.strip_suffix(']')?.split_once('[')?;
+    is_valid_name(array).then_some((array, index))
+}
+
+// Splits the list of an array assignment like arr=(a "b c" [5]=d) into its words
+// The list is given without the parentheses; newlines in it just separate words
+pub fn array_elements(list: &str) -> Result<Vec<String>, ParseError> {
+    let chars: Vec<char> = list.chars().collect();
+    let mut words = Vec::new();
+    for (token, span) in tokenize(&chars)? {
+        match token {
+            Token::Word(word) => words.push(word),
+            Token::Newline => {}
+            token => {
+                return Err(ParseError::UnexpectedToken {
+                    token: token_text(&token).to_string(),
+                    offset: byte_offset(&chars, span.start),
+                })
+            }
+        }
+    }
+    Ok(words)
+}
+
+// Splits a command line into tokens, keeping quoted sections, process substitutions
+// like <(cmd), arithmetic like $((1 + 2)) and parameter expansions like ${x:-a b}
+// together as part of one word
+// Comments, from a # at the start of a word to the end of the line, are left out, and
+// the bodies of here-documents are read from the lines after the one they start on
+// Each token comes with the range of character positions it was read from
+pub fn tokenize(chars: &[char]) -> Result<Vec<(Token, Range<usize>)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut word_start = 0;
+    let mut i = 0;
+    // The positions of the << operators on the current line, whose bodies come next
+    let mut here_docs = Vec::new();
+    // The bodies read so far, with the position of the delimiter word each one follows
+    let mut bodies = Vec::new();
+
+    while i < chars.len() {
+        let c = chars[i];
+        if !in_word {
+            word_start = i;
+        }
+        match c {
+            ' ' | '\t' | '\r' => {
+                // Whitespace ends the current word
+                if in_word {
+                    tokens.push((Token::Word(std::mem::take(&mut word)), word_start..i));
+                    in_word = false;
+                }
+                i += 1;
+                continue;
+            }
+            '#' if !in_word => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            '\\' => {
+                // Keep the backslash and the character it escapes together
+                let end = (i + 2).min(chars.len());
+                word.extend(&chars[i..end]);
+                i = end;
+            }
+            '\'' | '"' | '`' => {
+                let end = find_closing_quote(chars, i)?;
+                word.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '<' | '>' | '$' if chars.get(i + 1) == Some(&'(') => {
+                let end = find_closing_paren(chars, i + 1)?;
+                word.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                let end = find_closing_brace(chars, i + 1)?;
+                word.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            // (( at the start of a word is an arithmetic command if a )) closes it, and
+            // otherwise one subshell inside another, as in ((cd /tmp; ls) | wc)
+            '(' if !in_word && chars.get(i + 1) == Some(&'(') => {
+                let end = find_closing_paren(chars, i)?;
+                if end > i + 2 && find_closing_paren(chars, i + 1) == Ok(end - 1) {
+                    let expression = chars[i + 2..end - 1].iter().collect();
+                    tokens.push((Token::Arithmetic(expression), i..end + 1));
+                    i = end + 1;
+                    continue;
+                }
+                tokens.push((Token::LeftParen, i..i + 1));
+                i += 1;
+                continue;
+            }
+            // The list of an array assignment, as in arr=(a b c), belongs to the word
+            '(' if word.ends_with('=') && assignment(&word).is_some() => {
+                let end = find_closing_paren(chars, i)?;
+                word.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            // An extglob group, as in @(a|b) or !(*.rs), belongs to the word
+            '(' if in_word
+                && matches!(chars[i - 1], '@' | '?' | '*' | '+' | '!')
+                && (i < 2 || chars[i - 2] != '\\') =>
+            {
+                let end = find_closing_paren(chars, i)?;
+                word.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '|' | ';' | '&' | '(' | ')' | '\n' if c != '&' || chars.get(i + 1) != Some(&'>') => {
+                if in_word {
+                    tokens.push((Token::Word(std::mem::take(&mut word)), word_start..i));
+                    in_word = false;
+                }
+                let (token, len) = match (c, chars.get(i + 1)) {
+                    (';', Some(';')) => (Token::DoubleSemicolon, 2),
+                    ('&', Some('&')) => (Token::And, 2),
+                    ('|', Some('|')) => (Token::Or, 2),
+                    ('|', _) => (Token::Pipe, 1),
+                    (';', _) => (Token::Semicolon, 1),
+                    ('(', _) => (Token::LeftParen, 1),
+                    (')', _) => (Token::RightParen, 1),
+                    ('\n', _) => (Token::Newline, 1),
+                    _ => (Token::Background, 1),
+                };
+                tokens.push((token, i..i + len));
+                i += len;
+                if c == '\n' {
+                    for operator in here_docs.drain(..) {
+                        let (body, end) = read_here_doc(chars, i, &tokens, operator)?;
+                        bodies.push((operator + 1, body));
+                        i = end;
+                    }
+                }
+                continue;
+            }
+            '<' | '>' | '&' => {
+                // A word made only of digits right before the operator names the fd, as in 2>
+                let fd = if in_word && word.chars().all(|d| d.is_ascii_digit()) {
+                    word.parse().ok()
+                } else {
+                    None
+                };
+                if in_word {
+                    if fd.is_none() {
+                        tokens.push((Token::Word(word.clone()), word_start..i));
+                        word_start = i;
+                    }
+                    word.clear();
+                    in_word = false;
+                }
+                let (kind, len) = redirect_operator(&chars[i..]);
+                if matches!(kind, RedirectKind::HereDoc | RedirectKind::HereDocStripTabs) {
+                    here_docs.push(tokens.len());
+                }
+                tokens.push((Token::Redirect(fd, kind), word_start..i + len));
+                i += len;
+                continue;
+            }
+            _ => {
+                word.push(c);
+                i += 1;
+            }
+        }
+        in_word = true;
+    }
+
+    if in_word {
+        tokens.push((Token::Word(word), word_start..chars.len()));
+    }
+    if let Some(&operator) = here_docs.first() {
+        // The input ended on the line with the <<, before any of the body
+        let delimiter = match tokens.get(operator + 1) {
+            Some((Token::Word(word), _)) => here_doc_delimiter(word),
+            _ => String::new(),
+        };
+        return Err(ParseError::UnterminatedHereDoc {
+            delimiter,
+            offset: byte_offset(chars, tokens[operator].1.start),
+        });
+    }
+    // Each body goes straight after its delimiter word, sharing its position so that
+    // the text of the command doesn't take in the lines of the body
+    for (position, body) in bodies.into_iter().rev() {
+        let span = tokens[position].1.clone();
+        tokens.insert(position + 1, (Token::HereDocBody(body), span));
+    }
+    Ok(tokens)
+}
+
+// Reads the body of the here-document whose << operator is at `operator` in `tokens`,
+// starting at position `start` in `chars`
+// Returns the body and the position just after its delimiter line
+// A << without a delimiter word is left for the parser to complain about
+fn read_here_doc(
+    chars: &[char],
+    start: usize,
+    tokens: &[(Token, Range<usize>)],
+    operator: usize,
+) -> Result<(String, usize), ParseError> {
+    let (strip_tabs, delimiter) = match (&tokens[operator].0, tokens.get(operator + 1)) {
+        (Token::Redirect(_, kind), Some((Token::Word(word), _))) => (
+            *kind == RedirectKind::HereDocStripTabs,
+            here_doc_delimiter(word),
+        ),
+        _ => return Ok((String::new(), start)),
+    };
+
+    let mut body = String::new();
+    let mut i = start;
+    while i < chars.len() {
+        let end = chars[i..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(chars.len(), |len| i + len);
+        let mut line: String = chars[i..end].iter().collect();
+        if strip_tabs {
+            line = line.trim_start_matches('\t').to_string();
+        }
+        i = (end + 1).min(chars.len());
+        if line == delimiter {
+            return Ok((body, i));
+        }
+        body.push_str(&line);
+        body.push('\n');
+    }
+    Err(ParseError::UnterminatedHereDoc {
+        delimiter,
+        offset: byte_offset(chars, tokens[operator].1.start),
+    })
+}
+
+// The line that ends a here-document: its delimiter word with the quotes removed
+pub fn here_doc_delimiter(word: &str) -> String {
+    let mut delimiter = String::new();
+    let mut quote = None;
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None | Some('"'), '\\') => delimiter.extend(chars.next()),
+            _ => delimiter.push(c),
+        }
+    }
+    delimiter
+}
+
+// Identifies the redirection operator at the start of `chars`
+// Returns the kind of redirection and how many characters the operator uses
+fn redirect_operator(chars: &[char]) -> (RedirectKind, usize) {
+    match chars {
+        ['&', '>', '>', ..] => (RedirectKind::AppendAll, 3),
+        ['&', '>', ..] => (RedirectKind::OutputAll, 2),
+        ['>', '>', ..] => (RedirectKind::Append, 2),
+        ['>', '&', ..] => (RedirectKind::DuplicateOutput, 2),
+        ['>', '|', ..] => (RedirectKind::Clobber, 2),
+        ['<', '&', ..] => (RedirectKind::DuplicateInput, 2),
+        ['<', '<', '-', ..] => (RedirectKind::HereDocStripTabs, 3),
+        ['<', '<', ..] => (RedirectKind::HereDoc, 2),
+        ['<', ..] => (RedirectKind::Input, 1),
+        _ => (RedirectKind::Output, 1),
+    }
+}
+
+// Finds the index of the quote that closes the one at `start`, which may also be the
+// backtick starting a command substitution
+// Inside double quotes and backticks a backslash escapes the next character, and
+// inside double quotes a command substitution can have quotes of its own
+pub fn find_closing_quote(chars: &[char], start: usize) -> Result<usize, ParseError> {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() {
+        if chars[i] == quote {
+            return Ok(i);
+        }
+        match chars[i] {
+            '\\' if quote != '\'' => i += 1,
+            '$' if quote == '"' && chars.get(i + 1) == Some(&'(') => {
+                i = find_closing_paren(chars, i + 1)?;
+            }
+            '`' if quote == '"' => i = find_closing_quote(chars, i)?,
+            _ => {}
+        }
+        i += 1;
+    }
+    Err(ParseError::UnterminatedQuote {
+        quote,
+        offset: byte_offset(chars, start),
+    })
+}
+
+// Finds the index of the parenthesis that closes the one at `start`
+// Quoted and escaped parentheses don't count towards the nesting depth
+pub fn find_closing_paren(chars: &[char], start: usize) -> Result<usize, ParseError> {
+    let mut depth = 0;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '\'' | '"' | '`' => i = find_closing_quote(chars, i)?,
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    Err(ParseError::UnbalancedParen {
+        offset: byte_offset(chars, start),
+    })
+}
+
+// Returns the position of the } closing the { at `start`, as in ${NAME:-word}
+// Nested ${...} are skipped over
+pub fn find_closing_brace(chars: &[char], start: usize) -> Result<usize, ParseError> {
+    let mut depth = 0;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    Err(ParseError::UnbalancedBrace {
+        offset: byte_offset(chars, start),
+    })
+}
@@ -0,0 +1,132 @@
+// Rendering of the interactive prompts from the PS1 and PS2 templates
+use std::env;
+
+use crate::editor::{PROMPT_IGNORE_END, PROMPT_IGNORE_START};
+use crate::shell::Shell;
+use crate::sys::{self, LocalTime};
+
+// Prompt used when PS1 isn't set
+const DEFAULT_PROMPT: &str = "$ ";
+
+// Prompt for the further lines of an unfinished command, used when PS2 isn't set
+const DEFAULT_CONTINUATION_PROMPT: &str = "> ";
+
+// Builds the prompt from $PS1, replacing its backslash escapes:
+//   \u user name        \h host name (up to the first dot)   \H full host name
+//   \w working dir, with ~ for $HOME     \W last component of the working dir
+//   \$ '#' for root, '$' otherwise        \? exit status of the last command
+//   \t time as 23:59:59  \T time as 11:59:59  \@ time as 11:59 PM  \A time as 23:59
+//   \d date as "Tue May 26"
+//   \n newline          \\ backslash
+//   \e and \NNN (octal) for escape sequences such as colors
+//   \[ and \] around non-printing sequences, so the line editor can measure the prompt
+pub fn render_prompt(shell: &Shell) -> String {
+    match shell.get_var("PS1") {
+        Some(template) => expand_prompt(shell, &template),
+        None => DEFAULT_PROMPT.to_string(),
+    }
+}
+
+// Builds the prompt for the next line of a command that isn't finished yet, like an
+// open quote or a for loop still waiting for its done, from $PS2 with the same escapes
+pub fn render_continuation_prompt(shell: &Shell) -> String {
+    match shell.get_var("PS2") {
+        Some(template) => expand_prompt(shell, &template),
+        None => DEFAULT_CONTINUATION_PROMPT.to_string(),
+    }
+}
+
+// Replaces the backslash escapes of a prompt template
+fn expand_prompt(shell: &Shell, template: &str) -> String {
+    let chars: Vec<char> = template.chars().collect();
+    let mut prompt = String::new();
+    // The clock is only read if the template asks for the time or date
+    let mut now: Option<LocalTime> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '\\' || i + 1 == chars.len() {
+            prompt.push(chars[i]);
+            i += 1;
+            continue;
+        }
+
+        let escape = chars[i + 1];
+        i += 2;
+        match escape {
+            'u' => prompt.push_str(&env::var("USER").unwrap_or_default()),
+            'h' => prompt.push_str(sys::host_name().split('.').next().unwrap_or_default()),
+            'H' => prompt.push_str(&sys::host_name()),
+            'w' => prompt.push_str(&current_dir(shell)),
+            'W' => {
+                let dir = current_dir(shell);
+                let name = dir.rsplit('/').find(|part| !part.is_empty());
+                prompt.push_str(name.unwrap_or("/"));
+            }
+            '$' => prompt.push(if sys::is_root() { '#' } else { '$' }),
+            '?' => prompt.push_str(&shell.last_status.to_string()),
+            't' | 'T' | '@' | 'A' | 'd' => {
+                let now = now.get_or_insert_with(sys::local_time);
+                prompt.push_str(&format_time(now, escape));
+            }
+            'n' => prompt.push('\n'),
+            '\\' => prompt.push('\\'),
+            'a' => prompt.push('\x07'),
+            'e' => prompt.push('\x1b'),
+            '[' => prompt.push(PROMPT_IGNORE_START),
+            ']' => prompt.push(PROMPT_IGNORE_END),
+            '0'..='7' => {
+                // Up to three octal digits, as in \033
+                let mut value = escape.to_digit(8).unwrap_or(0);
+                let mut digits = 1;
+                while digits < 3 && i < chars.len() && chars[i].is_digit(8) {
+                    value = value * 8 + chars[i].to_digit(8).unwrap_or(0);
+                    digits += 1;
+                    i += 1;
+                }
+                prompt.extend(char::from_u32(value));
+            }
+            // Unknown escapes are left exactly as they were written
+            other => {
+                prompt.push('\\');
+                prompt.push(other);
+            }
+        }
+    }
+    prompt
+}
+
+// Returns the working directory for display, with the home directory shown as ~,
+// or an empty string if it can't be read
+fn current_dir(shell: &Shell) -> String {
+    env::current_dir()
+        .map(|path| shell.abbreviate_home(&path))
+        .unwrap_or_default()
+}
+
+// Formats the time or date for one of the \t, \T, \@, \A and \d escapes
+fn format_time(now: &LocalTime, escape: char) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    // 12-hour clocks go from 12 through 11
+    let hour12 = match now.hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+    match escape {
+        't' => format!("{:02}:{:02}:{:02}", now.hour, now.minute, now.second),
+        'T' => format!("{:02}:{:02}:{:02}", hour12, now.minute, now.second),
+        '@' => {
+            let suffix = if now.hour < 12 { "AM" } else { "PM" };
+            format!("{:02}:{:02} {}", hour12, now.minute, suffix)
+        }
+        'A' => format!("{:02}:{:02}", now.hour, now.minute),
+        _ => format!(
+            "{} {} {:02}",
+            WEEKDAYS[now.weekday as usize % 7],
+            MONTHS[(now.month as usize + 11) % 12],
+            now.day
+        ),
+    }
+}
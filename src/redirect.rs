@@ -0,0 +1,311 @@
+// Applies redirections like `> file` and `2>&1` to the shell's own file descriptors
+// Builtins then write through the redirected descriptors and external programs inherit them
+use std::cell::Cell;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::process;
+
+use crate::expand;
+use crate::parser::{Redirect, RedirectKind};
+use crate::shell::{error_message, Shell};
+use crate::sys;
+
+thread_local! {
+    // How many redirections are in force on each of fds 0, 1 and 2
+    static REDIRECTED: Cell<[usize; 3]> = const { Cell::new([0; 3]) };
+}
+
+// Returns true while a redirection has `fd` pointing somewhere other than where the
+// shell found it
+fn is_redirected(fd: i32) -> bool {
+    REDIRECTED.with(|counts| {
+        counts
+            .get()
+            .get(fd as usize)
+            .is_some_and(|&count| count > 0)
+    })
+}
+
+fn count_redirection(fd: i32, change: fn(usize) -> usize) {
+    if let Ok(index @ 0..=2) = usize::try_from(fd) {
+        REDIRECTED.with(|counts| {
+            let mut updated = counts.get();
+            updated[index] = change(updated[index]);
+            counts.set(updated);
+        });
+    }
+}
+
+// Where builtins write their output (fd 1) or their error messages (fd 2)
+// That's the shell's own stdout or stderr, unless a program embedding the shell has
+// swapped in a writer of its own, like a Vec<u8> to capture what's written
+// The swapped-in writer only stands in for the fd while nothing has redirected it, so
+// `echo hi > f` still writes to f; a forked copy of the shell, as at either end of a
+// pipe, lets go of it and writes to the fd too
+pub struct Output {
+    fd: i32,
+    writer: Option<Box<dyn Write>>,
+}
+
+impl Output {
+    pub fn stdout() -> Self {
+        Output {
+            fd: 1,
+            writer: None,
+        }
+    }
+
+    pub fn stderr() -> Self {
+        Output {
+            fd: 2,
+            writer: None,
+        }
+    }
+
+    // Sends what's written to `writer` instead of the fd, as long as the fd isn't redirected
+    pub fn replace(&mut self, writer: impl Write + 'static) {
+        self.writer = Some(Box::new(writer));
+    }
+
+    // Goes back to writing to the fd, in a forked copy of the shell
+    // The writer is forgotten rather than dropped, since dropping a buffered writer
+    // would flush a second copy of whatever the parent still has to write
+    pub fn release(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            std::mem::forget(writer);
+        }
+    }
+
+    fn target(&mut self) -> Box<dyn Write + '_> {
+        match &mut self.writer {
+            Some(writer) if !is_redirected(self.fd) => Box::new(writer),
+            _ if self.fd == 2 => Box::new(io::stderr()),
+            _ => Box::new(io::stdout()),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.target().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.target().flush()
+    }
+}
+
+// Remembers where redirected file descriptors pointed before, and puts them back when dropped
+pub struct SavedFds {
+    // (redirected fd, copy of its original target or None if it wasn't open)
+    saved: Vec<(i32, Option<i32>)>,
+}
+
+impl SavedFds {
+    fn new() -> Self {
+        SavedFds { saved: Vec::new() }
+    }
+
+    // Makes `fd` refer to the same file as `source`, saving the original the first time
+    fn replace(&mut self, fd: i32, source: i32) -> io::Result<()> {
+        self.save(fd)?;
+        sys::duplicate_fd(source, fd)
+    }
+
+    // Closes `fd`, saving the original the first time
+    fn close(&mut self, fd: i32) -> io::Result<()> {
+        self.save(fd)?;
+        sys::close_fd(fd);
+        Ok(())
+    }
+
+    // Makes the redirections permanent, as `exec` without a command does, by letting go
+    // of the saved originals instead of putting them back
+    // The fds stay counted as redirected, since they no longer point where they started
+    pub fn keep(mut self) {
+        for (_, original) in self.saved.drain(..) {
+            if let Some(copy) = original {
+                sys::close_fd(copy);
+            }
+        }
+    }
+
+    fn save(&mut self, fd: i32) -> io::Result<()> {
+        // Anything already written to stdout belongs to the old target
+        if fd == 1 {
+            io::stdout().flush().ok();
+        }
+        if !self.saved.iter().any(|&(saved_fd, _)| saved_fd == fd) {
+            self.saved.push((fd, sys::save_fd(fd)?));
+            count_redirection(fd, |count| count + 1);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SavedFds {
+    fn drop(&mut self) {
+        io::stdout().flush().ok();
+        // Restore in reverse so the earliest saved copy is what ends up in place
+        for &(fd, original) in self.saved.iter().rev() {
+            count_redirection(fd, |count| count - 1);
+            match original {
+                Some(copy) => {
+                    let _ = sys::duplicate_fd(copy, fd);
+                    sys::close_fd(copy);
+                }
+                None => sys::close_fd(fd),
+            }
+        }
+    }
+}
+
+// Applies a command's redirections from left to right
+// The returned guard restores the shell's file descriptors when it goes out of scope
+pub fn apply_redirects(shell: &mut Shell, redirects: &[Redirect]) -> Result<SavedFds, String> {
+    let mut saved = SavedFds::new();
+    for redirect in redirects {
+        if let Some(body) = &redirect.here_doc {
+            // Quoting any part of the delimiter makes the body literal
+            let body = if redirect.target.contains(['\'', '"', '\\']) {
+                body.clone()
+            } else {
+                expand::expand_here_doc(shell, body)?
+            };
+            saved.save(redirect.fd).map_err(|e| error_message(&e))?;
+            let file = here_doc_file(&body).map_err(|e| error_message(&e))?;
+            point_at_file(&mut saved, &[redirect.fd], file)?;
+            continue;
+        }
+        let target = expand::expand_redirect_target(shell, &redirect.target)?;
+        apply_redirect(&mut saved, redirect, &target, shell.options.noclobber)?;
+    }
+    Ok(saved)
+}
+
+fn apply_redirect(
+    saved: &mut SavedFds,
+    redirect: &Redirect,
+    target: &str,
+    noclobber: bool,
+) -> Result<(), String> {
+    let fd = redirect.fd;
+    let (options, fds) = match redirect.kind {
+        // Here-documents always come with a body, which apply_redirects uses instead
+        RedirectKind::Input | RedirectKind::HereDoc | RedirectKind::HereDocStripTabs => {
+            (read_options(), vec![fd])
+        }
+        RedirectKind::Output => (truncate_options(noclobber, target)?, vec![fd]),
+        RedirectKind::Clobber => (truncate_options(false, target)?, vec![fd]),
+        RedirectKind::Append => (append_options(), vec![fd]),
+        // Both stdout and stderr share one open file, so their writes interleave correctly
+        RedirectKind::OutputAll => (truncate_options(noclobber, target)?, vec![1, 2]),
+        RedirectKind::AppendAll => (append_options(), vec![1, 2]),
+        RedirectKind::DuplicateOutput | RedirectKind::DuplicateInput => {
+            if target == "-" {
+                return saved.close(fd).map_err(|e| error_message(&e));
+            }
+            match target.parse::<i32>() {
+                Ok(source) => {
+                    return saved
+                        .replace(fd, source)
+                        .map_err(|_| format!("{}: Bad file descriptor", source))
+                }
+                // `>&file` is an old spelling of `&> file`
+                Err(_) if redirect.kind == RedirectKind::DuplicateOutput && fd == 1 => {
+                    (truncate_options(noclobber, target)?, vec![1, 2])
+                }
+                Err(_) => return Err(format!("{}: ambiguous redirect", target)),
+            }
+        }
+    };
+
+    // Save the originals first, so a closed fd is remembered as closed even if the file
+    // is then opened on it
+    for &fd in &fds {
+        saved.save(fd).map_err(|e| error_message(&e))?;
+    }
+    let file = options
+        .open(target)
+        .map_err(|e| format!("{}: {}", target, error_message(&e)))?;
+    point_at_file(saved, &fds, file)
+}
+
+// Makes each of `fds` refer to an open file
+// The File itself is closed afterwards, as the duplicated descriptors keep it open,
+// unless it was opened on one of `fds` to begin with; then it's kept, but has to be
+// made inheritable, which files opened by Rust aren't
+fn point_at_file(saved: &mut SavedFds, fds: &[i32], file: File) -> Result<(), String> {
+    for &fd in fds {
+        saved
+            .replace(fd, file.as_raw_fd())
+            .map_err(|e| error_message(&e))?;
+    }
+    if fds.contains(&file.as_raw_fd()) {
+        sys::inherit_fd(file.into_raw_fd()).map_err(|e| error_message(&e))?;
+    }
+    Ok(())
+}
+
+// Puts the body of a here-document in a temporary file and opens it for reading
+// The file is removed straight away, so it's gone once the descriptor is closed
+fn here_doc_file(body: &str) -> io::Result<File> {
+    let dir = env::temp_dir();
+    let mut attempt = 0;
+    loop {
+        let path = dir.join(format!("shell-heredoc-{}-{}", process::id(), attempt));
+        let created = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path);
+        match created {
+            Ok(mut file) => {
+                fs::remove_file(&path)?;
+                file.write_all(body.as_bytes())?;
+                file.seek(SeekFrom::Start(0))?;
+                return Ok(file);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    options
+}
+
+// Options for `>`-style redirections
+// With noclobber set, an existing regular file is refused instead of being truncated
+fn truncate_options(noclobber: bool, target: &str) -> Result<OpenOptions, String> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if !noclobber {
+        options.create(true).truncate(true);
+        return Ok(options);
+    }
+
+    match fs::metadata(target) {
+        Ok(metadata) if metadata.is_file() => {
+            return Err(format!("{}: cannot overwrite existing file", target));
+        }
+        // Devices like /dev/null can still be written to
+        Ok(_) => {}
+        // create_new also fails if the file appears between the check and the open
+        Err(_) => {
+            options.create_new(true);
+        }
+    }
+    Ok(options)
+}
+
+fn append_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    options
+}
@@ -0,0 +1,1617 @@
+// Core shell state and the read-execute loop
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::arith;
+use crate::builtins::{read_input_line, register_builtins, CommandHandler};
+use crate::complete::CompletionSpec;
+use crate::conditional;
+use crate::editor::{self, Completer, LineEditor};
+use crate::expand::{self, ProcessSubstitution};
+use crate::glob;
+use crate::history::History;
+use crate::jobs::{self, Job, JobStatus, JobTable};
+use crate::options::ShellOptions;
+use crate::parser::{
+    self, AndOrList, CaseItem, CommandList, CompoundCommand, Connector, FunctionDefinition,
+    Pipeline, SimpleCommand,
+};
+use crate::prompt;
+use crate::redirect::{self, Output};
+use crate::sys::{self, Fork};
+use crate::variables::{is_valid_name, Variables};
+
+// Helper function to search for an executable in PATH
+// Returns Some(path) if found with execute permissions, None otherwise
+pub fn find_executable_in_path(command: &str) -> Option<String> {
+    executables_in_path(command).next()
+}
+
+// Every executable called `command` in the directories of PATH, in PATH order,
+// as 'type -a' lists them
+// A command with a slash in it, like ./prog or /bin/ls, is a path to the program
+// itself and isn't looked for in PATH
+pub fn executables_in_path(command: &str) -> impl Iterator<Item = String> + '_ {
+    // Get the PATH environment variable
+    let path_var = if command.contains('/') {
+        String::new()
+    } else {
+        std::env::var("PATH").unwrap_or_default()
+    };
+
+    // Split PATH by the OS-specific delimiter
+    let delimiter = if cfg!(windows) { ";" } else { ":" };
+    let dirs: Vec<String> = if command.contains('/') {
+        vec![String::new()]
+    } else {
+        path_var.split(delimiter).map(String::from).collect()
+    };
+
+    // Check each directory in turn for a file with execute permissions, stopping as
+    // soon as the caller has what it needs
+    dirs.into_iter()
+        .map(move |dir| Path::new(&dir).join(command))
+        .filter(|path| is_executable(path))
+        .filter_map(|path| path.to_str().map(|s| s.to_string()))
+}
+
+// Searches PATH for a regular file called `name`, executable or not, as 'source' does
+pub fn find_file_in_path(name: &str) -> Option<String> {
+    let path_var = std::env::var("PATH").unwrap_or_default();
+    path_var
+        .split(':')
+        .map(|dir| Path::new(dir).join(name))
+        .find(|path| path.is_file())
+        .and_then(|path| path.to_str().map(|s| s.to_string()))
+}
+
+// Returns true if the path is a file with any execute permission bit set
+pub fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+// Returns true if a command's status says Ctrl-C stopped it, which stops the loop it's
+// in as well; with job control only the command itself gets the signal, so the loop
+// would otherwise carry on with the next command
+fn is_interrupted(status: i32) -> bool {
+    status == 128 + sys::SIGINT
+}
+
+// Returns true if an && / || chain is just one simple command, with nothing to do after it
+fn is_single_command(and_or: &AndOrList) -> bool {
+    and_or.rest.is_empty()
+        && !and_or.first.timed
+        && matches!(
+            and_or.first.commands.as_slice(),
+            [parser::Command::Simple(_)]
+        )
+}
+
+// The name the shell puts in front of its error messages, as bash uses "bash"
+pub const PROGRAM_NAME: &str = "shell";
+
+// Describes an I/O error the way shells do, without Rust's "(os error N)" suffix
+pub fn error_message(error: &io::Error) -> String {
+    let message = error.to_string();
+    match message.find(" (os error") {
+        Some(index) => message[..index].to_string(),
+        None => message,
+    }
+}
+
+// Measures the real and CPU time taken by a pipeline prefixed with `time`
+struct PipelineTimer {
+    started: Instant,
+    cpu_at_start: (Duration, Duration),
+}
+
+impl PipelineTimer {
+    fn start() -> Self {
+        PipelineTimer {
+            started: Instant::now(),
+            cpu_at_start: sys::cpu_times(),
+        }
+    }
+
+    // Prints the elapsed times to `err` in the same layout as bash
+    fn report(self, err: &mut impl Write) {
+        let real = self.started.elapsed();
+        let (user, system) = sys::cpu_times();
+        writeln!(err).ok();
+        writeln!(err, "real\t{}", format_duration(real)).ok();
+        writeln!(
+            err,
+            "user\t{}",
+            format_duration(user.saturating_sub(self.cpu_at_start.0))
+        )
+        .ok();
+        writeln!(
+            err,
+            "sys\t{}",
+            format_duration(system.saturating_sub(self.cpu_at_start.1))
+        )
+        .ok();
+    }
+}
+
+// Formats a duration as minutes and seconds, like 0m1.003s
+fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    format!(
+        "{}m{}.{:03}s",
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+// Reads a single command line from stdin
+// Uses the line editor when one is available (stdin is a terminal), plain reads otherwise
+// Returns Some(command) if a line was read, None if EOF was reached, or an Interrupted
+// error if the line was abandoned with Ctrl-C
+fn read_command_line(
+    editor: Option<&mut LineEditor>,
+    prompt: &str,
+    history: &[String],
+    completer: &mut dyn Completer,
+) -> io::Result<Option<String>> {
+    if let Some(editor) = editor {
+        return editor.read_line(prompt, history, completer);
+    }
+
+    print!("{}", editor::strip_prompt_markers(prompt));
+    io::stdout().flush().unwrap();
+
+    let mut command = String::new();
+    match io::stdin().read_line(&mut command) {
+        Ok(bytes_read) if bytes_read > 0 => {
+            // Input written on Windows ends its lines with \r\n; the \r isn't part of the
+            // command, and left in it could be escaped by a trailing backslash
+            if command.ends_with("\r\n") {
+                command.truncate(command.len() - 2);
+                command.push('\n');
+            }
+            Ok(Some(command))
+        }
+        _ => Ok(None),
+    }
+}
+
+// A break or continue on its way out to the loop it's for, with how many of the loops
+// it's in are still to be left: 1 for the innermost
+// Return leaves every loop on its way out of the function or sourced file it's in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopControl {
+    Break(usize),
+    Continue(usize),
+    Return,
+}
+
+// A function defined in the shell, with the line its definition was parsed from,
+// which the lines of the commands in it count from
+#[derive(Debug, Clone)]
+pub struct ShellFunction {
+    pub definition: Rc<FunctionDefinition>,
+    pub line_base: usize,
+}
+
+// Where a function or sourced file was called from, for 'caller'
+#[derive(Debug, Clone)]
+pub struct CallFrame {
+    // What was called: the function's name, or "source" for a sourced file
+    pub name: String,
+    // The file, or "main", and line number the call was made from
+    pub file: String,
+    pub line: usize,
+}
+
+// A starting point for $RANDOM that differs from one shell to the next
+fn random_seed() -> u32 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos());
+    nanos ^ process::id().rotate_left(16)
+}
+
+// Startup file sourced from the home directory by interactive shells
+const DEFAULT_RCFILE: &str = ".myshellrc";
+
+// History file in the home directory that interactive shells use unless HISTFILE says
+// otherwise
+const DEFAULT_HISTFILE: &str = ".myshell_history";
+
+// Holds the state that lives for the whole shell session
+pub struct Shell {
+    // Registry of builtin commands, loaded once at startup
+    builtins: HashMap<&'static str, CommandHandler>,
+    // Where builtins write their output and their error messages: stdout and stderr,
+    // unless a program embedding the shell swaps in writers of its own with
+    // `Output::replace`, which only receive what isn't redirected or piped elsewhere
+    pub out: Output,
+    pub err: Output,
+    // Child processes feeding <(...) and >(...) arguments of the command being run
+    pub process_substitutions: Vec<ProcessSubstitution>,
+    // The status of the last $(...) run while expanding the command being run, which
+    // becomes the status of a command that's only assignments
+    pub substitution_status: Option<i32>,
+    // Options toggled with the 'set' builtin
+    pub options: ShellOptions,
+    // Shell variables, including the exported ones that make up the environment
+    pub variables: Variables,
+    // $0: the name of the shell, or of the script it's running
+    pub arg0: String,
+    // The positional parameters $1, $2 and so on: the arguments of the script, or
+    // those given with `set --`
+    pub positional_params: Vec<String>,
+    // Exit status of the most recently executed command
+    pub last_status: i32,
+    // Process ID of the most recent pipeline started with &, for $!
+    pub last_background_pid: Option<i32>,
+    // Jobs started in the background with &
+    pub jobs: JobTable,
+    // Command lines entered at the prompt
+    pub history: History,
+    // The file the history was read from, which it's saved back to when the shell exits
+    pub history_file: Option<String>,
+    // Whether the line being run went into the history, which HISTCONTROL can prevent
+    pub line_in_history: bool,
+    // Directories saved by pushd, most recent first; the current directory comes before them
+    pub dir_stack: Vec<PathBuf>,
+    // The terminal title most recently set with settitle
+    pub title: Option<String>,
+    // Where commands were found in PATH, by name, with how many times each has been run
+    // from there since, so PATH isn't searched every time; see `find_command`
+    pub command_hash: BTreeMap<String, (String, usize)>,
+    // Functions defined with NAME() { ...; }, by name
+    pub functions: BTreeMap<String, ShellFunction>,
+    // How the arguments of commands are completed, as set with the 'complete' builtin
+    pub completion_specs: BTreeMap<String, CompletionSpec>,
+    // Commands set with the 'trap' builtin, by the condition that runs them, like EXIT
+    pub traps: BTreeMap<String, String>,
+    // The file commands are being read from, or "main" when they're typed or given with -c
+    pub source_name: String,
+    // The line of `source_name` that the command being run starts on
+    pub line_number: usize,
+    // The line of `source_name` that the commands being run were parsed from, which
+    // the lines of the commands inside them count from
+    line_base: usize,
+    // The calls being run, outermost first
+    pub call_stack: Vec<CallFrame>,
+    // $SECONDS counts up from `seconds_offset` at `seconds_start`: the shell's start
+    // until something is assigned to it
+    seconds_start: Instant,
+    seconds_offset: i64,
+    // State of the generator behind $RANDOM, which moves on each time it's read
+    random_state: Cell<u32>,
+    // Set while reading commands typed at a terminal, which enables job notifications
+    pub interactive: bool,
+    // Set in a forked copy of the shell that only has one command left to run, so an
+    // external program can replace the copy rather than run under it, which lets
+    // signals sent to the job reach the program and its status reach the shell
+    pub exec_in_place: bool,
+    // Set in an interactive shell, which runs each job in its own process group and
+    // hands it the terminal while it's in the foreground, so Ctrl-Z stops just the job
+    pub job_control: bool,
+    // The terminal modes the shell puts back when a foreground job leaves the terminal
+    // in a state of its own, as an editor that's killed or stopped leaves raw mode
+    terminal_modes: Option<sys::Termios>,
+    // The text of the command line item being run, which names its job if it's stopped
+    running_text: String,
+    // How many loops the command being run is inside of, which break and continue
+    // can't go beyond
+    pub loop_depth: usize,
+    // Set by break and continue, which stops the rest of the loop body running
+    pub loop_control: Option<LoopControl>,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell::new()
+    }
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        Shell {
+            builtins: register_builtins(),
+            out: Output::stdout(),
+            err: Output::stderr(),
+            process_substitutions: Vec::new(),
+            substitution_status: None,
+            options: ShellOptions::default(),
+            variables: Variables::from_environment(),
+            arg0: PROGRAM_NAME.to_string(),
+            positional_params: Vec::new(),
+            last_status: 0,
+            last_background_pid: None,
+            jobs: JobTable::default(),
+            history: History::default(),
+            history_file: None,
+            line_in_history: false,
+            dir_stack: Vec::new(),
+            title: None,
+            command_hash: BTreeMap::new(),
+            functions: BTreeMap::new(),
+            completion_specs: BTreeMap::new(),
+            traps: BTreeMap::new(),
+            source_name: "main".to_string(),
+            line_number: 0,
+            line_base: 0,
+            call_stack: Vec::new(),
+            seconds_start: Instant::now(),
+            seconds_offset: 0,
+            random_state: Cell::new(random_seed()),
+            interactive: false,
+            exec_in_place: false,
+            job_control: false,
+            terminal_modes: None,
+            running_text: String::new(),
+            loop_depth: 0,
+            loop_control: None,
+        }
+    }
+
+    // Main shell loop - continuously reads and executes commands
+    // An interactive session first sources the startup file: `rcfile` if given, else ~/.myshellrc
+    pub fn run(&mut self, rcfile: Option<&str>) {
+        // Only use the raw-mode line editor when a user is typing at a terminal
+        let interactive = sys::is_terminal(0);
+        self.interactive = interactive;
+        if interactive {
+            // Take the terminal in a process group of our own, as job control needs
+            sys::ignore_job_control_signals();
+            let _ = sys::set_process_group(0, 0);
+            self.job_control = sys::set_foreground_group(sys::process_group()).is_ok();
+            self.terminal_modes = sys::get_termios(0).ok();
+        }
+        let mut editor = if interactive {
+            sys::watch_window_size();
+            Some(LineEditor::new())
+        } else {
+            None
+        };
+
+        if interactive && self.get_var("HISTFILE").is_none() {
+            if let Ok(home) = std::env::var("HOME") {
+                let histfile = Path::new(&home).join(DEFAULT_HISTFILE);
+                let _ = self.set_var("HISTFILE", &histfile.to_string_lossy());
+            }
+        }
+
+        match rcfile {
+            Some(path) => self.source_rcfile(path),
+            None if interactive => {
+                if let Ok(home) = std::env::var("HOME") {
+                    let default_rcfile = Path::new(&home).join(DEFAULT_RCFILE);
+                    // A missing default startup file is normal, so only source it if it exists
+                    if default_rcfile.is_file() {
+                        self.source_rcfile(&default_rcfile.to_string_lossy());
+                    }
+                }
+            }
+            None => {}
+        }
+
+        // The history carries on from earlier sessions, from the file HISTFILE names
+        // once the startup file has had its chance to change it
+        if interactive {
+            let histfile = self.get_var("HISTFILE").unwrap_or_default();
+            if !histfile.is_empty() {
+                // There's no file yet the first time round
+                let _ = self.history.load(&histfile);
+                self.history_file = Some(histfile);
+            }
+        }
+
+        // Read user input until EOF is reached
+        'read: loop {
+            self.notify_finished_jobs();
+            self.run_signal_traps();
+            let prompt = prompt::render_prompt(self);
+            let history = self.history.entries().to_vec();
+            let mut command = match read_command_line(editor.as_mut(), &prompt, &history, self) {
+                Ok(Some(command)) => command,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                _ => break,
+            };
+            // A command that isn't finished at the end of the line, like an open quote or
+            // a for loop still waiting for its done, goes on over more lines read with the
+            // PS2 prompt; Ctrl-C abandons all of it, and the end of input leaves what
+            // there is for parsing to report
+            while parser::is_incomplete(&command) {
+                if !command.ends_with('\n') {
+                    command.push('\n');
+                }
+                let prompt = prompt::render_continuation_prompt(self);
+                match read_command_line(editor.as_mut(), &prompt, &history, self) {
+                    Ok(Some(line)) => command.push_str(&line),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue 'read,
+                    _ => break,
+                }
+            }
+            if self.options.verbose {
+                writeln!(self.err, "{}", command.trim_end_matches('\n')).ok();
+            }
+            if interactive {
+                self.add_to_history(&command);
+            }
+            // $LINENO is the line the command starts on, and then moves past the rest of it
+            let more_lines = command.trim_end_matches('\n').matches('\n').count();
+            let first_line = self.line_number + 1;
+            self.line_number = first_line;
+            self.run_line(&command);
+            self.line_number = first_line + more_lines;
+        }
+        self.exit(self.last_status);
+    }
+
+    // Adds a command line to the history, unless HISTCONTROL, a list of settings
+    // separated by colons, leaves it out: ignorespace skips lines that start with a
+    // space, ignoredups those the same as the latest entry, and ignoreboth does both
+    fn add_to_history(&mut self, command: &str) {
+        self.line_in_history = false;
+        let control = self.get_var("HISTCONTROL").unwrap_or_default();
+        let has = |setting| {
+            control
+                .split(':')
+                .any(|value| value == setting || value == "ignoreboth")
+        };
+        if has("ignorespace") && command.starts_with(' ') {
+            return;
+        }
+        let line = command.trim_end_matches(['\n', '\r']);
+        if has("ignoredups")
+            && self
+                .history
+                .entries()
+                .last()
+                .is_some_and(|last| last == line)
+        {
+            return;
+        }
+        self.history.add(command);
+        self.line_in_history = true;
+    }
+
+    // Sends SIGHUP to the jobs that are still running or stopped, as an interactive
+    // shell does when it exits, except those marked with disown -h
+    fn hang_up_jobs(&mut self) {
+        self.jobs.update();
+        for job in self.jobs.iter() {
+            if !job.is_finished() && !job.no_hang_up {
+                sys::hang_up_group(job.pgid).ok();
+            }
+        }
+    }
+
+    // Ends the shell: runs the EXIT trap, if one is set, then flushes the output and
+    // exits with `status`
+    // The trap is removed before it runs, so an exit inside it doesn't run it again
+    pub fn exit(&mut self, status: i32) -> ! {
+        if let Some(command) = self.traps.remove("EXIT") {
+            self.run_line(&command);
+        }
+        if self.job_control {
+            self.hang_up_jobs();
+        }
+        if let Some(path) = self.history_file.take() {
+            if let Err(e) = self.history.save(&path) {
+                self.print_error(&path, &error_message(&e));
+            }
+        }
+        self.out.flush().ok();
+        self.err.flush().ok();
+        io::stdout().flush().ok();
+        io::stderr().flush().ok();
+        process::exit(status.rem_euclid(256));
+    }
+
+    // Sources a startup file, reporting failure without stopping the shell from starting
+    pub fn source_rcfile(&mut self, path: &str) {
+        if let Err(e) = self.source_file(path) {
+            self.print_error("", &e);
+        }
+    }
+
+    // Runs every line of a file in the current shell, as the 'source' builtin does
+    // Returns the exit status of the last command run
+    pub fn source_file(&mut self, path: &str) -> Result<i32, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("{}: {}", path, error_message(&e)))?;
+        let outer_source = std::mem::replace(&mut self.source_name, path.to_string());
+        let outer_line = self.line_number;
+        let status = self.execute_lines(&contents);
+        self.source_name = outer_source;
+        self.line_number = outer_line;
+        Ok(status)
+    }
+
+    // Runs a script given as one string, like a sourced file or the command of -c,
+    // a complete command at a time, keeping $LINENO at the line each one starts on
+    // Returns the exit status of the last command run
+    pub fn execute_lines(&mut self, contents: &str) -> i32 {
+        // Blank and comment lines are skipped without touching the status, and aren't
+        // echoed by set -v either
+        let mut status = 0;
+        for (line, command) in parser::split_commands(contents) {
+            if self.options.verbose {
+                write!(self.err, "{}", command).ok();
+            }
+            self.line_number = line;
+            status = self.run_line(&command);
+            // Only a return can get this far, and it ends the file
+            if self.loop_control.is_some() {
+                break;
+            }
+        }
+        status
+    }
+
+    // Parses, expands and executes a single command line against the shell's state
+    // Returns its exit status, which is also remembered as the shell's last status
+    // This is all the interactive loop does with each line read, and what a program
+    // embedding the shell calls to run commands through it
+    pub fn run_line(&mut self, line: &str) -> i32 {
+        // A blank or comment line leaves the previous status alone
+        let Some(line) = parser::preprocess_line(line) else {
+            return self.last_status;
+        };
+        match parser::parse(&line) {
+            Ok(list) => {
+                let outer_base = std::mem::replace(&mut self.line_base, self.line_number);
+                let status = self.execute_list(&list);
+                self.line_base = outer_base;
+                status
+            }
+            Err(e) => {
+                self.print_error("", &e.to_string());
+                self.last_status = 2;
+                2
+            }
+        }
+    }
+
+    // Runs the items of a list in order, updating the last status after each one
+    // A break or continue skips whatever is left of the list
+    fn execute_list(&mut self, list: &CommandList) -> i32 {
+        for item in &list.items {
+            if self.loop_control.is_some() {
+                break;
+            }
+            self.line_number = self.line_base + item.line;
+            let outer_text = std::mem::replace(&mut self.running_text, item.text.clone());
+            self.last_status = if item.background {
+                self.start_background(&item.and_or, &item.text)
+            } else {
+                self.execute_and_or(&item.and_or)
+            };
+            self.running_text = outer_text;
+            self.run_signal_traps();
+        }
+        self.last_status
+    }
+
+    // Runs the traps of the signals that have arrived since the last check, leaving
+    // the last status as it was
+    pub fn run_signal_traps(&mut self) {
+        for signal_number in sys::take_pending_signals() {
+            let name = sys::SIGNAL_NAMES[signal_number as usize];
+            let Some(command) = self.traps.get(name).cloned() else {
+                continue;
+            };
+            let status = self.last_status;
+            self.run_line(&command);
+            self.last_status = status;
+        }
+    }
+
+    // Drops the traps in a forked copy of the shell, as they belong to the shell that
+    // set them; a subshell can still set its own
+    // Signals caught for a trap go back to how they were handled before it, but those
+    // ignored with an empty trap stay ignored
+    pub fn reset_traps(&mut self) {
+        for (condition, command) in std::mem::take(&mut self.traps) {
+            let number = sys::SIGNAL_NAMES.iter().position(|&name| name == condition);
+            if let Some(number) = number.filter(|_| !command.is_empty()) {
+                sys::set_signal_action(number as i32, sys::SignalAction::Restore);
+            }
+        }
+    }
+
+    // Runs the pipelines of an && / || chain, skipping those whose condition isn't met
+    fn execute_and_or(&mut self, and_or: &AndOrList) -> i32 {
+        let mut status = self.execute_pipeline(&and_or.first);
+        for (connector, pipeline) in &and_or.rest {
+            self.last_status = status;
+            let run = match connector {
+                Connector::And => status == 0,
+                Connector::Or => status != 0,
+            };
+            if run && self.loop_control.is_none() {
+                status = self.execute_pipeline(pipeline);
+            }
+        }
+        status
+    }
+
+    // Runs an && / || chain in a forked copy of the shell without waiting for it to finish
+    fn start_background(&mut self, and_or: &AndOrList, text: &str) -> i32 {
+        io::stdout().flush().ok();
+        match sys::fork_process() {
+            Ok(Fork::Child) => {
+                self.join_job(0);
+                self.exec_in_place = is_single_command(and_or);
+                let status = self.execute_and_or(and_or);
+                io::stdout().flush().ok();
+                process::exit(status);
+            }
+            Ok(Fork::Parent(pid)) => {
+                self.add_to_job(pid, pid);
+                self.last_background_pid = Some(pid);
+                let id = self.jobs.add(Job::new(&[pid], text));
+                if self.interactive {
+                    writeln!(self.err, "[{}] {}", id, pid).ok();
+                }
+                0
+            }
+            Err(e) => {
+                self.print_error("fork", &error_message(&e));
+                1
+            }
+        }
+    }
+
+    // Sets up a forked copy of the shell that runs part of a job
+    // With job control it joins the job's process group `pgid` (or starts one if 0), and
+    // either way it leaves job control and the shell's traps to the shell itself, and
+    // writes to the real stdout and stderr rather than any writers swapped in for them
+    fn join_job(&mut self, pgid: i32) {
+        if self.job_control {
+            let _ = sys::set_process_group(0, pgid);
+        }
+        self.leave_job_control();
+        self.reset_traps();
+        self.history_file = None;
+        self.out.release();
+        self.err.release();
+    }
+
+    // Turns job control off in a forked copy of the shell, putting back the default
+    // handling of the signals the shell ignores for it
+    // SIGPIPE goes back to the default too, so a forked builtin writing into a pipe
+    // that's been closed, as in `(echo a; echo b) | true`, ends like other programs
+    pub fn leave_job_control(&mut self) {
+        if std::mem::take(&mut self.job_control) {
+            sys::reset_job_control_signals();
+        }
+        sys::reset_broken_pipe_signal();
+    }
+
+    // Puts a newly started child into process group `pgid`, from the shell's side
+    // The child does the same itself, so it doesn't matter which of them gets there first
+    fn add_to_job(&self, pid: i32, pgid: i32) {
+        if self.job_control {
+            let _ = sys::set_process_group(pid, pgid);
+        }
+    }
+
+    // Waits for a job running in the foreground, handing it the terminal meanwhile
+    // when job control is on
+    // If it's stopped, as by Ctrl-Z, it goes into the job table so fg or bg can carry it on
+    // Returns the job's exit status
+    fn wait_for_foreground(&mut self, mut job: Job) -> i32 {
+        if self.job_control {
+            let _ = sys::set_foreground_group(job.pgid);
+        }
+        job.wait();
+        if self.job_control {
+            let _ = sys::set_foreground_group(sys::process_group());
+            self.settle_terminal_modes(&mut job);
+        }
+
+        let status = job.status();
+        if let JobStatus::Stopped(_) = status {
+            let id = self.jobs.add(job);
+            if let Some(job) = self.jobs.get_mut(id) {
+                // Start a new line after the ^Z the terminal echoed
+                writeln!(self.err, "\n{}", jobs::format_job(job, '+')).ok();
+            }
+        }
+        status.exit_code()
+    }
+
+    // Sorts out the terminal's modes once a foreground job has given the terminal back
+    // A job that exits normally may have changed them on purpose, as stty does, so
+    // they're kept; one that was killed or stopped can have left them in any state, so
+    // the shell's own are put back, and a stopped job's are saved for when it carries on
+    fn settle_terminal_modes(&mut self, job: &mut Job) {
+        match job.status() {
+            JobStatus::Exited(_) => self.terminal_modes = sys::get_termios(0).ok(),
+            status => {
+                if let JobStatus::Stopped(_) = status {
+                    job.terminal_modes = sys::get_termios(0).ok();
+                }
+                if let Some(modes) = &self.terminal_modes {
+                    let _ = sys::set_termios(0, modes);
+                }
+            }
+        }
+    }
+
+    // Carries on a stopped job, either in the foreground, waiting for it as fg does,
+    // or in the background as bg does
+    // Returns the job's status in the foreground, and 0 in the background
+    pub fn continue_job(&mut self, mut job: Job, foreground: bool) -> Result<i32, String> {
+        if foreground {
+            let _ = sys::set_foreground_group(job.pgid);
+            if let Some(modes) = job.terminal_modes.take() {
+                let _ = sys::set_termios(0, &modes);
+            }
+        }
+        if let Err(e) = sys::continue_group(job.pgid) {
+            self.jobs.add(job);
+            return Err(error_message(&e));
+        }
+        job.set_running();
+        if foreground {
+            Ok(self.wait_for_foreground(job))
+        } else {
+            self.jobs.add(job);
+            Ok(0)
+        }
+    }
+
+    // Reports background jobs that have finished since the last prompt, like
+    // `[1]+  Done                    sleep 5`, and forgets about them
+    fn notify_finished_jobs(&mut self) {
+        self.jobs.update();
+        for (job, mark) in self.jobs.take_finished() {
+            if self.interactive {
+                writeln!(self.out, "{}", jobs::format_job(&job, mark)).ok();
+            }
+        }
+    }
+
+    // Runs a pipeline, reporting how long it took afterwards if it was prefixed with `time`
+    // The pipeline's status is the status of its last command, inverted if it was
+    // prefixed with `!`: 1 if that succeeded, otherwise 0
+    fn execute_pipeline(&mut self, pipeline: &Pipeline) -> i32 {
+        let timer = pipeline.timed.then(PipelineTimer::start);
+
+        let status = match pipeline.commands.as_slice() {
+            [] => 0,
+            // A lone command runs directly in the shell so builtins like cd affect it
+            [command] => self.run_command(command),
+            commands => self.execute_piped_commands(commands),
+        };
+        let status = if pipeline.negated {
+            i32::from(status == 0)
+        } else {
+            status
+        };
+
+        if let Some(timer) = timer {
+            timer.report(&mut self.err);
+        }
+        status
+    }
+
+    // Runs each command of a pipeline in its own forked process, connected by pipes,
+    // and waits for all of them to finish
+    fn execute_piped_commands(&mut self, commands: &[parser::Command]) -> i32 {
+        let mut pids = Vec::new();
+        // The commands share the process group of the first one
+        let mut pgid = 0;
+        // Read end of the pipe coming from the previous command
+        let mut input_fd: Option<i32> = None;
+
+        for (index, command) in commands.iter().enumerate() {
+            let output_pipe = if index + 1 < commands.len() {
+                match sys::create_pipe() {
+                    Ok(pipe) => Some(pipe),
+                    Err(e) => {
+                        self.print_error("pipe", &error_message(&e));
+                        break;
+                    }
+                }
+            } else {
+                None
+            };
+
+            // Nothing buffered may be written twice once there are two processes
+            io::stdout().flush().ok();
+
+            match sys::fork_process() {
+                Ok(Fork::Child) => {
+                    self.join_job(pgid);
+                    if let Some(fd) = input_fd {
+                        let _ = sys::duplicate_fd(fd, 0);
+                        sys::close_fd(fd);
+                    }
+                    if let Some((read_fd, write_fd)) = output_pipe {
+                        let _ = sys::duplicate_fd(write_fd, 1);
+                        sys::close_fd(write_fd);
+                        sys::close_fd(read_fd);
+                    }
+                    self.exec_in_place = matches!(command, parser::Command::Simple(_));
+                    let status = self.run_command(command);
+                    io::stdout().flush().ok();
+                    process::exit(status);
+                }
+                Ok(Fork::Parent(pid)) => {
+                    if pgid == 0 {
+                        pgid = pid;
+                    }
+                    self.add_to_job(pid, pgid);
+                    pids.push(pid);
+                }
+                Err(e) => self.print_error("fork", &error_message(&e)),
+            }
+
+            // The parent keeps only the read end of the newest pipe, for the next command
+            if let Some(fd) = input_fd {
+                sys::close_fd(fd);
+            }
+            input_fd = output_pipe.map(|(read_fd, write_fd)| {
+                sys::close_fd(write_fd);
+                read_fd
+            });
+        }
+
+        if let Some(fd) = input_fd {
+            sys::close_fd(fd);
+        }
+        if pids.is_empty() {
+            return 1;
+        }
+        let job = Job::new(&pids, &self.running_text);
+        self.wait_for_foreground(job)
+    }
+
+    // Runs one command of a pipeline, whether simple or compound
+    fn run_command(&mut self, command: &parser::Command) -> i32 {
+        match command {
+            parser::Command::Simple(command) => self.execute_simple_command(command),
+            parser::Command::Compound(compound, redirects) => {
+                let status = redirect::apply_redirects(self, redirects)
+                    .and_then(|_saved_fds| self.execute_compound(compound));
+                expand::finish_process_substitutions(self);
+                status.unwrap_or_else(|e| {
+                    self.print_error("", &e);
+                    1
+                })
+            }
+            parser::Command::Function(definition) => {
+                let function = ShellFunction {
+                    definition: Rc::clone(definition),
+                    line_base: self.line_base,
+                };
+                self.functions.insert(definition.name.clone(), function);
+                0
+            }
+        }
+    }
+
+    // Runs a function with `args` after its name as the positional parameters, which
+    // are put back afterwards
+    // A return inside it ends it early, with the status given to return
+    pub fn call_function(&mut self, function: &ShellFunction, args: &[&str]) -> i32 {
+        let params = args[1..].iter().map(|arg| arg.to_string()).collect();
+        let outer_params = std::mem::replace(&mut self.positional_params, params);
+        // Loops around the call can't be broken out of from inside it
+        let outer_loop_depth = std::mem::take(&mut self.loop_depth);
+        self.call_stack.push(CallFrame {
+            name: function.definition.name.clone(),
+            file: self.source_name.clone(),
+            line: self.line_number,
+        });
+        let outer_line = self.line_number;
+        let outer_base = std::mem::replace(&mut self.line_base, function.line_base);
+        let mut status = self.run_command(&function.definition.body);
+        if self.loop_control == Some(LoopControl::Return) {
+            self.loop_control = None;
+            status = self.last_status;
+        }
+        self.line_base = outer_base;
+        self.line_number = outer_line;
+        self.call_stack.pop();
+        self.loop_depth = outer_loop_depth;
+        self.positional_params = outer_params;
+        status
+    }
+
+    // Runs a control structure in the current shell
+    fn execute_compound(&mut self, compound: &CompoundCommand) -> Result<i32, String> {
+        match compound {
+            CompoundCommand::Case { word, items } => self.execute_case(word, items),
+            CompoundCommand::If {
+                branches,
+                otherwise,
+            } => Ok(self.execute_if(branches, otherwise.as_ref())),
+            CompoundCommand::While {
+                condition,
+                body,
+                until,
+            } => self.in_loop(|shell| Ok(shell.execute_while(condition, body, *until))),
+            CompoundCommand::For { name, words, body } => {
+                self.in_loop(|shell| shell.execute_for(name, words.as_deref(), body))
+            }
+            // A [[ ]] that can't be evaluated, as with a bad regular expression, fails
+            // with status 2 rather than 1
+            CompoundCommand::Conditional(condition) => {
+                match conditional::evaluate(self, condition) {
+                    Ok(holds) => Ok(if holds { 0 } else { 1 }),
+                    Err(e) => {
+                        self.print_error("", &e);
+                        Ok(2)
+                    }
+                }
+            }
+            CompoundCommand::Arithmetic(expression) => {
+                let value = self.evaluate_arithmetic(expression)?;
+                Ok(if value != 0 { 0 } else { 1 })
+            }
+            CompoundCommand::ArithmeticFor {
+                init,
+                condition,
+                step,
+                body,
+            } => self.in_loop(|shell| shell.execute_arithmetic_for(init, condition, step, body)),
+            CompoundCommand::Select { name, words, body } => {
+                self.in_loop(|shell| shell.execute_select(name, words.as_deref(), body))
+            }
+            CompoundCommand::Subshell(list) => self.execute_subshell(list),
+            CompoundCommand::Group(list) => Ok(self.execute_list(list)),
+        }
+    }
+
+    // Runs a list in a forked copy of the shell, so that variable assignments,
+    // directory changes and exits inside it don't affect the shell itself
+    fn execute_subshell(&mut self, list: &CommandList) -> Result<i32, String> {
+        self.run_as_job(|shell| {
+            shell.exec_in_place = matches!(list.items.as_slice(),
+                [item] if !item.background && is_single_command(&item.and_or));
+            shell.execute_list(list)
+        })
+    }
+
+    // Runs `run` in a forked copy of the shell, as a foreground job of its own that
+    // Ctrl-Z can stop like any program, and returns its status
+    pub fn run_as_job(&mut self, run: impl FnOnce(&mut Self) -> i32) -> Result<i32, String> {
+        io::stdout().flush().ok();
+        match sys::fork_process().map_err(|e| format!("fork: {}", error_message(&e)))? {
+            Fork::Child => {
+                self.join_job(0);
+                let status = run(self);
+                self.exit(status);
+            }
+            Fork::Parent(pid) => {
+                self.add_to_job(pid, pid);
+                let job = Job::new(&[pid], &self.running_text);
+                Ok(self.wait_for_foreground(job))
+            }
+        }
+    }
+
+    // Runs the commands of the first case branch with a pattern matching `word`
+    // The status is 0 if no branch matches
+    fn execute_case(&mut self, word: &str, items: &[CaseItem]) -> Result<i32, String> {
+        let subject = expand::expand_word(self, word)?;
+        for item in items {
+            for pattern in &item.patterns {
+                let pattern = expand::expand_pattern(self, pattern)?;
+                if glob::matches(&pattern, &subject, self.options.extglob) {
+                    return Ok(self.execute_list(&item.body));
+                }
+            }
+        }
+        Ok(0)
+    }
+
+    // Runs the commands of the first branch whose condition succeeds, or of the else
+    // branch if none does
+    // The status is that of the commands run, or 0 if no branch runs
+    fn execute_if(
+        &mut self,
+        branches: &[(CommandList, CommandList)],
+        otherwise: Option<&CommandList>,
+    ) -> i32 {
+        for (condition, body) in branches {
+            if self.execute_list(condition) == 0 {
+                return self.execute_list(body);
+            }
+        }
+        otherwise.map_or(0, |body| self.execute_list(body))
+    }
+
+    // Runs the body for as long as the condition succeeds, or with `until`, for as
+    // long as it fails
+    // The status is that of the last run of the body, or 0 if it never runs
+    fn execute_while(&mut self, condition: &CommandList, body: &CommandList, until: bool) -> i32 {
+        let mut status = 0;
+        while (self.execute_list(condition) == 0) != until {
+            if self.loop_finished() {
+                break;
+            }
+            status = self.execute_list(body);
+            if self.loop_finished() || is_interrupted(status) {
+                break;
+            }
+        }
+        status
+    }
+
+    // Runs the body once for each of the expanded words, with the variable `name` set
+    // to it, or for each positional parameter when there are no words
+    fn execute_for(
+        &mut self,
+        name: &str,
+        words: Option<&[String]>,
+        body: &CommandList,
+    ) -> Result<i32, String> {
+        let values = match words {
+            Some(words) => expand::expand_words(self, words)?,
+            None => self.positional_params.clone(),
+        };
+        let mut status = 0;
+        for value in values {
+            self.set_var(name, &value)?;
+            status = self.execute_list(body);
+            if self.loop_finished() || is_interrupted(status) {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    // Evaluates INIT, then runs the body for as long as CONDITION comes to anything
+    // but 0, evaluating STEP after each run; an empty CONDITION counts as true
+    // The status is that of the last run of the body, or 0 if it never runs
+    fn execute_arithmetic_for(
+        &mut self,
+        init: &str,
+        condition: &str,
+        step: &str,
+        body: &CommandList,
+    ) -> Result<i32, String> {
+        self.evaluate_arithmetic(init)?;
+        let mut status = 0;
+        while condition.trim().is_empty() || self.evaluate_arithmetic(condition)? != 0 {
+            status = self.execute_list(body);
+            if self.loop_finished() || is_interrupted(status) {
+                break;
+            }
+            self.evaluate_arithmetic(step)?;
+        }
+        Ok(status)
+    }
+
+    // Expands and evaluates the expression of a (( )) command or an arithmetic for
+    fn evaluate_arithmetic(&mut self, expression: &str) -> Result<i64, String> {
+        let expression: Vec<char> = expression.chars().collect();
+        expand::evaluate_expression(self, &expression)
+    }
+
+    // Offers the expanded words, or the positional parameters, as a numbered menu on
+    // stderr and reads a choice after the PS3 prompt, then prompts again after each run
+    // of the body, until the body breaks out or the input ends
+    // The line read goes in $REPLY, and the body runs with `name` set to the word
+    // picked; a blank line, or one that isn't the number of a word, shows the menu again
+    fn execute_select(
+        &mut self,
+        name: &str,
+        words: Option<&[String]>,
+        body: &CommandList,
+    ) -> Result<i32, String> {
+        let values = match words {
+            Some(words) => expand::expand_words(self, words)?,
+            None => self.positional_params.clone(),
+        };
+        if values.is_empty() {
+            return Ok(0);
+        }
+        let width = values.len().to_string().len();
+        let mut status = 0;
+        let mut show_menu = true;
+        loop {
+            if std::mem::take(&mut show_menu) {
+                for (index, value) in values.iter().enumerate() {
+                    writeln!(self.err, "{:>width$}) {}", index + 1, value).ok();
+                }
+            }
+            let prompt = self.get_var("PS3").unwrap_or_else(|| "#? ".to_string());
+            write!(self.err, "{}", prompt).ok();
+            self.err.flush().ok();
+            let mut line = Vec::new();
+            match read_input_line(&mut line, true, false, None) {
+                Ok(ended) if ended || !line.is_empty() => {}
+                // The end of the input ends the loop, on a line of its own
+                _ => {
+                    writeln!(self.err).ok();
+                    return Ok(status);
+                }
+            }
+            let bytes: Vec<u8> = line.into_iter().map(|(byte, _)| byte).collect();
+            let reply = String::from_utf8_lossy(&bytes).into_owned();
+            self.set_var("REPLY", &reply)?;
+            let choice = match reply.trim().parse::<usize>() {
+                Ok(number) if (1..=values.len()).contains(&number) => number,
+                _ => {
+                    show_menu = true;
+                    continue;
+                }
+            };
+            self.set_var(name, &values[choice - 1])?;
+            status = self.execute_list(body);
+            if self.loop_finished() || is_interrupted(status) {
+                return Ok(status);
+            }
+        }
+    }
+
+    // Runs a loop, with break and continue inside it able to reach it
+    fn in_loop(
+        &mut self,
+        run: impl FnOnce(&mut Self) -> Result<i32, String>,
+    ) -> Result<i32, String> {
+        self.loop_depth += 1;
+        let status = run(self);
+        self.loop_depth -= 1;
+        status
+    }
+
+    // Deals with a break or continue from inside a loop once it has left the loop's
+    // body, returning true if the loop has to stop
+    // One that's for a loop further out stops this one and goes on to the next
+    fn loop_finished(&mut self) -> bool {
+        match self.loop_control.take() {
+            None | Some(LoopControl::Continue(1)) => false,
+            Some(LoopControl::Return) => {
+                self.loop_control = Some(LoopControl::Return);
+                true
+            }
+            Some(LoopControl::Break(1)) => true,
+            Some(LoopControl::Break(levels)) => {
+                self.loop_control = Some(LoopControl::Break(levels - 1));
+                true
+            }
+            Some(LoopControl::Continue(levels)) => {
+                self.loop_control = Some(LoopControl::Continue(levels - 1));
+                true
+            }
+        }
+    }
+
+    // Expands a single command, applies its redirections and executes it
+    fn execute_simple_command(&mut self, command: &SimpleCommand) -> i32 {
+        let status = self.run_simple_command(command).unwrap_or_else(|e| {
+            self.print_error("", &e);
+            1
+        });
+
+        // The command is done with any /dev/fd paths it was given
+        expand::finish_process_substitutions(self);
+        status
+    }
+
+    fn run_simple_command(&mut self, command: &SimpleCommand) -> Result<i32, String> {
+        // Leading NAME=value words are assignments rather than part of the command
+        // As in bash, their values are expanded after the command's words and redirections
+        let assignment_words: Vec<(&str, &str)> = command
+            .words
+            .iter()
+            .map_while(|word| parser::assignment(word))
+            .collect();
+        self.substitution_status = None;
+        let args = expand::expand_words(self, &command.words[assignment_words.len()..])?;
+        let saved_fds = redirect::apply_redirects(self, &command.redirects)?;
+        // `exec` without a command applies its redirections to the shell for good, as in
+        // `exec 3> log` to keep a file open on fd 3
+        if args.len() == 1 && args[0] == "exec" {
+            saved_fds.keep();
+            return Ok(0);
+        }
+
+        if args.is_empty() {
+            // With no command to run, the assignments set shell variables
+            for (name, value) in assignment_words {
+                self.assign(name, value)?;
+            }
+            return Ok(self.substitution_status.take().unwrap_or(0));
+        }
+        let mut assignments = Vec::new();
+        for (name, value) in assignment_words {
+            if !is_valid_name(name) || value.starts_with('(') {
+                return Err(format!(
+                    "{}: cannot assign an array to a command's environment",
+                    name
+                ));
+            }
+            assignments.push((name.to_string(), expand::expand_assignment(self, value)?));
+        }
+        if let Some((name, _)) = assignments
+            .iter()
+            .find(|(name, _)| self.variables.get(name).is_some_and(|var| var.readonly))
+        {
+            return Err(format!("{}: readonly variable", name));
+        }
+        let parts: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        Ok(self.execute_command(&parts, &assignments))
+    }
+
+    // Formats a path for display with $HOME shortened to ~, so /home/me/work is shown
+    // as ~/work; paths outside the home directory are shown in full
+    pub fn abbreviate_home(&self, path: &Path) -> String {
+        let text = path.to_string_lossy().into_owned();
+        let home = self.get_var("HOME").unwrap_or_default();
+        let home = home.trim_end_matches('/');
+        if home.is_empty() {
+            return text;
+        }
+        match text.strip_prefix(home) {
+            Some("") => "~".to_string(),
+            Some(rest) if rest.starts_with('/') => format!("~{}", rest),
+            _ => text,
+        }
+    }
+
+    // Looks up the value of a variable
+    // The special parameters $? (last status), $$ (shell PID) and $! (last background PID)
+    // are answered from the shell's own state, as are the positional parameters $0, $1
+    // and so on, with $# counting them and $@ and $* joining them, $LINENO, the line
+    // being run, $SECONDS, the whole seconds since the shell started, and $RANDOM,
+    // a new number from 0 to 32767 each time
+    pub fn get_var(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "LINENO" => Some(self.line_number.to_string()),
+            "SECONDS" => {
+                let elapsed = self.seconds_start.elapsed().as_secs() as i64;
+                Some((self.seconds_offset + elapsed).to_string())
+            }
+            "RANDOM" => Some(self.next_random().to_string()),
+            "$" => Some(process::id().to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "0" => Some(self.arg0.clone()),
+            "#" => Some(self.positional_params.len().to_string()),
+            "@" | "*" => Some(self.positional_params.join(" ")),
+            _ if name.chars().all(|c| c.is_ascii_digit()) => {
+                let index: usize = name.parse().ok()?;
+                self.positional_params.get(index.checked_sub(1)?).cloned()
+            }
+            _ => self.variables.get(name).map(|var| var.scalar().to_string()),
+        }
+    }
+
+    // Steps the $RANDOM generator, a linear congruential one like C's rand(), and
+    // returns the next number from 0 to 32767
+    fn next_random(&self) -> u32 {
+        let state = self
+            .random_state
+            .get()
+            .wrapping_mul(1_103_515_245)
+            .wrapping_add(12_345);
+        self.random_state.set(state);
+        (state >> 16) & 0x7fff
+    }
+
+    // Assigns a value to a variable, creating it if needed
+    // Fails for readonly variables; values for integer variables are evaluated as arithmetic
+    pub fn set_var(&mut self, name: &str, value: &str) -> Result<(), String> {
+        // A new PATH can find commands somewhere else, so where they were found is
+        // forgotten
+        if name == "PATH" {
+            self.command_hash.clear();
+        }
+        match self.variables.get(name) {
+            Some(var) if var.readonly => Err(format!("{}: readonly variable", name)),
+            // Assigning to SECONDS starts the count again from the number given
+            _ if name == "SECONDS" => {
+                self.seconds_start = Instant::now();
+                self.seconds_offset = value.trim().parse().unwrap_or(0);
+                Ok(())
+            }
+            // Assigning to RANDOM seeds it, so the numbers that follow can be repeated
+            _ if name == "RANDOM" => {
+                let seed: i64 = value.trim().parse().unwrap_or(0);
+                self.random_state.set(seed as u32);
+                Ok(())
+            }
+            Some(var) if var.integer => {
+                let number = arith::evaluate(self, value)?;
+                self.variables.set(name, &number.to_string());
+                Ok(())
+            }
+            _ => {
+                self.variables.set(name, value);
+                Ok(())
+            }
+        }
+    }
+
+    // Replaces a variable's value with an array, as in arr=(a b c), read -a and mapfile
+    pub fn set_array(
+        &mut self,
+        name: &str,
+        elements: BTreeMap<usize, String>,
+    ) -> Result<(), String> {
+        if self.variables.get(name).is_some_and(|var| var.readonly) {
+            return Err(format!("{}: readonly variable", name));
+        }
+        self.variables.set_array(name, elements);
+        Ok(())
+    }
+
+    // Sets one element of an array, as in arr[2]=value
+    pub fn set_element(&mut self, name: &str, index: usize, value: &str) -> Result<(), String> {
+        if self.variables.get(name).is_some_and(|var| var.readonly) {
+            return Err(format!("{}: readonly variable", name));
+        }
+        self.variables.set_element(name, index, value);
+        Ok(())
+    }
+
+    // Carries out an assignment word, expanding its value first
+    // Besides NAME=value this handles arr[i]=value, which sets one element, and
+    // arr=(a b c), which sets a whole array; in that list [i]=value puts a value at
+    // a given index and later values carry on from there
+    fn assign(&mut self, name: &str, value: &str) -> Result<(), String> {
+        if let Some((array, subscript)) = parser::subscript(name) {
+            let index = expand::evaluate_subscript(self, array, subscript)?;
+            let value = expand::expand_word(self, value)?;
+            return self.set_element(array, index, &value);
+        }
+        let Some(list) = value.strip_prefix('(').and_then(|v| v.strip_suffix(')')) else {
+            let value = expand::expand_assignment(self, value)?;
+            return self.set_var(name, &value);
+        };
+
+        let mut elements = BTreeMap::new();
+        let mut index = 0;
+        for word in parser::array_elements(list)? {
+            if let Some((subscript, value)) = word
+                .strip_prefix('[')
+                .and_then(|word| word.split_once("]="))
+            {
+                index = expand::evaluate_subscript(self, name, subscript)?;
+                elements.insert(index, expand::expand_word(self, value)?);
+                index += 1;
+                continue;
+            }
+            for value in expand::expand_words(self, &[word])? {
+                elements.insert(index, value);
+                index += 1;
+            }
+        }
+        self.set_array(name, elements)
+    }
+
+    // Finds the program a command name runs, remembering where it was found in PATH so
+    // it isn't searched for again next time, as the 'hash' builtin shows
+    // A path with a slash in it is never remembered, and a remembered program that's
+    // since gone is looked for again
+    pub fn find_command(&mut self, name: &str) -> Option<String> {
+        if name.contains('/') {
+            return find_executable_in_path(name);
+        }
+        if let Some((path, hits)) = self.command_hash.get_mut(name) {
+            if is_executable(Path::new(path)) {
+                *hits += 1;
+                return Some(path.clone());
+            }
+        }
+        let path = find_executable_in_path(name)?;
+        self.command_hash
+            .insert(name.to_string(), (path.clone(), 1));
+        Some(path)
+    }
+
+    // Replaces the shell with an external program, as the 'exec' builtin does
+    // Returns only if the program couldn't be found or started, with its exit status
+    pub fn exec_program(&mut self, args: &[&str]) -> i32 {
+        if self.find_command(args[0]).is_none() {
+            self.print_error(args[0], "not found");
+            return 127;
+        }
+        // The program gets the default signal handling, not what the shell uses for
+        // job control
+        self.leave_job_control();
+        self.execute_external_program(args[0], args, &[], true)
+    }
+
+    // Helper function to execute an external program
+    // Takes the program name, all arguments (including the program name as the first arg)
+    // and any variables to add to its environment
+    // With `replace_shell` set the program takes over the current process instead of
+    // running as a child, which forked copies of the shell use for their last command
+    // Returns the program's exit status, or 127 if it couldn't be found
+    fn execute_external_program(
+        &mut self,
+        program: &str,
+        args: &[&str],
+        env: &[(String, String)],
+        replace_shell: bool,
+    ) -> i32 {
+        // Try to find the executable in PATH
+        let Some(executable_path) = self.find_command(program) else {
+            if program.contains('/') {
+                // A path to a program that isn't there, or can't be run
+                return match fs::metadata(program) {
+                    Ok(_) => {
+                        self.print_error(program, "Permission denied");
+                        126
+                    }
+                    Err(e) => {
+                        self.print_error(program, &error_message(&e));
+                        127
+                    }
+                };
+            }
+            // Program not found in PATH, which a command_not_found_handle function can
+            // deal with instead, given the command as it was run
+            let handler = self.functions.get("command_not_found_handle").cloned();
+            let in_handler = self
+                .call_stack
+                .iter()
+                .any(|frame| frame.name == "command_not_found_handle");
+            if let Some(handler) = handler.filter(|_| !in_handler) {
+                let mut handler_args = vec!["command_not_found_handle"];
+                handler_args.extend_from_slice(args);
+                return self.call_function(&handler, &handler_args);
+            }
+            self.print_error(program, "command not found");
+            return 127;
+        };
+
+        // Execute the program with all arguments
+        let mut cmd = Command::new(&executable_path);
+
+        #[cfg(unix)]
+        {
+            // On Unix, use arg0 to set argv[0] to the name the program was run by, just
+            // as it was typed, since multi-call programs like busybox go by it
+            cmd.arg0(program);
+        }
+
+        // Add all remaining arguments (argv[1..])
+        for arg in &args[1..] {
+            cmd.arg(arg);
+        }
+        cmd.envs(env.iter().map(|(name, value)| (name, value)));
+
+        if replace_shell {
+            io::stdout().flush().ok();
+            // exec only returns if the program couldn't be started
+            let e = cmd.exec();
+            self.print_error(program, &error_message(&e));
+            return 126;
+        }
+
+        if self.job_control {
+            // The program gets a process group of its own, and stops on Ctrl-Z as usual
+            unsafe {
+                cmd.pre_exec(|| {
+                    let _ = sys::set_process_group(0, 0);
+                    sys::reset_job_control_signals();
+                    Ok(())
+                });
+            }
+        }
+
+        // Start the program and wait for it to complete (or be stopped)
+        match cmd.spawn() {
+            Ok(child) => {
+                let pid = child.id() as i32;
+                self.add_to_job(pid, pid);
+                let job = Job::new(&[pid], &self.running_text);
+                self.wait_for_foreground(job)
+            }
+            Err(e) => {
+                // Failed to execute the program
+                self.print_error(program, &error_message(&e));
+                126
+            }
+        }
+    }
+
+    // Returns the names of all builtin commands, in alphabetical order
+    pub fn builtin_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = self.builtins.keys().copied().collect();
+        names.sort_unstable();
+        names
+    }
+
+    // Reports an error through `err` as `shell: context: message`, where the context is
+    // usually the builtin or program the error is about; an empty context is left out
+    // Every error the shell reports goes through here
+    pub fn print_error(&mut self, context: &str, message: &str) {
+        if context.is_empty() {
+            writeln!(self.err, "{}: {}", PROGRAM_NAME, message).ok();
+        } else {
+            writeln!(self.err, "{}: {}: {}", PROGRAM_NAME, context, message).ok();
+        }
+    }
+
+    // Adds a builtin command, or replaces the one already called `name`
+    // This is how a program embedding the shell gives it commands of its own; see
+    // CommandHandler for what a handler gets and returns
+    pub fn register_builtin(&mut self, name: &'static str, handler: CommandHandler) {
+        self.builtins.insert(name, handler);
+    }
+
+    // Returns true if `name` is one of the shell's builtin commands
+    pub fn is_builtin(&self, name: &str) -> bool {
+        self.builtins.contains_key(name)
+    }
+
+    // Returns the handler of the builtin command `name`, if there is one
+    pub fn builtin_handler(&self, name: &str) -> Option<CommandHandler> {
+        self.builtins.get(name).copied()
+    }
+
+    // Executes a command (a function, builtin or external) and returns its exit status
+    // `assignments` only apply while the command runs, as in `FOO=bar cmd`
+    fn execute_command(&mut self, parts: &[&str], assignments: &[(String, String)]) -> i32 {
+        let in_place = std::mem::take(&mut self.exec_in_place);
+        // Functions come first, so one can stand in for a builtin or program of the
+        // same name
+        if let Some(function) = self.functions.get(parts[0]).cloned() {
+            return self
+                .with_assignments(assignments, |shell| shell.call_function(&function, parts));
+        }
+        if let Some(&handler) = self.builtins.get(parts[0]) {
+            // A status only has 8 bits, as a program's does, so one out of range from
+            // a builtin added by a program embedding the shell wraps around like one
+            return self
+                .with_assignments(assignments, |shell| handler(shell, parts).rem_euclid(256));
+        }
+        // A PATH given with the command, as in `PATH=/opt/bin:$PATH cmd`, is where the
+        // program is looked for, without using or changing the hashed locations that
+        // belong to the shell's own PATH
+        if assignments.iter().any(|(name, _)| name == "PATH") {
+            let hash = std::mem::take(&mut self.command_hash);
+            let status = self.with_assignments(assignments, |shell| {
+                shell.execute_external_program(parts[0], parts, assignments, in_place)
+            });
+            self.command_hash = hash;
+            return status;
+        }
+        // Not a function or builtin - try to execute as an external program
+        self.execute_external_program(parts[0], parts, assignments, in_place)
+    }
+
+    // Runs a function or builtin with `assignments` set for the duration of the call
+    // They're exported too, as they would be in an external program's environment
+    fn with_assignments(
+        &mut self,
+        assignments: &[(String, String)],
+        run: impl FnOnce(&mut Self) -> i32,
+    ) -> i32 {
+        let saved: Vec<_> = assignments
+            .iter()
+            .map(|(name, value)| {
+                let previous = self.variables.get(name).cloned();
+                self.variables.set(name, value);
+                self.variables.entry(name).exported = true;
+                self.variables.sync_export(name);
+                (name, previous)
+            })
+            .collect();
+        let status = run(self);
+        for (name, previous) in saved.into_iter().rev() {
+            self.variables.restore(name, previous);
+        }
+        status
+    }
+}
@@ -0,0 +1,853 @@
+// Minimal bindings to the C library for terminal and process control
+// The standard library doesn't expose these, so we declare the few functions we need directly
+use std::ffi::{c_char, CStr, CString};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::time::Duration;
+
+// Terminal attributes as laid out by the C library on Linux
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Termios {
+    c_iflag: u32,
+    c_oflag: u32,
+    c_cflag: u32,
+    c_lflag: u32,
+    c_line: u8,
+    c_cc: [u8; 32],
+    c_ispeed: u32,
+    c_ospeed: u32,
+}
+
+// Terminal attributes as laid out by the C library on macOS
+#[cfg(target_os = "macos")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Termios {
+    c_iflag: u64,
+    c_oflag: u64,
+    c_cflag: u64,
+    c_lflag: u64,
+    c_cc: [u8; 20],
+    c_ispeed: u64,
+    c_ospeed: u64,
+}
+
+#[cfg(target_os = "linux")]
+mod flags {
+    pub const BRKINT: u32 = 0o2;
+    pub const ICRNL: u32 = 0o400;
+    pub const INPCK: u32 = 0o20;
+    pub const ISTRIP: u32 = 0o40;
+    pub const IXON: u32 = 0o2000;
+    pub const ECHO: u32 = 0o10;
+    pub const ICANON: u32 = 0o2;
+    pub const IEXTEN: u32 = 0o100000;
+    pub const ISIG: u32 = 0o1;
+    pub const VMIN: usize = 6;
+    pub const VTIME: usize = 5;
+}
+
+#[cfg(target_os = "macos")]
+mod flags {
+    pub const BRKINT: u64 = 0x2;
+    pub const ICRNL: u64 = 0x100;
+    pub const INPCK: u64 = 0x10;
+    pub const ISTRIP: u64 = 0x20;
+    pub const IXON: u64 = 0x200;
+    pub const ECHO: u64 = 0x8;
+    pub const ICANON: u64 = 0x100;
+    pub const IEXTEN: u64 = 0x400;
+    pub const ISIG: u64 = 0x80;
+    pub const VMIN: usize = 16;
+    pub const VTIME: usize = 17;
+}
+
+// A time value from the C library, in seconds and microseconds
+#[repr(C)]
+#[derive(Default)]
+struct Timeval {
+    tv_sec: i64,
+    #[cfg(target_os = "linux")]
+    tv_usec: i64,
+    #[cfg(target_os = "macos")]
+    tv_usec: i32,
+}
+
+// Resource usage counters; only the CPU times are read, the rest just reserves space
+#[repr(C)]
+#[derive(Default)]
+struct Rusage {
+    ru_utime: Timeval,
+    ru_stime: Timeval,
+    ru_other: [i64; 14],
+}
+
+// Broken-down calendar time as laid out by the C library (the same on Linux and macOS)
+#[repr(C)]
+struct Tm {
+    tm_sec: i32,
+    tm_min: i32,
+    tm_hour: i32,
+    tm_mday: i32,
+    tm_mon: i32,
+    tm_year: i32,
+    tm_wday: i32,
+    tm_yday: i32,
+    tm_isdst: i32,
+    tm_gmtoff: i64,
+    tm_zone: *const u8,
+}
+
+// The current local date and time, as shown in prompts
+pub struct LocalTime {
+    // 1 to 12
+    pub month: u32,
+    pub day: u32,
+    // 0 is Sunday
+    pub weekday: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+// A compiled regular expression, regex_t, which is 64 bytes on Linux and 32 on macOS
+// Only the count of its groups is ever read, from the word at REGEX_GROUPS_INDEX
+#[repr(C)]
+struct Regex {
+    words: [usize; 8],
+}
+
+#[cfg(target_os = "linux")]
+const REGEX_GROUPS_INDEX: usize = 6;
+#[cfg(target_os = "macos")]
+const REGEX_GROUPS_INDEX: usize = 1;
+
+// Where a regular expression or one of its groups matched, as byte offsets, or -1
+// for a group that took no part in the match
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RegexMatch {
+    start: i32,
+    end: i32,
+}
+#[cfg(target_os = "macos")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RegexMatch {
+    start: i64,
+    end: i64,
+}
+
+// regcomp flag for extended regular expressions, as [[ =~ ]] uses
+const REG_EXTENDED: i32 = 1;
+
+// access() modes, for checking whether a file can be read, written or run
+pub const R_OK: i32 = 4;
+pub const W_OK: i32 = 2;
+pub const X_OK: i32 = 1;
+
+// An entry of the user database as laid out by the C library on Linux
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct Passwd {
+    pw_name: *const c_char,
+    pw_passwd: *const c_char,
+    pw_uid: u32,
+    pw_gid: u32,
+    pw_gecos: *const c_char,
+    pw_dir: *const c_char,
+    pw_shell: *const c_char,
+}
+
+// An entry of the user database as laid out by the C library on macOS
+#[cfg(target_os = "macos")]
+#[repr(C)]
+struct Passwd {
+    pw_name: *const c_char,
+    pw_passwd: *const c_char,
+    pw_uid: u32,
+    pw_gid: u32,
+    pw_change: i64,
+    pw_class: *const c_char,
+    pw_gecos: *const c_char,
+    pw_dir: *const c_char,
+    pw_shell: *const c_char,
+    pw_expire: i64,
+}
+
+// A terminal's window size, as read by the TIOCGWINSZ ioctl
+#[repr(C)]
+#[derive(Default)]
+struct Winsize {
+    ws_row: u16,
+    ws_col: u16,
+    ws_xpixel: u16,
+    ws_ypixel: u16,
+}
+
+// ioctl request that reads a terminal's window size
+#[cfg(target_os = "linux")]
+const TIOCGWINSZ: u64 = 0x5413;
+#[cfg(target_os = "macos")]
+const TIOCGWINSZ: u64 = 0x4008_7468;
+
+// getrusage targets: the calling process, and its children that have been waited for
+const RUSAGE_SELF: i32 = 0;
+const RUSAGE_CHILDREN: i32 = -1;
+
+// Apply attribute changes immediately
+const TCSANOW: i32 = 0;
+
+// waitpid option that returns straight away if the child hasn't changed state
+const WNOHANG: i32 = 1;
+// waitpid option that also reports children that have been stopped
+const WUNTRACED: i32 = 2;
+
+// Signals used for job control
+#[cfg(target_os = "linux")]
+mod signals {
+    pub const SIGCONT: i32 = 18;
+    pub const SIGTSTP: i32 = 20;
+}
+#[cfg(target_os = "macos")]
+mod signals {
+    pub const SIGCONT: i32 = 19;
+    pub const SIGTSTP: i32 = 18;
+}
+// Signal names without the SIG prefix, indexed by signal number
+#[cfg(target_os = "linux")]
+pub const SIGNAL_NAMES: [&str; 32] = [
+    "", "HUP", "INT", "QUIT", "ILL", "TRAP", "ABRT", "BUS", "FPE", "KILL", "USR1", "SEGV", "USR2",
+    "PIPE", "ALRM", "TERM", "STKFLT", "CHLD", "CONT", "STOP", "TSTP", "TTIN", "TTOU", "URG",
+    "XCPU", "XFSZ", "VTALRM", "PROF", "WINCH", "IO", "PWR", "SYS",
+];
+#[cfg(target_os = "macos")]
+pub const SIGNAL_NAMES: [&str; 32] = [
+    "", "HUP", "INT", "QUIT", "ILL", "TRAP", "ABRT", "EMT", "FPE", "KILL", "BUS", "SEGV", "SYS",
+    "PIPE", "ALRM", "TERM", "URG", "STOP", "TSTP", "CONT", "CHLD", "TTIN", "TTOU", "IO", "XCPU",
+    "XFSZ", "VTALRM", "PROF", "WINCH", "INFO", "USR1", "USR2",
+];
+
+// The hangup signal a shell sends its jobs when it exits
+const SIGHUP: i32 = 1;
+const SIGTTIN: i32 = 21;
+const SIGTTOU: i32 = 22;
+
+// Sent to a process writing to a pipe nobody reads from any more
+const SIGPIPE: i32 = 13;
+
+// Sent by Ctrl-C
+pub const SIGINT: i32 = 2;
+
+// Sent when the terminal window changes size
+const SIGWINCH: i32 = 28;
+
+// Signal dispositions for signal()
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+// errno for an invalid file descriptor
+const EBADF: i32 = 9;
+
+// A file descriptor to wait on with poll(), and the events to wait for
+#[repr(C)]
+struct Pollfd {
+    fd: i32,
+    events: i16,
+    revents: i16,
+}
+
+// poll() event for data being ready to read
+const POLLIN: i16 = 1;
+
+#[cfg(target_os = "linux")]
+type Nfds = u64;
+#[cfg(target_os = "macos")]
+type Nfds = u32;
+
+// fcntl command that duplicates a descriptor with close-on-exec set
+#[cfg(target_os = "linux")]
+const F_DUPFD_CLOEXEC: i32 = 1030;
+#[cfg(target_os = "macos")]
+const F_DUPFD_CLOEXEC: i32 = 67;
+// fcntl command that sets a descriptor's flags, of which there is just close-on-exec
+const F_SETFD: i32 = 2;
+
+extern "C" {
+    fn isatty(fd: i32) -> i32;
+    fn tcgetattr(fd: i32, termios: *mut Termios) -> i32;
+    fn tcsetattr(fd: i32, optional_actions: i32, termios: *const Termios) -> i32;
+    fn fork() -> i32;
+    fn pipe(fds: *mut i32) -> i32;
+    fn dup2(old_fd: i32, new_fd: i32) -> i32;
+    fn close(fd: i32) -> i32;
+    fn read(fd: i32, buf: *mut u8, count: usize) -> isize;
+    fn poll(fds: *mut Pollfd, nfds: Nfds, timeout: i32) -> i32;
+    fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    fn ioctl(fd: i32, request: u64, ...) -> i32;
+    fn waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+    fn getrusage(who: i32, usage: *mut Rusage) -> i32;
+    fn geteuid() -> u32;
+    fn getegid() -> u32;
+    fn gethostname(name: *mut u8, len: usize) -> i32;
+    fn time(t: *mut i64) -> i64;
+    fn localtime_r(t: *const i64, result: *mut Tm) -> *mut Tm;
+    fn strftime(s: *mut u8, max: usize, format: *const c_char, tm: *const Tm) -> usize;
+    fn getpwnam(name: *const c_char) -> *const Passwd;
+    fn signal(signum: i32, handler: usize) -> usize;
+    fn kill(pid: i32, sig: i32) -> i32;
+    fn setpgid(pid: i32, pgid: i32) -> i32;
+    fn getpgrp() -> i32;
+    fn tcsetpgrp(fd: i32, pgrp: i32) -> i32;
+    fn regcomp(regex: *mut Regex, pattern: *const c_char, flags: i32) -> i32;
+    fn regexec(
+        regex: *const Regex,
+        text: *const c_char,
+        count: usize,
+        matches: *mut RegexMatch,
+        flags: i32,
+    ) -> i32;
+    fn regfree(regex: *mut Regex);
+    fn access(path: *const c_char, mode: i32) -> i32;
+}
+
+impl Termios {
+    // Switch these attributes to raw mode: no echo, no line buffering, no signal keys
+    // Output processing is left alone so "\n" still moves to the start of the next line
+    pub fn make_raw(&mut self) {
+        use flags::*;
+        self.c_iflag &= !(BRKINT | ICRNL | INPCK | ISTRIP | IXON);
+        self.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
+        // Return from read() as soon as a single byte is available
+        self.c_cc[VMIN] = 1;
+        self.c_cc[VTIME] = 0;
+    }
+
+    // Switch these attributes to a mode for reading secrets: no echo, and the line
+    // editing and signal keys left for the reader to handle, so that Ctrl-C can't kill
+    // the shell before the echo is turned back on
+    pub fn make_silent(&mut self) {
+        use flags::*;
+        self.c_lflag &= !(ECHO | ICANON | ISIG);
+        self.c_cc[VMIN] = 1;
+        self.c_cc[VTIME] = 0;
+    }
+}
+
+// Matches `text` against the extended regular expression `pattern`
+// Returns None if it doesn't match, or else the byte range of the match followed by
+// that of each group, None for a group that took no part in the match
+pub fn match_regex(
+    pattern: &str,
+    text: &str,
+) -> Result<Option<Vec<Option<std::ops::Range<usize>>>>, String> {
+    let invalid = || format!("{}: invalid regular expression", pattern);
+    let pattern = CString::new(pattern).map_err(|_| invalid())?;
+    let Ok(text) = CString::new(text) else {
+        return Ok(None);
+    };
+    let mut regex = Regex { words: [0; 8] };
+    if unsafe { regcomp(&mut regex, pattern.as_ptr(), REG_EXTENDED) } != 0 {
+        return Err(invalid());
+    }
+    let mut matches = vec![RegexMatch::default(); regex.words[REGEX_GROUPS_INDEX] + 1];
+    let result = unsafe {
+        regexec(
+            &regex,
+            text.as_ptr(),
+            matches.len(),
+            matches.as_mut_ptr(),
+            0,
+        )
+    };
+    unsafe { regfree(&mut regex) };
+    if result != 0 {
+        return Ok(None);
+    }
+    let ranges = matches
+        .iter()
+        .map(|found| (found.start >= 0).then_some(found.start as usize..found.end as usize))
+        .collect();
+    Ok(Some(ranges))
+}
+
+// Returns true if the calling user may access `path` in the way `mode` asks, a mix of
+// R_OK, W_OK and X_OK
+pub fn can_access(path: &str, mode: i32) -> bool {
+    let Ok(path) = CString::new(path) else {
+        return false;
+    };
+    unsafe { access(path.as_ptr(), mode) == 0 }
+}
+
+// Returns true if the file descriptor refers to a terminal
+pub fn is_terminal(fd: i32) -> bool {
+    unsafe { isatty(fd) == 1 }
+}
+
+// Returns the width in columns of the terminal on a file descriptor, or None if it
+// isn't a terminal or doesn't know its size
+pub fn terminal_columns(fd: i32) -> Option<usize> {
+    let mut size = Winsize::default();
+    if unsafe { ioctl(fd, TIOCGWINSZ, &mut size as *mut Winsize) } != 0 || size.ws_col == 0 {
+        return None;
+    }
+    Some(size.ws_col as usize)
+}
+
+// Set by the SIGWINCH handler, and cleared once the new size has been noticed
+static WINDOW_RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn note_window_resize(_signum: i32) {
+    WINDOW_RESIZED.store(true, Ordering::Relaxed);
+}
+
+// One bit for each signal with a trap that has arrived and not been handled yet
+static PENDING_SIGNALS: AtomicU32 = AtomicU32::new(0);
+
+// How each signal was handled before a trap was first set on it, to go back to when
+// the trap is removed; usize::MAX until then
+static ORIGINAL_HANDLERS: [AtomicUsize; 32] = [const { AtomicUsize::new(usize::MAX) }; 32];
+
+extern "C" fn note_signal(signum: i32) {
+    PENDING_SIGNALS.fetch_or(1 << signum, Ordering::Relaxed);
+    // The line editor still needs to hear about resizes while WINCH is trapped
+    if signum == SIGWINCH {
+        WINDOW_RESIZED.store(true, Ordering::Relaxed);
+    }
+}
+
+// What the trap builtin has a signal do
+pub enum SignalAction {
+    // Note it for the shell to run the trap's command
+    Catch,
+    Ignore,
+    // Go back to how it was handled before any trap was set
+    Restore,
+}
+
+// Changes how `signum`, from 1 to 31, is handled for a trap
+pub fn set_signal_action(signum: i32, action: SignalAction) {
+    let original = &ORIGINAL_HANDLERS[signum as usize];
+    let handler = match action {
+        SignalAction::Catch => note_signal as extern "C" fn(i32) as usize,
+        SignalAction::Ignore => SIG_IGN,
+        SignalAction::Restore => match original.load(Ordering::Relaxed) {
+            usize::MAX => return,
+            handler => handler,
+        },
+    };
+    let previous = unsafe { signal(signum, handler) };
+    if original.load(Ordering::Relaxed) == usize::MAX {
+        original.store(previous, Ordering::Relaxed);
+    }
+}
+
+// Returns the trapped signals that have arrived since the last call, lowest first
+pub fn take_pending_signals() -> Vec<i32> {
+    let pending = PENDING_SIGNALS.swap(0, Ordering::Relaxed);
+    (1..32)
+        .filter(|signum| pending & (1 << signum) != 0)
+        .collect()
+}
+
+// Starts watching for the terminal window being resized, for window_resized to report
+pub fn watch_window_size() {
+    unsafe {
+        signal(SIGWINCH, note_window_resize as extern "C" fn(i32) as usize);
+    }
+}
+
+// Returns true if the terminal window has been resized since the last call
+pub fn window_resized() -> bool {
+    WINDOW_RESIZED.swap(false, Ordering::Relaxed)
+}
+
+// Set by the SIGINT handler while interrupts are being caught
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn note_interrupt(_signum: i32) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// Catches SIGINT rather than letting it end the shell, while a builtin that waits, like
+// sleep, checks `interrupted` to stop early; the old handling comes back on drop
+pub struct InterruptCatcher {
+    previous: usize,
+}
+
+pub fn catch_interrupts() -> InterruptCatcher {
+    INTERRUPTED.store(false, Ordering::Relaxed);
+    let previous = unsafe { signal(SIGINT, note_interrupt as extern "C" fn(i32) as usize) };
+    InterruptCatcher { previous }
+}
+
+impl InterruptCatcher {
+    // Returns true if Ctrl-C has been pressed since interrupts started being caught
+    pub fn interrupted(&self) -> bool {
+        INTERRUPTED.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for InterruptCatcher {
+    fn drop(&mut self) {
+        unsafe {
+            signal(SIGINT, self.previous);
+        }
+    }
+}
+
+// Returns true if the shell is running as the superuser
+pub fn is_root() -> bool {
+    effective_user() == 0
+}
+
+// The user and group IDs the shell runs as, which decide what it may do with files
+pub fn effective_user() -> u32 {
+    unsafe { geteuid() }
+}
+
+pub fn effective_group() -> u32 {
+    unsafe { getegid() }
+}
+
+// Returns the machine's host name, or an empty string if it can't be read
+pub fn host_name() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { gethostname(buffer.as_mut_ptr(), buffer.len()) } != 0 {
+        return String::new();
+    }
+    let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+// Reads the current terminal attributes of a file descriptor
+pub fn get_termios(fd: i32) -> io::Result<Termios> {
+    let mut termios = Termios::default();
+    if unsafe { tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(termios)
+}
+
+// Applies terminal attributes to a file descriptor
+pub fn set_termios(fd: i32, termios: &Termios) -> io::Result<()> {
+    if unsafe { tcsetattr(fd, TCSANOW, termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The result of forking: which side of the fork we ended up on
+pub enum Fork {
+    Parent(i32),
+    Child,
+}
+
+// Forks the current process
+// The shell is single-threaded, so the child can safely keep running Rust code
+pub fn fork_process() -> io::Result<Fork> {
+    match unsafe { fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(Fork::Child),
+        pid => Ok(Fork::Parent(pid)),
+    }
+}
+
+// Creates a pipe and returns its (read, write) file descriptors
+// Unlike the pipes std creates, these are inherited by spawned programs
+pub fn create_pipe() -> io::Result<(i32, i32)> {
+    let mut fds = [0i32; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((fds[0], fds[1]))
+}
+
+// Makes `new_fd` refer to the same open file as `old_fd`
+pub fn duplicate_fd(old_fd: i32, new_fd: i32) -> io::Result<()> {
+    if unsafe { dup2(old_fd, new_fd) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Clears close-on-exec on `fd`, so that programs the shell starts inherit it
+pub fn inherit_fd(fd: i32) -> io::Result<()> {
+    if unsafe { fcntl(fd, F_SETFD, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Makes a close-on-exec copy of `fd` numbered 10 or above, out of the way of redirections
+// Returns None if `fd` isn't open
+pub fn save_fd(fd: i32) -> io::Result<Option<i32>> {
+    match unsafe { fcntl(fd, F_DUPFD_CLOEXEC, 10) } {
+        -1 => {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(EBADF) {
+                Ok(None)
+            } else {
+                Err(error)
+            }
+        }
+        copy => Ok(Some(copy)),
+    }
+}
+
+// Closes a file descriptor, ignoring errors since there's nothing useful to do about them
+pub fn close_fd(fd: i32) {
+    unsafe {
+        close(fd);
+    }
+}
+
+// Reads straight from a file descriptor, bypassing any buffering, so builtins reading
+// stdin take no more input than they use and see the effect of redirections
+// Returns the number of bytes read, 0 at end of input
+pub fn read_fd(fd: i32, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match unsafe { read(fd, buf.as_mut_ptr(), buf.len()) } {
+            -1 => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+            count => return Ok(count as usize),
+        }
+    }
+}
+
+// Waits up to `timeout` for a file descriptor to have input, or reach the end of it
+// Returns false if the time ran out first
+pub fn wait_readable(fd: i32, timeout: Duration) -> io::Result<bool> {
+    let mut entry = Pollfd {
+        fd,
+        events: POLLIN,
+        revents: 0,
+    };
+    // Rounded up, so input isn't given up on a moment early
+    let millis = timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32;
+    match unsafe { poll(&mut entry, 1, millis) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+// Waits for a child process to finish and returns its raw wait status
+pub fn wait_for(pid: i32) -> io::Result<i32> {
+    let mut status = 0;
+    if unsafe { waitpid(pid, &mut status, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(status)
+}
+
+// Waits for a child process to finish or be stopped and returns its raw wait status
+pub fn wait_for_change(pid: i32) -> io::Result<i32> {
+    let mut status = 0;
+    if unsafe { waitpid(pid, &mut status, WUNTRACED) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(status)
+}
+
+// Checks whether a child process has finished or been stopped, without blocking
+// Returns its raw wait status if it has, or None if it's still running
+pub fn try_wait(pid: i32) -> io::Result<Option<i32>> {
+    let mut status = 0;
+    match unsafe { waitpid(pid, &mut status, WNOHANG | WUNTRACED) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(None),
+        _ => Ok(Some(status)),
+    }
+}
+
+// Converts a raw wait status into a shell exit status
+// A process killed by a signal reports 128 + the signal number, as in shells
+pub fn exit_code(wait_status: i32) -> i32 {
+    let signal = wait_status & 0x7f;
+    if signal == 0 {
+        (wait_status >> 8) & 0xff
+    } else {
+        128 + signal
+    }
+}
+
+// Looks up a user's home directory in the user database
+pub fn home_dir_of(user: &str) -> Option<String> {
+    let name = CString::new(user).ok()?;
+    unsafe {
+        let entry = getpwnam(name.as_ptr());
+        if entry.is_null() || (*entry).pw_dir.is_null() {
+            return None;
+        }
+        Some(
+            CStr::from_ptr((*entry).pw_dir)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+// Returns the current time in the local time zone
+pub fn local_time() -> LocalTime {
+    let tm = local_tm(unsafe { time(std::ptr::null_mut()) });
+    LocalTime {
+        month: tm.tm_mon as u32 + 1,
+        day: tm.tm_mday as u32,
+        weekday: tm.tm_wday as u32,
+        hour: tm.tm_hour as u32,
+        minute: tm.tm_min as u32,
+        second: tm.tm_sec as u32,
+    }
+}
+
+// Formats a time given in seconds since 1970 in the local time zone with a strftime
+// format, like "%F %T " for "2024-05-01 14:03:59 "
+pub fn format_local_time(seconds: i64, format: &str) -> String {
+    let Ok(format) = CString::new(format) else {
+        return String::new();
+    };
+    let tm = local_tm(seconds);
+    // strftime gives 0 both for output that doesn't fit and for empty output, so the
+    // buffer grows a few times before the output is taken to be empty
+    let mut size = 256;
+    while size <= 16384 {
+        let mut buffer = vec![0u8; size];
+        let length = unsafe { strftime(buffer.as_mut_ptr(), size, format.as_ptr(), &tm) };
+        if length > 0 {
+            return String::from_utf8_lossy(&buffer[..length]).into_owned();
+        }
+        size *= 4;
+    }
+    String::new()
+}
+
+// Breaks a time given in seconds since 1970 down into the local date and time
+fn local_tm(seconds: i64) -> Tm {
+    let mut tm = Tm {
+        tm_sec: 0,
+        tm_min: 0,
+        tm_hour: 0,
+        tm_mday: 1,
+        tm_mon: 0,
+        tm_year: 70,
+        tm_wday: 4,
+        tm_yday: 0,
+        tm_isdst: 0,
+        tm_gmtoff: 0,
+        tm_zone: std::ptr::null(),
+    };
+    unsafe {
+        localtime_r(&seconds, &mut tm);
+    }
+    tm
+}
+
+// Returns the signal that killed a process, given its raw wait status, or None if it exited
+pub fn termination_signal(wait_status: i32) -> Option<i32> {
+    match wait_status & 0x7f {
+        0 | 0x7f => None,
+        signal => Some(signal),
+    }
+}
+
+// Returns the signal that stopped a process, given its raw wait status, or None if it
+// wasn't stopped
+pub fn stop_signal(wait_status: i32) -> Option<i32> {
+    (wait_status & 0xff == 0x7f).then_some((wait_status >> 8) & 0xff)
+}
+
+// Puts back the default handling of SIGPIPE, which the Rust runtime ignores, so that
+// writing to a closed pipe ends the process quietly, as it does other programs, instead
+// of failing with an error
+pub fn reset_broken_pipe_signal() {
+    unsafe {
+        signal(SIGPIPE, SIG_DFL);
+    }
+}
+
+// Makes the shell ignore the signals that would stop it, so that only the job in the
+// foreground is stopped by Ctrl-Z, and the shell can hand the terminal back and forth
+pub fn ignore_job_control_signals() {
+    set_job_control_signals(SIG_IGN);
+}
+
+// Restores the default handling of the job control signals, for processes started by
+// the shell, which would otherwise inherit the ignored dispositions
+pub fn reset_job_control_signals() {
+    set_job_control_signals(SIG_DFL);
+}
+
+fn set_job_control_signals(handler: usize) {
+    for signal_number in [signals::SIGTSTP, SIGTTIN, SIGTTOU] {
+        unsafe {
+            signal(signal_number, handler);
+        }
+    }
+}
+
+// Moves process `pid` (0 for the calling process) into process group `pgid`
+// (0 to start a new group led by the process)
+pub fn set_process_group(pid: i32, pgid: i32) -> io::Result<()> {
+    if unsafe { setpgid(pid, pgid) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Returns the process group of the calling process
+pub fn process_group() -> i32 {
+    unsafe { getpgrp() }
+}
+
+// Makes `pgid` the foreground process group of the terminal on stdin, the one that
+// gets the signals from keys like Ctrl-C and Ctrl-Z and may read from the terminal
+pub fn set_foreground_group(pgid: i32) -> io::Result<()> {
+    if unsafe { tcsetpgrp(0, pgid) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Lets a stopped process group carry on
+pub fn continue_group(pgid: i32) -> io::Result<()> {
+    if unsafe { kill(-pgid, signals::SIGCONT) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Sends a process group the hangup signal, and lets it carry on if it's stopped so
+// that it sees the signal
+pub fn hang_up_group(pgid: i32) -> io::Result<()> {
+    if unsafe { kill(-pgid, SIGHUP) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    continue_group(pgid)
+}
+
+// Returns the (user, system) CPU time used so far by the shell plus all of its reaped children
+pub fn cpu_times() -> (Duration, Duration) {
+    let mut user = Duration::ZERO;
+    let mut system = Duration::ZERO;
+    for who in [RUSAGE_SELF, RUSAGE_CHILDREN] {
+        let mut usage = Rusage::default();
+        if unsafe { getrusage(who, &mut usage) } == 0 {
+            user += timeval_duration(&usage.ru_utime);
+            system += timeval_duration(&usage.ru_stime);
+        }
+    }
+    (user, system)
+}
+
+fn timeval_duration(time: &Timeval) -> Duration {
+    Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+}
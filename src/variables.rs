@@ -0,0 +1,194 @@
+// Shell variables and the environment they are exported to
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+
+// Returns true if `name` can be used as a variable name
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+// The value of a variable: a plain string, or an indexed array of them
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(String),
+    // Arrays can have gaps, so elements are kept by index
+    Array(BTreeMap<usize, String>),
+}
+
+impl Value {
+    // Turns a plain value into an array with it as element 0 (an empty value becomes
+    // an empty array), and returns the elements
+    pub fn make_array(&mut self) -> &mut BTreeMap<usize, String> {
+        if let Value::Scalar(scalar) = self {
+            let mut elements = BTreeMap::new();
+            if !scalar.is_empty() {
+                elements.insert(0, std::mem::take(scalar));
+            }
+            *self = Value::Array(elements);
+        }
+        match self {
+            Value::Array(elements) => elements,
+            Value::Scalar(_) => unreachable!(),
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Scalar(String::new())
+    }
+}
+
+// A single shell variable
+#[derive(Debug, Clone, Default)]
+pub struct Variable {
+    pub value: Value,
+    // Exported variables are copied into the environment of every program the shell runs
+    pub exported: bool,
+    // Readonly variables can't be assigned to or unset
+    pub readonly: bool,
+    // Values assigned to integer variables are evaluated as arithmetic first
+    pub integer: bool,
+}
+
+impl Variable {
+    // The variable's value as a string; for an array that's element 0, as in bash
+    pub fn scalar(&self) -> &str {
+        match &self.value {
+            Value::Scalar(value) => value,
+            Value::Array(elements) => elements.get(&0).map_or("", String::as_str),
+        }
+    }
+
+    // Returns the element at `index` of an array; a plain variable acts as a
+    // one-element array
+    pub fn element(&self, index: usize) -> Option<&str> {
+        match &self.value {
+            Value::Scalar(value) => (index == 0).then_some(value.as_str()),
+            Value::Array(elements) => elements.get(&index).map(String::as_str),
+        }
+    }
+
+    // Every element in index order; a plain variable is a single element
+    pub fn elements(&self) -> Vec<&str> {
+        match &self.value {
+            Value::Scalar(value) => vec![value],
+            Value::Array(elements) => elements.values().map(String::as_str).collect(),
+        }
+    }
+
+    // The highest index in use, which negative subscripts count back from
+    pub fn last_index(&self) -> Option<usize> {
+        match &self.value {
+            Value::Scalar(_) => Some(0),
+            Value::Array(elements) => elements.keys().next_back().copied(),
+        }
+    }
+}
+
+// All the variables of a shell session
+// The values of exported variables are mirrored into the process environment,
+// so programs started by the shell (and forked copies of it) inherit them
+#[derive(Debug, Default)]
+pub struct Variables {
+    vars: HashMap<String, Variable>,
+}
+
+impl Variables {
+    // Starts with every variable of the environment the shell was started with, exported
+    pub fn from_environment() -> Self {
+        let vars = env::vars()
+            .map(|(name, value)| {
+                let variable = Variable {
+                    value: Value::Scalar(value),
+                    exported: true,
+                    ..Variable::default()
+                };
+                (name, variable)
+            })
+            .collect();
+        Variables { vars }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Variable> {
+        self.vars.get(name)
+    }
+
+    // Returns every variable, sorted by name
+    pub fn sorted(&self) -> Vec<(&str, &Variable)> {
+        let mut vars: Vec<_> = self
+            .vars
+            .iter()
+            .map(|(name, variable)| (name.as_str(), variable))
+            .collect();
+        vars.sort_by_key(|&(name, _)| name);
+        vars
+    }
+
+    // Returns a variable for changing its attributes, creating it empty if needed
+    // The environment is brought up to date by `sync_export` afterwards
+    pub fn entry(&mut self, name: &str) -> &mut Variable {
+        self.vars.entry(name.to_string()).or_default()
+    }
+
+    // Adds or removes a variable from the environment to match its exported attribute
+    // Arrays can't be passed in the environment, so they are never exported
+    pub fn sync_export(&self, name: &str) {
+        match self.vars.get(name) {
+            Some(Variable {
+                value: Value::Scalar(value),
+                exported: true,
+                ..
+            }) => env::set_var(name, value),
+            _ => env::remove_var(name),
+        }
+    }
+
+    // Sets a variable's value, keeping its attributes if it already exists
+    // Assigning to an array without a subscript sets element 0
+    pub fn set(&mut self, name: &str, value: &str) {
+        let variable = self.vars.entry(name.to_string()).or_default();
+        match &mut variable.value {
+            Value::Array(elements) => {
+                elements.insert(0, value.to_string());
+            }
+            scalar => *scalar = Value::Scalar(value.to_string()),
+        }
+        self.sync_export(name);
+    }
+
+    // Replaces a variable's value with an array of `elements`
+    pub fn set_array(&mut self, name: &str, elements: BTreeMap<usize, String>) {
+        let variable = self.vars.entry(name.to_string()).or_default();
+        variable.value = Value::Array(elements);
+        self.sync_export(name);
+    }
+
+    // Sets one element of an array, turning a plain variable into an array first
+    pub fn set_element(&mut self, name: &str, index: usize, value: &str) {
+        let variable = self.vars.entry(name.to_string()).or_default();
+        variable.value.make_array().insert(index, value.to_string());
+        self.sync_export(name);
+    }
+
+    // Removes a variable, and takes it out of the environment if it was exported
+    pub fn unset(&mut self, name: &str) -> Option<Variable> {
+        let variable = self.vars.remove(name)?;
+        if variable.exported {
+            env::remove_var(name);
+        }
+        Some(variable)
+    }
+
+    // Puts back a variable exactly as it was, or removes it if it didn't exist
+    // Used to undo temporary assignments like `FOO=bar cmd` once the command is done
+    pub fn restore(&mut self, name: &str, previous: Option<Variable>) {
+        self.unset(name);
+        if let Some(variable) = previous {
+            self.vars.insert(name.to_string(), variable);
+            self.sync_export(name);
+        }
+    }
+}
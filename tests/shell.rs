@@ -0,0 +1,237 @@
+// Runs command lines through the shell library, checking what they write
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+
+use codecrafters_shell::shell::Shell;
+
+// A writer that keeps everything written to it for the test to look at afterwards
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Runs each line in turn in a fresh shell, returning what it wrote to its output and
+// to its error output
+fn run(lines: &[&str]) -> (String, String) {
+    let (out, err) = (Captured::default(), Captured::default());
+    let mut shell = Shell::new();
+    shell.out.replace(out.clone());
+    shell.err.replace(err.clone());
+    for line in lines {
+        shell.run_line(line);
+    }
+    (out.text(), err.text())
+}
+
+// A fresh, empty directory for one test's files
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("shell-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn crlf_line_endings_leave_arguments_clean() {
+    let (out, err) = run(&["set -- a b\r\n", "echo \"<$2>\" $#\r\n"]);
+    assert_eq!(out, "<b> 2\n");
+    assert_eq!(err, "");
+}
+
+#[test]
+fn exit_status_is_seen_by_arithmetic_and_conditions() {
+    let (out, _) = run(&[
+        "false; echo $(( $? ))",
+        "false; echo $(( $? + 1 ))",
+        "false; let 'n = $? * 3'; echo $n",
+        "false; [[ $? -eq 1 ]] && echo failed",
+    ]);
+    assert_eq!(out, "1\n2\n3\nfailed\n");
+}
+
+#[test]
+fn seconds_advance_while_the_shell_runs() {
+    let (out, _) = run(&["echo $SECONDS", "sleep 1.1", "echo $SECONDS"]);
+    let seconds: Vec<u64> = out.lines().map(|line| line.parse().unwrap()).collect();
+    assert!(seconds[1] > seconds[0], "{:?}", seconds);
+
+    // Assigning sets the count it carries on from
+    let (out, _) = run(&["SECONDS=100", "echo $SECONDS"]);
+    assert_eq!(out, "100\n");
+}
+
+#[test]
+fn unquoted_here_doc_delimiter_expands_the_body() {
+    let (out, _) = run(&[
+        "X=value",
+        "while read -r line; do echo \"[$line]\"; done <<EOF\n$X \\$X $(echo sub) a\\\\b\nEOF",
+    ]);
+    assert_eq!(out, "[value $X sub a\\b]\n");
+}
+
+#[test]
+fn quoted_here_doc_delimiter_keeps_the_body_as_written() {
+    let (out, _) = run(&[
+        "X=value",
+        "while read -r line; do echo \"[$line]\"; done <<'EOF'\n$X $(echo sub) a\\\\b\nEOF",
+        "while read -r line; do echo \"[$line]\"; done <<\"EOF\"\n$X\nEOF",
+    ]);
+    assert_eq!(out, "[$X $(echo sub) a\\\\b]\n[$X]\n");
+}
+
+#[test]
+fn echo_flags_combine_and_stop_at_the_first_other_word() {
+    let (out, _) = run(&[
+        "echo -ne 'a\\tb'",
+        "echo",
+        "echo hi -n",
+        "echo -x",
+        "echo -n -e 'c\\n'",
+    ]);
+    assert_eq!(out, "a\tb\nhi -n\n-x\nc\n");
+}
+
+// cat prints /proc/self/cmdline, its own argument list, so a link to it shows the
+// argv[0] it was given
+#[cfg(target_os = "linux")]
+#[test]
+fn programs_get_the_name_they_were_run_by_as_argv0() {
+    let dir = temp_dir("arg0");
+    symlink("/bin/cat", dir.join("show-args")).unwrap();
+    let dir_text = dir.display().to_string();
+
+    let outer_dir = env::current_dir().unwrap();
+    let (out, err) = run(&[
+        &format!("cd {}", dir_text),
+        "./show-args /proc/self/cmdline > output",
+        &format!("{}/show-args /proc/self/cmdline >> output", dir_text),
+        &format!(
+            "PATH={}:$PATH show-args /proc/self/cmdline >> output",
+            dir_text
+        ),
+    ]);
+    env::set_current_dir(outer_dir).unwrap();
+
+    let output = fs::read_to_string(dir.join("output")).unwrap();
+    let argv0s: Vec<&str> = output
+        .split_terminator('\0')
+        .filter(|arg| *arg != "/proc/self/cmdline")
+        .collect();
+    assert_eq!(
+        argv0s,
+        [
+            "./show-args".to_string(),
+            format!("{}/show-args", dir_text),
+            "show-args".to_string(),
+        ]
+    );
+    assert_eq!((out.as_str(), err.as_str()), ("", ""));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn redirected_and_piped_output_bypasses_swapped_in_writers() {
+    let dir = temp_dir("redirect");
+    let file = |name: &str| dir.join(name).display().to_string();
+    let (out, err) = run(&[
+        &format!("echo hi > {}", file("redirected")),
+        &format!("{{ echo grouped; }} > {}", file("grouped")),
+        &format!("echo piped | cat > {}", file("piped")),
+        &format!(
+            "echo -n missing 2> {} >&2; cd /nonexistent 2>> {}",
+            file("errors"),
+            file("errors")
+        ),
+        "echo captured",
+        "cd /nonexistent",
+    ]);
+    assert_eq!(fs::read_to_string(file("redirected")).unwrap(), "hi\n");
+    assert_eq!(fs::read_to_string(file("grouped")).unwrap(), "grouped\n");
+    assert_eq!(fs::read_to_string(file("piped")).unwrap(), "piped\n");
+    assert_eq!(
+        fs::read_to_string(file("errors")).unwrap(),
+        "missingshell: cd: /nonexistent: No such file or directory\n"
+    );
+    assert_eq!(out, "captured\n");
+    assert_eq!(err, "shell: cd: /nonexistent: No such file or directory\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn shell_errors_and_timings_go_through_the_error_writer() {
+    let (out, err) = run(&["no-such-command-here", "echo (", "time :"]);
+    assert_eq!(out, "");
+    let lines: Vec<&str> = err.lines().collect();
+    assert_eq!(lines[0], "shell: no-such-command-here: command not found");
+    assert!(lines[1].starts_with("shell: "), "{:?}", lines);
+    assert_eq!(lines[2], "");
+    assert!(lines[3].starts_with("real\t0m0."), "{:?}", lines);
+}
+
+#[test]
+fn path_given_with_a_command_bypasses_the_hash() {
+    let dir = temp_dir("hash");
+    for (name, text) in [("d1", "one"), ("d2", "two")] {
+        let script = dir.join(name).join("foo");
+        fs::create_dir(dir.join(name)).unwrap();
+        fs::write(&script, format!("#!/bin/sh\necho {}\n", text)).unwrap();
+        fs::set_permissions(&script, Permissions::from_mode(0o755)).unwrap();
+    }
+    let (d1, d2) = (dir.join("d1"), dir.join("d2"));
+    let (out, err) = run(&[
+        &format!("PATH={}:$PATH hash foo", d1.display()),
+        &format!("echo \"$(PATH={}:$PATH foo)\"", d2.display()),
+        "hash",
+    ]);
+    assert_eq!(
+        out,
+        format!("two\nhits\tcommand\n   0\t{}/foo\n", d1.display())
+    );
+    assert_eq!(err, "");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn read_timing_out_leaves_the_variable_empty() {
+    let (out, _) = run(&[
+        "echo \"$({ printf part; sleep 1; } | { v=old; read -t 0.3 v; echo \"[$v] $?\"; })\"",
+    ]);
+    assert_eq!(out, "[] 142\n");
+}
+
+#[test]
+fn fds_opened_with_exec_reach_child_processes_until_closed() {
+    let dir = temp_dir("fd3");
+    let file = |name: &str| dir.join(name).display().to_string();
+    // The fds belong to the whole test process, so the shell opens fd 3 in a subshell
+    let (_, err) = run(&[&format!(
+        "(exec 3> {}; sh -c 'echo hi >&3'; exec 3>&-; sh -c 'echo gone >&3' 2> /dev/null; echo $? > {})",
+        file("fd3"),
+        file("status")
+    )]);
+    assert_eq!(fs::read_to_string(file("fd3")).unwrap(), "hi\n");
+    assert_ne!(fs::read_to_string(file("status")).unwrap(), "0\n");
+    assert_eq!(err, "");
+    fs::remove_dir_all(dir).unwrap();
+}